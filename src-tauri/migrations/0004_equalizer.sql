@@ -0,0 +1,6 @@
+-- Equalizer settings, bands and custom presets are stored as JSON arrays
+ALTER TABLE settings ADD COLUMN eq_enabled BOOLEAN DEFAULT false;
+ALTER TABLE settings ADD COLUMN eq_preamp REAL DEFAULT 0.0;
+ALTER TABLE settings ADD COLUMN eq_bands TEXT;
+ALTER TABLE settings ADD COLUMN eq_preset TEXT DEFAULT 'Flat';
+ALTER TABLE settings ADD COLUMN eq_presets TEXT;
@@ -0,0 +1,7 @@
+-- ReplayGain values read from the song's tags (gain in dB, peak as a linear value)
+ALTER TABLE songs ADD COLUMN replaygain_track_gain REAL;
+ALTER TABLE songs ADD COLUMN replaygain_track_peak REAL;
+ALTER TABLE songs ADD COLUMN replaygain_album_gain REAL;
+ALTER TABLE songs ADD COLUMN replaygain_album_peak REAL;
+-- 0 - Off, 1 - Track, 2 - Album
+ALTER TABLE settings ADD COLUMN replaygain_mode INTEGER DEFAULT 0;
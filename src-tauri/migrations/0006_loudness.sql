@@ -0,0 +1,5 @@
+-- Loudness measured by the background analysis (EBU R128), for songs without ReplayGain tags
+ALTER TABLE songs ADD COLUMN loudness_lufs REAL;
+ALTER TABLE songs ADD COLUMN loudness_peak REAL;
+-- Set once a song has been analyzed, so the analysis can pick up where it stopped
+ALTER TABLE songs ADD COLUMN loudness_scanned BOOLEAN DEFAULT false;
@@ -0,0 +1,4 @@
+-- Crossfade length in seconds, 0 turns crossfade off
+ALTER TABLE settings ADD COLUMN crossfade_duration REAL DEFAULT 0.0;
+-- Keep albums gapless by turning crossfade off while an album is playing
+ALTER TABLE settings ADD COLUMN crossfade_album_gapless BOOLEAN DEFAULT true;
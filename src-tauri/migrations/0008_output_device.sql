@@ -0,0 +1,2 @@
+-- Name of the chosen output device, NULL follows the system default
+ALTER TABLE settings ADD COLUMN output_device TEXT DEFAULT NULL;
@@ -0,0 +1,6 @@
+-- Where the player was when the app was closed, restored paused on the next start
+ALTER TABLE settings ADD COLUMN session_index INTEGER DEFAULT 0;
+ALTER TABLE settings ADD COLUMN session_position_ms INTEGER DEFAULT 0;
+ALTER TABLE settings ADD COLUMN session_volume REAL DEFAULT 0.4;
+ALTER TABLE settings ADD COLUMN session_repeat_mode INTEGER DEFAULT 1;
+ALTER TABLE settings ADD COLUMN session_shuffle BOOLEAN DEFAULT false;
@@ -0,0 +1,2 @@
+-- Length of the volume ramp on play, pause, seek and skips in ms, 0 turns it off
+ALTER TABLE settings ADD COLUMN volume_ramp_ms INTEGER DEFAULT 40;
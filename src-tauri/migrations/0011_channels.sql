@@ -0,0 +1,6 @@
+-- Channel processing, balance is -1 (left) to 1 (right)
+ALTER TABLE settings ADD COLUMN channel_balance REAL DEFAULT 0.0;
+ALTER TABLE settings ADD COLUMN channel_mono BOOLEAN DEFAULT false;
+ALTER TABLE settings ADD COLUMN channel_swap BOOLEAN DEFAULT false;
+-- 0 - Off, 1 - Default, 2 - Chu Moy, 3 - Jan Meier
+ALTER TABLE settings ADD COLUMN channel_crossfeed INTEGER DEFAULT 0;
@@ -0,0 +1,8 @@
+-- Songs the player couldn't open or decode, kept until the song is fixed or removed
+CREATE TABLE IF NOT EXISTS playback_errors (
+    song_id TEXT PRIMARY KEY,
+    reason TEXT NOT NULL,
+    failed_at TIMESTAMP NOT NULL,
+    fail_count INTEGER NOT NULL DEFAULT 1,
+    FOREIGN KEY(song_id) REFERENCES songs(path) ON DELETE CASCADE
+);
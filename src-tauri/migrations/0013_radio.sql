@@ -0,0 +1,7 @@
+-- Internet radio stations, added by hand or imported from M3U / PLS playlists
+CREATE TABLE IF NOT EXISTS radio_stations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    url TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL
+);
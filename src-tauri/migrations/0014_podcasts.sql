@@ -0,0 +1,26 @@
+-- Podcast feeds that were subscribed to, and every episode seen in them
+-- file_path is set once the episode has been downloaded into the podcast folder
+CREATE TABLE IF NOT EXISTS podcast_feeds (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL UNIQUE,
+    title TEXT NOT NULL,
+    description TEXT,
+    image_url TEXT,
+    subscribed_at TIMESTAMP NOT NULL,
+    refreshed_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS podcast_episodes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    feed_id INTEGER NOT NULL,
+    guid TEXT NOT NULL,
+    title TEXT NOT NULL,
+    description TEXT,
+    audio_url TEXT NOT NULL,
+    published TIMESTAMP,
+    duration INTEGER,
+    file_path TEXT,
+    played BOOLEAN NOT NULL DEFAULT false,
+    UNIQUE(feed_id, guid),
+    FOREIGN KEY(feed_id) REFERENCES podcast_feeds(id) ON DELETE CASCADE
+);
@@ -0,0 +1,16 @@
+-- Where songs that remember their position were left off
+CREATE TABLE IF NOT EXISTS bookmarks (
+    song_id TEXT PRIMARY KEY,
+    position_ms INTEGER NOT NULL,
+    duration INTEGER NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+-- Songs marked to always remember their position
+CREATE TABLE IF NOT EXISTS resume_songs (
+    song_id TEXT PRIMARY KEY
+);
+
+-- Songs this long (0 is off) or in one of these genres (comma separated) remember their position too
+ALTER TABLE settings ADD COLUMN resume_min_minutes INTEGER DEFAULT 0;
+ALTER TABLE settings ADD COLUMN resume_genres TEXT DEFAULT '';
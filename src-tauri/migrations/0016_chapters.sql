@@ -0,0 +1,10 @@
+-- Chapter markers read from the song's tags while scanning, replaced every time the song is scanned again
+CREATE TABLE IF NOT EXISTS chapters (
+    song_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    start_ms INTEGER NOT NULL,
+    end_ms INTEGER NOT NULL,
+    PRIMARY KEY(song_id, position),
+    FOREIGN KEY(song_id) REFERENCES songs(path) ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+-- Songs from a cue sheet are a part of a bigger file, the path is "<file>#<track>" and the audio is read from source_path
+ALTER TABLE songs ADD COLUMN source_path TEXT;
+ALTER TABLE songs ADD COLUMN start_ms INTEGER;
+ALTER TABLE songs ADD COLUMN end_ms INTEGER;
@@ -6,4 +6,11 @@ DROP TABLE IF EXISTS queue;
 DROP TABLE IF EXISTS queue_shuffled;
 DROP TABLE IF EXISTS history;
 DROP TABLE IF EXISTS dirs;
-DROP TABLE IF EXISTS playlists;
\ No newline at end of file
+DROP TABLE IF EXISTS playlists;
+DROP TABLE IF EXISTS playback_errors;
+DROP TABLE IF EXISTS radio_stations;
+DROP TABLE IF EXISTS podcast_episodes;
+DROP TABLE IF EXISTS podcast_feeds;
+DROP TABLE IF EXISTS bookmarks;
+DROP TABLE IF EXISTS resume_songs;
+DROP TABLE IF EXISTS chapters;
\ No newline at end of file
@@ -0,0 +1,152 @@
+use std::{ sync::{ Arc, atomic::{ AtomicU64, Ordering } }, time::Duration };
+use rodio::{ ChannelCount, SampleRate, Source, source::SeekError };
+
+use crate::tempo::Tempo;
+
+// A-B repeat, used for practicing along with a part of a song
+// The loop wraps the tempo source, so the points are in the song's own time at any speed
+// Reaching B seeks back to A right inside the source, a few ms are faded on both sides of the jump to stop clicks
+// The loop belongs to one song, so a song loaded after it (gapless / repeat one) won't loop
+
+const DECLICK: f64 = 0.008;
+
+// ------------------- Shared Controls -------------------
+
+pub struct LoopControls {
+    // Every song gets its own id when it is loaded, 0 is never used
+    next_id: AtomicU64,
+    // The song the loop belongs to, 0 when there is no loop
+    loop_id: AtomicU64,
+    a_ms: AtomicU64,
+    b_ms: AtomicU64
+}
+
+impl LoopControls {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            next_id: AtomicU64::new(1),
+            loop_id: AtomicU64::new(0),
+            a_ms: AtomicU64::new(0),
+            b_ms: AtomicU64::new(0)
+        })
+    }
+
+    // Loop the song with this id
+    pub fn set(&self, id: u64, a: Duration, b: Duration) {
+        self.a_ms.store(a.as_millis() as u64, Ordering::Relaxed);
+        self.b_ms.store(b.as_millis() as u64, Ordering::Relaxed);
+        self.loop_id.store(id, Ordering::Release);
+    }
+
+    pub fn clear(&self) {
+        self.loop_id.store(0, Ordering::Release);
+    }
+
+    // (A, B) in seconds, only when the loop belongs to the song with this id
+    fn points(&self, id: u64) -> Option<(f64, f64)> {
+        if self.loop_id.load(Ordering::Acquire) != id {
+            return None;
+        }
+        let a = self.a_ms.load(Ordering::Relaxed) as f64 / 1000.0;
+        let b = self.b_ms.load(Ordering::Relaxed) as f64 / 1000.0;
+        if b > a { Some((a, b)) } else { None }
+    }
+}
+
+
+// ------------------- Loop Source -------------------
+
+pub struct AbLoop<S: Source> {
+    input: Tempo<S>,
+    controls: Arc<LoopControls>,
+    id: u64,
+    channels: usize,
+    channel: usize,
+    gain: f32,
+    // Set right after a jump, the sound fades back in from A
+    jumped_to: Option<f64>
+}
+
+impl<S: Source> AbLoop<S> {
+    pub fn new(input: Tempo<S>, controls: Arc<LoopControls>) -> Self {
+        let id = controls.next_id.fetch_add(1, Ordering::Relaxed);
+        let channels = (u16::from(input.channels()) as usize).max(1);
+        Self { input, controls, id, channels, channel: 0, gain: 1.0, jumped_to: None }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Work out the gain for the next frame, and jump back to A when B is reached
+    fn update_frame(&mut self) {
+        let points = self.controls.points(self.id);
+        let position = self.input.position().as_secs_f64();
+
+        let Some((a, b)) = points else {
+            self.gain = 1.0;
+            self.jumped_to = None;
+            return;
+        };
+
+        // Only loop when playing into B, a seek past B plays on to the end of the song
+        if position >= b && position < b + DECLICK * 4.0 {
+            if self.input.try_seek(Duration::from_secs_f64(a)).is_ok() {
+                self.jumped_to = Some(a);
+            }
+        }
+
+        let position = self.input.position().as_secs_f64();
+        let fade_out = if position < b { ((b - position) / DECLICK).min(1.0) } else { 1.0 };
+        let fade_in = match self.jumped_to {
+            Some(from) => {
+                let t = ((position - from) / DECLICK).clamp(0.0, 1.0);
+                if t >= 1.0 {
+                    self.jumped_to = None;
+                }
+                t
+            },
+            None => 1.0
+        };
+        self.gain = fade_out.min(fade_in) as f32;
+    }
+}
+
+impl<S: Source> Iterator for AbLoop<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            self.update_frame();
+        }
+
+        let sample = self.input.next()?;
+        self.channel = (self.channel + 1) % self.channels;
+        Some(sample * self.gain)
+    }
+}
+
+impl<S: Source> Source for AbLoop<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.channel = 0;
+        self.jumped_to = None;
+        Ok(())
+    }
+}
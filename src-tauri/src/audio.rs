@@ -0,0 +1,554 @@
+use std::{ thread, time::{ Duration, Instant } };
+use flume::{ Receiver, RecvTimeoutError, Sender };
+use rodio::mixer::Mixer;
+use tauri::{ AppHandle, Emitter, Manager };
+use tauri_plugin_log::log;
+
+use crate::{
+    AppState, bookmarks::BookmarkChange, db,
+    music::{ MusicPlayer, QueueEdit, QueueSnapshot, TrackEnd }, radio::RadioSource, sleep_timer::SleepTimer,
+    types::{ AbLoopState, ChannelSettings, CrossfadeSettings, EqualizerSettings, GetCurrentSong, PlaybackProgress, PlaybackSession, PlaybackSpeed, PlayerStatus, RadioNowPlaying, RadioStation, ResumeSettings, SleepTimerStatus, SongTable }
+};
+
+// The music player lives on its own thread, and everything else talks to it through a channel
+// Commands never lock the player, so a slow command (or a slow frontend) can't hold up playback
+// Whenever the player changes, the new status is sent to the frontend as a "player-state" event
+// While a song is playing, the position is sent as a "playback-progress" event every STATUS_INTERVAL
+// Songs ending are handled here too, so the queue keeps going while the window is hidden or busy
+
+// How often the thread checks for changes nobody asked for (ex. the last song in the sink ended)
+const STATUS_INTERVAL: Duration = Duration::from_millis(250);
+// How often the session is saved to the database, it is also saved on exit
+const SESSION_INTERVAL: Duration = Duration::from_secs(10);
+
+pub enum PlayerCommand {
+    // Needed to send events, given once the app is set up
+    AttachApp(AppHandle),
+
+    // Queue
+    SetQueue(Vec<SongTable>),
+    GetQueue(Sender<Vec<SongTable>>),
+    AddToQueue(Vec<SongTable>),
+    // Replies true when the song that was playing got removed
+    RemoveFromQueue(usize, Sender<bool>),
+    RemoveFromQueueByValue(String),
+    // Queue edits are planned, saved to the queue tables by the command, then applied
+    // Plans are None when there is nothing to change
+    PlanPlayNext(Vec<SongTable>, Sender<Option<QueueEdit>>),
+    PlanMoveInQueue(Vec<usize>, usize, Sender<Result<Option<QueueEdit>, String>>),
+    PlanUndoQueueEdit(Sender<Option<QueueEdit>>),
+    // Replies with the queue after the edit, an error when the queue changed since the plan
+    ApplyQueueEdit(QueueEdit, Sender<Result<QueueSnapshot, String>>),
+    GetQueueSnapshot(Sender<QueueSnapshot>),
+    ClearQueue,
+    SetAlbumQueue(bool),
+    UpdateIndex(usize, Sender<Result<(), String>>),
+    LoadSong(usize, Sender<Result<(), String>>),
+    JumpToSong(usize),
+    GetQueueLength(Sender<usize>),
+    GetCurrentSong(Sender<Result<SongTable, bool>>),
+    GetCurrentPosition(Sender<usize>),
+    GetSinkLength(Sender<usize>),
+    // None while the queue is empty, an empty player shouldn't replace the saved session
+    GetSession(Sender<Option<PlaybackSession>>),
+    RestoreSession(Vec<SongTable>, PlaybackSession, Sender<Result<(), String>>),
+
+    // Media Controls
+    Play,
+    Pause,
+    Stop,
+    // Replies true when it is now playing
+    TogglePlay(Sender<bool>),
+    NextSong,
+    PreviousSong,
+    Seek(Duration),
+    SetVolume(f32),
+    SetRepeatMode(i64),
+    GetRepeatMode(Sender<i64>),
+    SetShuffle(bool),
+    GetShuffle(Sender<bool>),
+    IsPaused(Sender<bool>),
+    IsLoaded(Sender<bool>),
+    GetPosition(Sender<f64>),
+    GetSongPos(Sender<Duration>),
+    // None sets the point at the current position
+    SetLoopA(Option<Duration>, Sender<Result<(), String>>),
+    SetLoopB(Option<Duration>, Sender<Result<(), String>>),
+    ClearLoop,
+    GetLoop(Sender<AbLoopState>),
+
+    // Sound Settings
+    SetEqualizer(EqualizerSettings),
+    GetEqualizer(Sender<EqualizerSettings>),
+    SetChannels(ChannelSettings),
+    GetChannels(Sender<ChannelSettings>),
+    SetReplayGainMode(i64),
+    GetReplayGainMode(Sender<i64>),
+    SetCrossfade(CrossfadeSettings),
+    GetCrossfade(Sender<CrossfadeSettings>),
+    SetVolumeRamp(Duration),
+    GetVolumeRamp(Sender<Duration>),
+    SetSpeed(f32, bool),
+    GetSpeed(Sender<PlaybackSpeed>),
+    // Replies with the radio station that was playing (and whether it was paused), its stream has to be opened again
+    SwitchOutput(Mixer, Sender<Option<(RadioStation, bool)>>),
+    SetVisualizer(bool),
+    GetVisualizer(Sender<bool>),
+
+    // Resume Bookmarks
+    LoadBookmarks(ResumeSettings, Vec<String>, Vec<(String, u64)>),
+    SetResumeSettings(ResumeSettings),
+    GetResumeSettings(Sender<ResumeSettings>),
+    SetSongResume(String, bool),
+    ClearBookmark(String),
+    // Saves the playing song's spot and hands over every change not written yet, used when the app closes
+    FlushBookmarks(Sender<Vec<BookmarkChange>>),
+
+    // Radio
+    PlayRadio(RadioStation, RadioSource),
+    GetRadio(Sender<Option<RadioNowPlaying>>),
+
+    // Sleep Timer
+    StartSleepTimer(SleepTimer),
+    // Minutes or songs, depending on the timer
+    ExtendSleepTimer(u32, Sender<Result<(), String>>),
+    CancelSleepTimer,
+    GetSleepTimer(Sender<Option<SleepTimerStatus>>)
+}
+
+// Cheap to clone, every clone talks to the same player
+#[derive(Clone)]
+pub struct PlayerHandle {
+    tx: Sender<PlayerCommand>
+}
+
+impl PlayerHandle {
+    pub fn new(player: MusicPlayer) -> Result<Self, String> {
+        let (tx, rx) = flume::unbounded::<PlayerCommand>();
+
+        thread::Builder::new()
+            .name("music-player".to_string())
+            .spawn(move || run_player(player, rx))
+            .map_err(|e| format!("Error starting the music player: {:?}", e))?;
+
+        Ok(Self { tx })
+    }
+
+    // Fire and forget, the commands are still run in the order they are sent
+    pub fn send(&self, command: PlayerCommand) {
+        if let Err(e) = self.tx.send(command) {
+            log::error!("Player Handle - Music player thread is gone: {:?}", e);
+        }
+    }
+
+    // Send a command and wait for the answer
+    // ex. state.player.request(PlayerCommand::GetQueueLength)?
+    pub fn request<T>(&self, command: impl FnOnce(Sender<T>) -> PlayerCommand) -> Result<T, String> {
+        let (reply_tx, reply_rx) = flume::bounded(1);
+        self.tx.send(command(reply_tx)).map_err(|_| "Music player is not running".to_string())?;
+        reply_rx.recv().map_err(|_| "Music player did not answer".to_string())
+    }
+}
+
+
+// ------------------- Player Thread -------------------
+
+fn run_player(mut player: MusicPlayer, rx: Receiver<PlayerCommand>) {
+    let mut app: Option<AppHandle> = None;
+    let mut last_status: Option<PlayerStatus> = None;
+    let mut last_progress: Option<PlaybackProgress> = None;
+    let mut last_session: Option<PlaybackSession> = None;
+    let mut last_sleep_timer: Option<SleepTimerStatus> = None;
+    let mut last_radio: Option<RadioNowPlaying> = None;
+    let mut next_session_save = Instant::now() + SESSION_INTERVAL;
+    // Commands don't push the next check back, so the progress events keep a steady pace
+    let mut next_check = Instant::now() + STATUS_INTERVAL;
+
+    loop {
+        let received = rx.recv_deadline(next_check);
+
+        // Look for songs that ended by themselves before the command changes the sink
+        if let Some(end) = player.check_track_end() {
+            if let Some(app) = app.as_ref() {
+                on_track_end(app, end);
+            }
+        }
+
+        match received {
+            Ok(PlayerCommand::AttachApp(handle)) => {
+                app = Some(handle);
+                last_status = None;
+            },
+            Ok(command) => {
+                handle_command(&mut player, command);
+                // Songs that ended while the command was fading out
+                if let Some(app) = app.as_ref() {
+                    for end in player.take_track_ends() {
+                        on_track_end(app, end);
+                    }
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => player.preload_next(false),
+            // Every handle was dropped, the app is closing
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+
+        player.update_sleep_timer();
+        player.update_radio_title();
+
+        // Only tell the frontend when something actually changed
+        if let Some(app) = app.as_ref() {
+            // The countdown only changes once a second
+            let sleep_timer = player.get_sleep_timer();
+            if last_sleep_timer != sleep_timer {
+                let _ = app.emit("sleep-timer", sleep_timer.clone());
+                last_sleep_timer = sleep_timer;
+            }
+            let radio = player.get_radio();
+            if last_radio != radio {
+                let _ = app.emit("radio-now-playing", radio.clone());
+                last_radio = radio;
+            }
+
+            if player.take_sleep_finished() {
+                let _ = app.emit("controls-play-pause", false);
+            }
+
+            // Songs that were skipped because they couldn't be played, kept so they can be fixed or removed
+            for (path, reason) in player.take_load_errors() {
+                let pool = app.state::<AppState>().pool.clone();
+                tauri::async_runtime::spawn(async move {
+                    let _ = db::record_playback_error(&pool, path, reason).await;
+                });
+            }
+
+            // Podcast episodes are marked played once they are listened to the end
+            for path in player.take_finished() {
+                let pool = app.state::<AppState>().pool.clone();
+                tauri::async_runtime::spawn(async move {
+                    let _ = db::mark_podcast_file_played(&pool, path).await;
+                });
+            }
+
+            let status = player.get_status();
+            if last_status.as_ref() != Some(&status) {
+                let _ = app.emit("player-state", status.clone());
+                last_status = Some(status);
+            }
+
+            if Instant::now() >= next_check {
+                next_check = Instant::now() + STATUS_INTERVAL;
+
+                // Sent while playing, and once more after pausing or seeking while paused
+                let progress = player.get_progress();
+                if last_progress.as_ref() != Some(&progress) {
+                    let _ = app.emit("playback-progress", progress.clone());
+                    last_progress = Some(progress);
+                }
+            }
+
+            // Nothing to save until there is a queue, this also keeps the saved session safe until it is restored
+            if Instant::now() >= next_session_save {
+                next_session_save = Instant::now() + SESSION_INTERVAL;
+
+                // Keep the bookmark close in case the app doesn't close cleanly
+                if !player.check_is_paused() {
+                    player.save_bookmark();
+                }
+
+                let session = player.get_session();
+                if player.get_queue_length() > 0 && last_session.as_ref() != Some(&session) {
+                    let pool = app.state::<AppState>().pool.clone();
+                    let saved = session.clone();
+                    tauri::async_runtime::spawn(async move {
+                        let _ = db::set_session(&pool, &saved).await;
+                    });
+                    last_session = Some(session);
+                }
+            }
+
+            for change in player.take_bookmark_changes() {
+                let pool = app.state::<AppState>().pool.clone();
+                tauri::async_runtime::spawn(async move {
+                    let _ = db::save_bookmark(&pool, change).await;
+                });
+            }
+        }
+        else if Instant::now() >= next_check {
+            next_check = Instant::now() + STATUS_INTERVAL;
+        }
+    }
+}
+
+// Tell the frontend about the new song and add it to the history
+fn on_track_end(app: &AppHandle, end: TrackEnd) {
+    match end {
+        TrackEnd::NextSong(song) => {
+            let pool = app.state::<AppState>().pool.clone();
+            let path = song.path.clone();
+            tauri::async_runtime::spawn(async move {
+                let _ = db::record_play(&pool, path).await;
+            });
+            let _ = app.emit("get-current-song", GetCurrentSong { q: song });
+        },
+        TrackEnd::QueueEnded => {
+            let _ = app.emit("controls-play-pause", false);
+        }
+    }
+}
+
+fn handle_command(player: &mut MusicPlayer, command: PlayerCommand) {
+    // Replies are ignored if whoever asked stopped waiting
+    match command {
+        PlayerCommand::AttachApp(_) => {},
+
+        PlayerCommand::SetQueue(queue) => player.set_queue(queue),
+        PlayerCommand::GetQueue(reply) => { let _ = reply.send(player.get_current_queue().to_vec()); },
+        PlayerCommand::AddToQueue(queue) => player.add_to_queue(queue),
+        PlayerCommand::RemoveFromQueue(index, reply) => { let _ = reply.send(player.remove_from_queue(index)); },
+        PlayerCommand::RemoveFromQueueByValue(path) => player.remove_from_queue_by_value(path),
+        PlayerCommand::PlanPlayNext(songs, reply) => { let _ = reply.send(player.plan_play_next(songs)); },
+        PlayerCommand::PlanMoveInQueue(indexes, to, reply) => { let _ = reply.send(player.plan_move(indexes, to)); },
+        PlayerCommand::PlanUndoQueueEdit(reply) => { let _ = reply.send(player.plan_undo()); },
+        PlayerCommand::ApplyQueueEdit(edit, reply) => {
+            let _ = reply.send(player.apply_queue_edit(edit).map(|_| player.get_queue_snapshot()));
+        },
+        PlayerCommand::GetQueueSnapshot(reply) => { let _ = reply.send(player.get_queue_snapshot()); },
+        PlayerCommand::ClearQueue => player.clear_queue(),
+        PlayerCommand::SetAlbumQueue(is_album) => player.set_album_queue(is_album),
+        PlayerCommand::UpdateIndex(index, reply) => { let _ = reply.send(player.update_current_index(index)); },
+        PlayerCommand::LoadSong(index, reply) => { let _ = reply.send(player.load_song(index)); },
+        PlayerCommand::JumpToSong(index) => player.jump_to_song(index),
+        PlayerCommand::GetQueueLength(reply) => { let _ = reply.send(player.get_queue_length()); },
+        PlayerCommand::GetCurrentSong(reply) => { let _ = reply.send(player.get_current_song()); },
+        PlayerCommand::GetCurrentPosition(reply) => { let _ = reply.send(player.get_current_position()); },
+        PlayerCommand::GetSinkLength(reply) => { let _ = reply.send(player.get_sink_length()); },
+        PlayerCommand::GetSession(reply) => {
+            let session = if player.get_queue_length() > 0 { Some(player.get_session()) } else { None };
+            let _ = reply.send(session);
+        },
+        PlayerCommand::RestoreSession(queue, session, reply) => { let _ = reply.send(player.restore_session(queue, session)); },
+
+        PlayerCommand::Play => player.play_song(),
+        PlayerCommand::Pause => player.pause_song(),
+        PlayerCommand::Stop => player.stop_song(),
+        PlayerCommand::TogglePlay(reply) => {
+            if player.check_is_paused() {
+                player.play_song();
+            }
+            else {
+                player.pause_song();
+            }
+            let _ = reply.send(!player.check_is_paused());
+        },
+        PlayerCommand::NextSong => player.next_song(),
+        PlayerCommand::PreviousSong => player.previous_song(),
+        PlayerCommand::Seek(position) => player.seek(position),
+        PlayerCommand::SetVolume(volume) => player.set_volume(volume),
+        PlayerCommand::SetRepeatMode(mode) => player.set_repeat_mode(mode),
+        PlayerCommand::GetRepeatMode(reply) => { let _ = reply.send(player.check_repeat_mode()); },
+        PlayerCommand::SetShuffle(mode) => player.set_shuffle(mode),
+        PlayerCommand::GetShuffle(reply) => { let _ = reply.send(player.get_shuffle()); },
+        PlayerCommand::IsPaused(reply) => { let _ = reply.send(player.check_is_paused()); },
+        PlayerCommand::IsLoaded(reply) => { let _ = reply.send(player.check_is_loaded()); },
+        PlayerCommand::GetPosition(reply) => { let _ = reply.send(player.get_position()); },
+        PlayerCommand::GetSongPos(reply) => { let _ = reply.send(player.get_song_pos()); },
+        PlayerCommand::SetLoopA(position, reply) => { let _ = reply.send(player.set_loop_a(position)); },
+        PlayerCommand::SetLoopB(position, reply) => { let _ = reply.send(player.set_loop_b(position)); },
+        PlayerCommand::ClearLoop => player.clear_loop(),
+        PlayerCommand::GetLoop(reply) => { let _ = reply.send(player.get_loop()); },
+
+        PlayerCommand::SetEqualizer(settings) => player.set_equalizer(settings),
+        PlayerCommand::GetEqualizer(reply) => { let _ = reply.send(player.get_equalizer()); },
+        PlayerCommand::SetChannels(settings) => player.set_channels(settings),
+        PlayerCommand::GetChannels(reply) => { let _ = reply.send(player.get_channels()); },
+        PlayerCommand::SetReplayGainMode(mode) => player.set_replaygain_mode(mode),
+        PlayerCommand::GetReplayGainMode(reply) => { let _ = reply.send(player.get_replaygain_mode()); },
+        PlayerCommand::SetCrossfade(settings) => player.set_crossfade(settings),
+        PlayerCommand::GetCrossfade(reply) => { let _ = reply.send(player.get_crossfade()); },
+        PlayerCommand::SetSpeed(speed, keep_pitch) => player.set_speed(speed, keep_pitch),
+        PlayerCommand::GetSpeed(reply) => { let _ = reply.send(player.get_speed()); },
+        PlayerCommand::SetVolumeRamp(duration) => player.set_ramp_duration(duration),
+        PlayerCommand::GetVolumeRamp(reply) => { let _ = reply.send(player.get_ramp_duration()); },
+        PlayerCommand::SwitchOutput(mixer, reply) => { let _ = reply.send(player.switch_output(&mixer)); },
+        PlayerCommand::SetVisualizer(enabled) => player.set_visualizer(enabled),
+        PlayerCommand::GetVisualizer(reply) => { let _ = reply.send(player.get_visualizer()); },
+
+        PlayerCommand::LoadBookmarks(settings, songs, positions) => player.load_bookmarks(settings, songs, positions),
+        PlayerCommand::SetResumeSettings(settings) => player.set_resume_settings(settings),
+        PlayerCommand::GetResumeSettings(reply) => { let _ = reply.send(player.get_resume_settings()); },
+        PlayerCommand::SetSongResume(path, remember) => player.set_song_resume(path, remember),
+        PlayerCommand::ClearBookmark(path) => player.clear_bookmark(&path),
+        PlayerCommand::FlushBookmarks(reply) => {
+            player.save_bookmark();
+            let _ = reply.send(player.take_bookmark_changes());
+        },
+
+        PlayerCommand::PlayRadio(station, source) => player.play_radio(station, source),
+        PlayerCommand::GetRadio(reply) => { let _ = reply.send(player.get_radio()); },
+
+        PlayerCommand::StartSleepTimer(timer) => player.start_sleep_timer(timer),
+        PlayerCommand::ExtendSleepTimer(amount, reply) => { let _ = reply.send(player.extend_sleep_timer(amount)); },
+        PlayerCommand::CancelSleepTimer => player.cancel_sleep_timer(),
+        PlayerCommand::GetSleepTimer(reply) => { let _ = reply.send(player.get_sleep_timer()); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::Player;
+    use sqlx::{ Executor, Pool, Sqlite, sqlite::SqlitePoolOptions };
+    use tauri::async_runtime::block_on;
+
+    use super::{ PlayerCommand, PlayerHandle };
+    use crate::{ commands::save_queue_edit, db, music::{ MusicPlayer, QueueEdit, QueueSnapshot }, output::{ NullBackend, OutputBackend }, types::SongTable };
+
+    fn song(name: &str) -> SongTable {
+        SongTable { name: name.to_string(), path: format!("/music/{}.flac", name), ..SongTable::default() }
+    }
+
+    fn songs(names: &[&str]) -> Vec<SongTable> {
+        names.iter().map(|name| song(name)).collect()
+    }
+
+    // A library with these songs, kept to one connection so every query sees the same in-memory database
+    fn test_pool(library: &[SongTable]) -> Pool<Sqlite> {
+        block_on(async {
+            let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+            pool.execute(include_str!("../migrations/0001_init.sql")).await.unwrap();
+            for song in library {
+                sqlx::query("INSERT INTO songs (name, path, song_section) VALUES (?1, ?2, 0)")
+                    .bind(&song.name)
+                    .bind(&song.path)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+            }
+            pool
+        })
+    }
+
+    fn table(pool: &Pool<Sqlite>, name: &str) -> Vec<String> {
+        let rows: Vec<(String,)> = block_on(sqlx::query_as(&format!("SELECT song_id FROM {} ORDER BY position ASC", name)).fetch_all(pool)).unwrap();
+        rows.into_iter().map(|row| row.0).collect()
+    }
+
+    fn paths(songs: &[SongTable]) -> Vec<String> {
+        songs.iter().map(|song| song.path.clone()).collect()
+    }
+
+    fn sorted(mut paths: Vec<String>) -> Vec<String> {
+        paths.sort();
+        paths
+    }
+
+    fn test_player(backend: &dyn OutputBackend, queue: Vec<SongTable>, shuffled: bool) -> PlayerHandle {
+        let mut player = MusicPlayer::new(Player::connect_new(backend.mixer())).unwrap();
+        player.set_shuffle(shuffled);
+        player.set_queue(queue);
+        PlayerHandle::new(player).unwrap()
+    }
+
+    // What the queue edit commands do, None when there was nothing to change
+    fn edit_queue(pool: &Pool<Sqlite>, player: &PlayerHandle, edit: Option<QueueEdit>) -> Result<Option<QueueSnapshot>, String> {
+        edit.map(|edit| block_on(save_queue_edit(player, pool, edit))).transpose()
+    }
+
+    fn queue(player: &PlayerHandle) -> Vec<String> {
+        paths(&player.request(PlayerCommand::GetQueue).unwrap())
+    }
+
+    fn position(player: &PlayerHandle) -> usize {
+        player.request(PlayerCommand::GetCurrentPosition).unwrap()
+    }
+
+    fn plan_undo(player: &PlayerHandle) -> Option<QueueEdit> {
+        player.request(PlayerCommand::PlanUndoQueueEdit).unwrap()
+    }
+
+    // The table being played matches the player, the other one has the same songs
+    fn assert_in_sync(player: &PlayerHandle, pool: &Pool<Sqlite>, played: &str, other: &str) {
+        let queue = queue(player);
+        assert_eq!(table(pool, played), queue);
+        assert_eq!(sorted(table(pool, other)), sorted(queue));
+    }
+
+    #[test]
+    fn play_next_move_and_undo_keep_both_tables_in_sync() {
+        let pool = test_pool(&songs(&["a", "b", "c", "d", "e"]));
+        let backend = NullBackend::new().unwrap();
+        let player = test_player(&backend, songs(&["a", "b", "c"]), false);
+
+        let edit = player.request(|reply| PlayerCommand::PlanPlayNext(songs(&["d", "e"]), reply)).unwrap();
+        edit_queue(&pool, &player, edit).unwrap();
+        assert_eq!(queue(&player), paths(&songs(&["a", "d", "e", "b", "c"])));
+        assert_in_sync(&player, &pool, "queue", "queue_shuffled");
+
+        let edit = player.request(|reply| PlayerCommand::PlanMoveInQueue(vec![3], 0, reply)).unwrap().unwrap();
+        let snapshot = edit_queue(&pool, &player, edit).unwrap().unwrap();
+        assert_eq!(queue(&player), paths(&songs(&["b", "a", "d", "e", "c"])));
+        // The playing song moved along with the edit
+        assert_eq!(snapshot.position, 1);
+        assert_eq!(position(&player), 1);
+        assert_in_sync(&player, &pool, "queue", "queue_shuffled");
+
+        edit_queue(&pool, &player, plan_undo(&player)).unwrap();
+        assert_eq!(queue(&player), paths(&songs(&["a", "d", "e", "b", "c"])));
+        assert_eq!(position(&player), 0);
+        assert_in_sync(&player, &pool, "queue", "queue_shuffled");
+
+        edit_queue(&pool, &player, plan_undo(&player)).unwrap();
+        assert_eq!(queue(&player), paths(&songs(&["a", "b", "c"])));
+        assert_in_sync(&player, &pool, "queue", "queue_shuffled");
+
+        assert!(plan_undo(&player).is_none());
+    }
+
+    #[test]
+    fn shuffled_edits_keep_the_other_order() {
+        let pool = test_pool(&songs(&["a", "b", "c", "d"]));
+        block_on(db::sync_queue(&pool, &songs(&["a", "b", "c"]), false)).unwrap();
+        block_on(db::sync_queue(&pool, &songs(&["c", "a", "b"]), true)).unwrap();
+        let backend = NullBackend::new().unwrap();
+        let player = test_player(&backend, songs(&["c", "a", "b"]), true);
+
+        let edit = player.request(|reply| PlayerCommand::PlanPlayNext(songs(&["d"]), reply)).unwrap();
+        edit_queue(&pool, &player, edit).unwrap();
+        assert_eq!(table(&pool, "queue_shuffled"), paths(&songs(&["c", "d", "a", "b"])));
+        // New songs go in after the song they follow in the played order
+        assert_eq!(table(&pool, "queue"), paths(&songs(&["a", "b", "c", "d"])));
+        assert_in_sync(&player, &pool, "queue_shuffled", "queue");
+    }
+
+    #[test]
+    fn failed_save_leaves_the_player_unchanged() {
+        let pool = test_pool(&songs(&["a", "b"]));
+        let backend = NullBackend::new().unwrap();
+        let player = test_player(&backend, songs(&["a", "b"]), false);
+        block_on(db::sync_queue(&pool, &songs(&["a", "b"]), false)).unwrap();
+
+        // Not in the library, the queue tables can't point at it
+        let edit = player.request(|reply| PlayerCommand::PlanPlayNext(songs(&["missing"]), reply)).unwrap();
+        assert!(edit_queue(&pool, &player, edit).is_err());
+
+        assert_eq!(queue(&player), paths(&songs(&["a", "b"])));
+        assert!(plan_undo(&player).is_none());
+        assert_in_sync(&player, &pool, "queue", "queue_shuffled");
+    }
+
+    #[test]
+    fn edit_planned_before_the_queue_changed_is_dropped() {
+        let pool = test_pool(&songs(&["a", "b", "c", "d"]));
+        let backend = NullBackend::new().unwrap();
+        let player = test_player(&backend, songs(&["a", "b"]), false);
+
+        let edit = player.request(|reply| PlayerCommand::PlanPlayNext(songs(&["d"]), reply)).unwrap();
+        // Changed while the edit was being saved
+        player.send(PlayerCommand::AddToQueue(songs(&["c"])));
+        assert!(edit_queue(&pool, &player, edit).is_err());
+
+        // The tables are put back to the player's queue
+        assert_eq!(queue(&player), paths(&songs(&["a", "b", "c"])));
+        assert_in_sync(&player, &pool, "queue", "queue_shuffled");
+    }
+}
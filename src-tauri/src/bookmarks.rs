@@ -0,0 +1,108 @@
+use std::{ collections::{ HashMap, HashSet }, time::Duration };
+
+use crate::types::{ ResumeSettings, SongTable };
+
+// Resume bookmarks, long songs (mixes, audiobooks, podcasts) start again where they were left off
+// A song remembers its position if it was marked on its own, or if it matches the length or genre rules
+// Positions are saved on pause, skip, stop and exit (and every few seconds while playing), and dropped once the song plays to the end
+// The player keeps everything in memory, the player thread saves the changes to the database
+
+// Closer to the start than this isn't worth remembering
+const MIN_POSITION: Duration = Duration::from_secs(15);
+// Closer to the end than this counts as finished
+const END_MARGIN: Duration = Duration::from_secs(30);
+
+// A saved or removed bookmark, waiting to be written to the database
+pub struct BookmarkChange {
+    pub path: String,
+    // None removes the bookmark
+    pub position_ms: Option<u64>,
+    pub duration: u64
+}
+
+#[derive(Default)]
+pub struct Bookmarks {
+    settings: ResumeSettings,
+    // Songs that were marked to always remember their position
+    songs: HashSet<String>,
+    // Path -> position in ms
+    positions: HashMap<String, u64>,
+    changes: Vec<BookmarkChange>
+}
+
+impl Bookmarks {
+    // Everything saved in the database, given once at startup
+    pub fn load(&mut self, settings: ResumeSettings, songs: Vec<String>, positions: Vec<(String, u64)>) {
+        self.settings = settings;
+        self.songs = songs.into_iter().collect();
+        self.positions = positions.into_iter().collect();
+    }
+
+    pub fn set_settings(&mut self, settings: ResumeSettings) {
+        self.settings = settings;
+    }
+
+    pub fn get_settings(&self) -> ResumeSettings {
+        self.settings.clone()
+    }
+
+    // Bookmarks that are already saved stay until they are finished or cleared
+    pub fn set_song(&mut self, path: String, remember: bool) {
+        if remember {
+            self.songs.insert(path);
+        }
+        else {
+            self.songs.remove(&path);
+        }
+    }
+
+    pub fn remembers(&self, song: &SongTable) -> bool {
+        if self.songs.contains(&song.path) {
+            return true;
+        }
+        let min_secs = self.settings.min_duration_minutes * 60;
+        if min_secs > 0 && song.duration >= min_secs {
+            return true;
+        }
+        // Songs can have more than one genre, ex. "Audiobook; Fantasy"
+        song.genre.split([',', ';', '/'])
+            .map(|g| g.trim())
+            .filter(|g| !g.is_empty())
+            .any(|g| self.settings.genres.iter().any(|rule| rule.trim().eq_ignore_ascii_case(g)))
+    }
+
+    // Where to start the song, None to start from the beginning
+    pub fn position(&self, song: &SongTable) -> Option<Duration> {
+        if !self.remembers(song) {
+            return None;
+        }
+        self.positions.get(&song.path).map(|ms| Duration::from_millis(*ms))
+    }
+
+    pub fn save(&mut self, song: &SongTable, position: Duration) {
+        if !self.remembers(song) {
+            return;
+        }
+        let duration = Duration::from_secs(song.duration);
+        if position < MIN_POSITION || (!duration.is_zero() && position + END_MARGIN >= duration) {
+            self.remove(&song.path);
+            return;
+        }
+
+        let position_ms = position.as_millis() as u64;
+        if self.positions.insert(song.path.clone(), position_ms) != Some(position_ms) {
+            self.changes.push(BookmarkChange { path: song.path.clone(), position_ms: Some(position_ms), duration: song.duration });
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        if self.positions.remove(path).is_some() {
+            self.changes.push(BookmarkChange { path: path.to_string(), position_ms: None, duration: 0 });
+        }
+    }
+
+    // Changes since the last call, saved to the database by the player thread
+    pub fn take_changes(&mut self) -> Vec<BookmarkChange> {
+        std::mem::take(&mut self.changes)
+    }
+}
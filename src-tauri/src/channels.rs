@@ -0,0 +1,233 @@
+use std::{ f32::consts::PI, sync::{ Arc, Mutex, atomic::{ AtomicUsize, Ordering } }, time::Duration };
+use rodio::{ ChannelCount, SampleRate, Source, source::SeekError };
+
+use crate::types::ChannelSettings;
+
+// Channel processing, runs on whole frames right after the equalizer
+// Order: swap -> mono -> crossfeed -> balance
+// Swap, crossfeed and balance only make sense for stereo songs, mono works on any layout
+
+// Crossfeed levels from bs2b (cut frequency in Hz, feed level in dB)
+// 0 - Off, 1 - Default, 2 - Chu Moy, 3 - Jan Meier
+const CROSSFEED_LEVELS: [(f32, f32); 3] = [(700.0, 4.5), (700.0, 6.0), (650.0, 9.5)];
+pub const CROSSFEED_MAX: i64 = CROSSFEED_LEVELS.len() as i64;
+
+// Keep the settings inside of the allowed ranges
+pub fn clamp_settings(settings: ChannelSettings) -> ChannelSettings {
+    ChannelSettings {
+        balance: if settings.balance.is_finite() { settings.balance.clamp(-1.0, 1.0) } else { 0.0 },
+        crossfeed: settings.crossfeed.clamp(0, CROSSFEED_MAX),
+        ..settings
+    }
+}
+
+
+// ------------------- Shared Controls -------------------
+// Same setup as the equalizer, the version is bumped on each change so the source picks it up
+
+pub struct ChannelControls {
+    settings: Mutex<ChannelSettings>,
+    version: AtomicUsize
+}
+
+impl ChannelControls {
+    pub fn new(settings: ChannelSettings) -> Arc<Self> {
+        Arc::new(Self {
+            settings: Mutex::new(settings),
+            version: AtomicUsize::new(0)
+        })
+    }
+
+    pub fn get(&self) -> ChannelSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, settings: ChannelSettings) {
+        *self.settings.lock().unwrap() = settings;
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    fn version(&self) -> usize {
+        self.version.load(Ordering::Acquire)
+    }
+}
+
+
+// ------------------- Crossfeed -------------------
+// Bauer stereophonic-to-binaural filter, based on bs2b
+// Each ear gets the other side low passed and a little quieter, and its own side slightly high passed
+// so the sound isn't stuck inside the head on headphones
+
+#[derive(Clone, Copy, Default)]
+struct Crossfeed {
+    a0_lo: f32,
+    b1_lo: f32,
+    a0_hi: f32,
+    a1_hi: f32,
+    b1_hi: f32,
+    gain: f32,
+    // Filter memory, [left, right]
+    lo: [f32; 2],
+    hi: [f32; 2],
+    last_in: [f32; 2]
+}
+
+impl Crossfeed {
+    fn new(sample_rate: f32, cut: f32, feed_db: f32) -> Self {
+        let gb_lo = feed_db * -5.0 / 6.0 - 3.0;
+        let gb_hi = feed_db / 6.0 - 3.0;
+
+        let g_lo = 10f32.powf(gb_lo / 20.0);
+        let g_hi = 1.0 - 10f32.powf(gb_hi / 20.0);
+        let cut_hi = cut * 2f32.powf((gb_lo - 20.0 * g_hi.log10()) / 12.0);
+
+        let x = (-2.0 * PI * cut / sample_rate).exp();
+        let (a0_lo, b1_lo) = (g_lo * (1.0 - x), x);
+
+        let x = (-2.0 * PI * cut_hi / sample_rate).exp();
+        let (a0_hi, a1_hi, b1_hi) = (1.0 - g_hi * (1.0 - x), -x, x);
+
+        Self { a0_lo, b1_lo, a0_hi, a1_hi, b1_hi, gain: 1.0 / (1.0 - g_hi + g_lo), ..Self::default() }
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        for (ch, input) in [frame[0], frame[1]].into_iter().enumerate() {
+            self.lo[ch] = self.a0_lo * input + self.b1_lo * self.lo[ch];
+            self.hi[ch] = self.a0_hi * input + self.a1_hi * self.last_in[ch] + self.b1_hi * self.hi[ch];
+            self.last_in[ch] = input;
+        }
+        frame[0] = (self.hi[0] + self.lo[1]) * self.gain;
+        frame[1] = (self.hi[1] + self.lo[0]) * self.gain;
+    }
+
+    fn reset(&mut self) {
+        self.lo = [0.0; 2];
+        self.hi = [0.0; 2];
+        self.last_in = [0.0; 2];
+    }
+}
+
+
+// ------------------- Channel Source -------------------
+
+pub struct Channels<S: Source> {
+    input: S,
+    controls: Arc<ChannelControls>,
+    version: usize,
+    settings: ChannelSettings,
+    crossfeed: Option<Crossfeed>,
+    // The level the crossfeed filter was built for
+    crossfeed_level: i64,
+    // The frame being played, filled a whole frame at a time
+    frame: Vec<f32>,
+    frame_pos: usize
+}
+
+impl<S: Source> Channels<S> {
+    pub fn new(input: S, controls: Arc<ChannelControls>) -> Self {
+        let channels = (u16::from(input.channels()) as usize).max(1);
+        let mut source = Self {
+            input,
+            settings: controls.get(),
+            controls,
+            version: 0,
+            crossfeed: None,
+            crossfeed_level: 0,
+            frame: vec![0.0; channels],
+            frame_pos: channels
+        };
+        source.update_settings();
+        source
+    }
+
+    fn update_settings(&mut self) {
+        self.version = self.controls.version();
+        self.settings = clamp_settings(self.controls.get());
+
+        // Keep the filter memory if the level didn't change, prevents clicks when moving the balance
+        if self.settings.crossfeed == self.crossfeed_level {
+            return;
+        }
+        self.crossfeed_level = self.settings.crossfeed;
+
+        let sample_rate = u32::from(self.input.sample_rate()) as f32;
+        self.crossfeed = match self.settings.crossfeed {
+            0 => None,
+            level => CROSSFEED_LEVELS.get(level as usize - 1).map(|(cut, feed)| Crossfeed::new(sample_rate, *cut, *feed))
+        };
+    }
+
+    fn process_frame(&mut self) {
+        let settings = &self.settings;
+        let frame = &mut self.frame;
+
+        if frame.len() == 2 && settings.swap {
+            frame.swap(0, 1);
+        }
+
+        if settings.mono && frame.len() > 1 {
+            let mix = frame.iter().sum::<f32>() / frame.len() as f32;
+            frame.iter_mut().for_each(|s| *s = mix);
+        }
+        else if frame.len() == 2 {
+            if let Some(crossfeed) = self.crossfeed.as_mut() {
+                crossfeed.process(frame);
+            }
+        }
+
+        if frame.len() == 2 && settings.balance != 0.0 {
+            frame[0] *= (1.0 - settings.balance).min(1.0);
+            frame[1] *= (1.0 + settings.balance).min(1.0);
+        }
+    }
+}
+
+impl<S: Source> Iterator for Channels<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // Read a whole frame before handing out any of it
+        if self.frame_pos >= self.frame.len() {
+            if self.controls.version() != self.version {
+                self.update_settings();
+            }
+            for sample in self.frame.iter_mut() {
+                *sample = self.input.next()?;
+            }
+            self.process_frame();
+            self.frame_pos = 0;
+        }
+
+        let sample = self.frame[self.frame_pos];
+        self.frame_pos += 1;
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for Channels<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        // Old filter memory belongs to a different part of the song
+        if let Some(crossfeed) = self.crossfeed.as_mut() {
+            crossfeed.reset();
+        }
+        self.frame_pos = self.frame.len();
+        Ok(())
+    }
+}
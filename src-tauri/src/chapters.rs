@@ -0,0 +1,499 @@
+use std::{ fs::File, io::{ Read, Seek, SeekFrom }, time::Duration };
+
+use crate::{ formats, types::Chapter };
+
+// Chapter markers for audiobooks and long mixes, read while scanning and saved in the chapters table
+// MP3 - ID3v2 CHAP frames, titles from the TIT2 sub frame
+// M4A / M4B - the QuickTime chapter track (iTunes, Audible) or the Nero "chpl" atom
+// lofty doesn't read either of these, so the few parts needed are parsed here
+
+// moov atoms bigger than this aren't read, they are never near this size in audio files
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+// Longest chapter title read from a QuickTime text sample
+const MAX_TITLE_SAMPLE: u32 = 1024;
+// Going to the previous chapter this far into one goes back to its start instead
+pub const PREVIOUS_RESTART: Duration = Duration::from_secs(3);
+
+// Sorted by start, every chapter ends where the next one starts (the last one at the end of the song)
+pub fn read_chapters(path: &str, duration: Duration) -> Vec<Chapter> {
+    let Ok(mut file) = File::open(path) else { return vec![] };
+
+    let marks = match formats::decoder_hint(path) {
+        Some("mp3") => read_id3_chapters(&mut file),
+        Some("m4a") => read_mp4_chapters(&mut file),
+        _ => None
+    };
+    finish_chapters(marks.unwrap_or_default(), duration.as_millis() as i64)
+}
+
+// The chapter playing at this position
+pub fn chapter_at(chapters: &[Chapter], position: Duration) -> Option<usize> {
+    let ms = position.as_millis() as i64;
+    chapters.iter().rposition(|c| c.start_ms <= ms)
+}
+
+// (title, start ms, end ms if the file gives one)
+type ChapterMark = (String, i64, Option<i64>);
+
+fn finish_chapters(mut marks: Vec<ChapterMark>, duration_ms: i64) -> Vec<Chapter> {
+    marks.sort_by_key(|(_, start, _)| *start);
+    marks.dedup_by_key(|(_, start, _)| *start);
+
+    let starts: Vec<i64> = marks.iter().map(|(_, start, _)| *start).collect();
+    marks.into_iter().enumerate().filter_map(|(i, (title, start_ms, end))| {
+        // A mark past the end of the song doesn't stretch the chapter before it
+        let next = starts.get(i + 1).copied().filter(|s| duration_ms <= 0 || *s < duration_ms).unwrap_or(duration_ms);
+        let end_ms = end.filter(|e| *e > start_ms).unwrap_or(next).min(next.max(start_ms));
+        if duration_ms > 0 && start_ms >= duration_ms {
+            return None;
+        }
+        let title = if title.trim().is_empty() { format!("Chapter {}", i + 1) } else { title.trim().to_string() };
+        Some(Chapter { title, start_ms, end_ms })
+    }).collect()
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+
+// ------------------- ID3v2 CHAP -------------------
+// CHAP: element id (null terminated) | start ms | end ms | start offset | end offset | sub frames
+
+fn read_id3_chapters(file: &mut File) -> Option<Vec<ChapterMark>> {
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header).ok()?;
+    if &header[0..3] != b"ID3" {
+        return None;
+    }
+    // v2.2 has no chapters
+    let version = header[3];
+    if version < 3 {
+        return None;
+    }
+    let flags = header[5];
+
+    let mut tag = vec![0u8; synchsafe(&header[6..10]) as usize];
+    file.read_exact(&mut tag).ok()?;
+    if flags & 0x80 != 0 {
+        tag = remove_unsync(&tag);
+    }
+
+    // Skip the extended header, v2.4 counts its own size bytes and v2.3 doesn't
+    let mut pos = 0;
+    if flags & 0x40 != 0 {
+        pos = match version {
+            3 => be_u32(&tag, 0)? as usize + 4,
+            _ => synchsafe(tag.get(0..4)?) as usize
+        };
+    }
+
+    let chapters = id3_frames(tag.get(pos..)?, version).into_iter()
+        .filter(|(id, _)| id == b"CHAP")
+        .filter_map(|(_, body)| parse_chap(body, version))
+        .collect();
+    Some(chapters)
+}
+
+fn parse_chap(body: &[u8], version: u8) -> Option<ChapterMark> {
+    let id_end = body.iter().position(|b| *b == 0)?;
+    let start = be_u32(body, id_end + 1)? as i64;
+    let end = be_u32(body, id_end + 5)? as i64;
+
+    let title = body.get(id_end + 17..)
+        .map(|sub| id3_frames(sub, version))
+        .unwrap_or_default()
+        .into_iter()
+        .find(|(id, _)| id == b"TIT2")
+        .map(|(_, text)| id3_text(text))
+        .unwrap_or_default();
+
+    Some((title, start, Some(end)))
+}
+
+// (frame id, frame body) of every frame in order
+fn id3_frames(data: &[u8], version: u8) -> Vec<([u8; 4], &[u8])> {
+    let mut frames = vec![];
+    let mut pos = 0;
+
+    while pos + 10 <= data.len() {
+        let id: [u8; 4] = data[pos..pos + 4].try_into().unwrap_or_default();
+        // Padding
+        if id[0] == 0 {
+            break;
+        }
+        let size = match version {
+            3 => be_u32(data, pos + 4).unwrap_or(0),
+            _ => synchsafe(&data[pos + 4..pos + 8])
+        } as usize;
+
+        let Some(body) = data.get(pos + 10..pos + 10 + size) else { break };
+        frames.push((id, body));
+        pos += 10 + size;
+    }
+    frames
+}
+
+fn synchsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |size, b| (size << 7) | (*b as u32 & 0x7f))
+}
+
+// Unsynchronisation puts a 0 after every 0xFF, take them back out
+fn remove_unsync(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, b) in data.iter().enumerate() {
+        if *b == 0 && i > 0 && data[i - 1] == 0xff {
+            continue;
+        }
+        out.push(*b);
+    }
+    out
+}
+
+// Encoding byte then the text, 0 - Latin-1, 1 - UTF-16 with BOM, 2 - UTF-16BE, 3 - UTF-8
+fn id3_text(body: &[u8]) -> String {
+    let Some((encoding, text)) = body.split_first() else { return String::new() };
+    let text = match encoding {
+        0 => text.iter().map(|b| *b as char).collect(),
+        1 => utf16_with_bom(text),
+        2 => utf16(text, true),
+        _ => String::from_utf8_lossy(text).to_string()
+    };
+    text.trim_end_matches('\0').to_string()
+}
+
+fn utf16_with_bom(bytes: &[u8]) -> String {
+    match bytes {
+        [0xff, 0xfe, rest @ ..] => utf16(rest, false),
+        [0xfe, 0xff, rest @ ..] => utf16(rest, true),
+        _ => utf16(bytes, true)
+    }
+}
+
+fn utf16(bytes: &[u8], big_endian: bool) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2)
+        .map(|c| if big_endian { u16::from_be_bytes([c[0], c[1]]) } else { u16::from_le_bytes([c[0], c[1]]) })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+
+// ------------------- MP4 -------------------
+
+fn read_mp4_chapters(file: &mut File) -> Option<Vec<ChapterMark>> {
+    let moov = read_moov(file)?;
+    quicktime_chapters(file, &moov)
+        .filter(|c| !c.is_empty())
+        .or_else(|| nero_chapters(&moov))
+}
+
+// Only the moov atom is loaded, the audio (mdat) can be hundreds of MB
+fn read_moov(file: &mut File) -> Option<Vec<u8>> {
+    let file_len = file.metadata().ok()?.len();
+    let mut pos = 0;
+
+    while pos + 8 <= file_len {
+        file.seek(SeekFrom::Start(pos)).ok()?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header).ok()?;
+
+        let mut size = be_u32(&header, 0)? as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            let mut large = [0u8; 8];
+            file.read_exact(&mut large).ok()?;
+            size = u64::from_be_bytes(large);
+            header_len = 16;
+        }
+        else if size == 0 {
+            size = file_len - pos;
+        }
+        if size < header_len {
+            return None;
+        }
+
+        if &header[4..8] == b"moov" {
+            if size > MAX_MOOV_SIZE {
+                return None;
+            }
+            let mut moov = vec![0u8; (size - header_len) as usize];
+            file.read_exact(&mut moov).ok()?;
+            return Some(moov);
+        }
+        pos += size;
+    }
+    None
+}
+
+// (atom type, atom body) of every atom directly inside of data
+fn atoms(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut found = vec![];
+    let mut pos = 0;
+
+    while pos + 8 <= data.len() {
+        let Some(size) = be_u32(data, pos) else { break };
+        let kind: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap_or_default();
+        let (size, header_len) = match size {
+            0 => (data.len() - pos, 8),
+            1 => match be_u64(data, pos + 8) {
+                Some(large) => (large as usize, 16),
+                None => break
+            },
+            size => (size as usize, 8)
+        };
+        let Some(body) = pos.checked_add(size).and_then(|end| data.get(pos + header_len..end)) else { break };
+        found.push((kind, body));
+        pos += size;
+    }
+    found
+}
+
+// Follow a path of atoms, ex. [b"udta", b"chpl"]
+fn find_atom<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let (_, body) = atoms(data).into_iter().find(|(kind, _)| kind == *first)?;
+    if rest.is_empty() { Some(body) } else { find_atom(body, rest) }
+}
+
+// moov/udta/chpl: version | flags (3) | [reserved (4) in version 1] | count (1) | (start in 100ns (8) | title length (1) | title) ...
+fn nero_chapters(moov: &[u8]) -> Option<Vec<ChapterMark>> {
+    let chpl = find_atom(moov, &[b"udta", b"chpl"])?;
+    let mut pos = if chpl.first()? == &1 { 8 } else { 4 };
+    let count = *chpl.get(pos)? as usize;
+    pos += 1;
+
+    let mut chapters = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = be_u64(chpl, pos)?;
+        let len = *chpl.get(pos + 8)? as usize;
+        let title = String::from_utf8_lossy(chpl.get(pos + 9..pos + 9 + len)?).to_string();
+        chapters.push((title, (start / 10_000) as i64, None));
+        pos += 9 + len;
+    }
+    Some(chapters)
+}
+
+// The audio track points at a text track through tref/chap, each sample of that track is one chapter title
+fn quicktime_chapters(file: &mut File, moov: &[u8]) -> Option<Vec<ChapterMark>> {
+    let traks: Vec<&[u8]> = atoms(moov).into_iter().filter(|(kind, _)| kind == b"trak").map(|(_, body)| body).collect();
+
+    let chapter_ids: Vec<u32> = traks.iter()
+        .filter_map(|trak| find_atom(trak, &[b"tref", b"chap"]))
+        .flat_map(|chap| chap.chunks_exact(4).map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]])))
+        .collect();
+    if chapter_ids.is_empty() {
+        return None;
+    }
+
+    let trak = traks.into_iter().find(|trak| track_id(trak).is_some_and(|id| chapter_ids.contains(&id)))?;
+    let timescale = media_timescale(trak)?;
+    let stbl = find_atom(trak, &[b"mdia", b"minf", b"stbl"])?;
+
+    let starts = sample_starts(find_atom(stbl, &[b"stts"])?);
+    let sizes = sample_sizes(find_atom(stbl, &[b"stsz"])?)?;
+    let offsets = sample_offsets(stbl, &sizes)?;
+
+    let chapters = starts.into_iter().zip(offsets.iter().zip(&sizes)).map(|(start, (offset, size))| {
+        let title = read_text_sample(file, *offset, *size).unwrap_or_default();
+        (title, (start * 1000 / timescale as u64) as i64, None)
+    }).collect();
+    Some(chapters)
+}
+
+// tkhd: version | flags (3) | created | modified | track id, the times are 8 bytes in version 1
+fn track_id(trak: &[u8]) -> Option<u32> {
+    let tkhd = find_atom(trak, &[b"tkhd"])?;
+    be_u32(tkhd, if tkhd.first()? == &1 { 20 } else { 12 })
+}
+
+// mdhd: version | flags (3) | created | modified | timescale, the times are 8 bytes in version 1
+fn media_timescale(trak: &[u8]) -> Option<u32> {
+    let mdhd = find_atom(trak, &[b"mdia", b"mdhd"])?;
+    be_u32(mdhd, if mdhd.first()? == &1 { 20 } else { 12 }).filter(|t| *t > 0)
+}
+
+// stts: count | (sample count | sample duration) ..., start of each sample in the track's timescale
+fn sample_starts(stts: &[u8]) -> Vec<u64> {
+    let count = be_u32(stts, 4).unwrap_or(0) as usize;
+    let mut starts = vec![];
+    let mut time = 0u64;
+
+    for i in 0..count {
+        let (Some(samples), Some(delta)) = (be_u32(stts, 8 + i * 8), be_u32(stts, 12 + i * 8)) else { break };
+        for _ in 0..samples {
+            starts.push(time);
+            time += delta as u64;
+        }
+    }
+    starts
+}
+
+// stsz: one size for every sample, or a size per sample
+fn sample_sizes(stsz: &[u8]) -> Option<Vec<u32>> {
+    let size = be_u32(stsz, 4)?;
+    let count = be_u32(stsz, 8)? as usize;
+    if size != 0 {
+        return Some(vec![size; count]);
+    }
+    (0..count).map(|i| be_u32(stsz, 12 + i * 4)).collect()
+}
+
+// Samples are stored in chunks (stco / co64), stsc says how many samples are in each chunk
+fn sample_offsets(stbl: &[u8], sizes: &[u32]) -> Option<Vec<u64>> {
+    let chunk_offsets: Vec<u64> = match (find_atom(stbl, &[b"stco"]), find_atom(stbl, &[b"co64"])) {
+        (Some(stco), _) => (0..be_u32(stco, 4)? as usize).map(|i| be_u32(stco, 8 + i * 4).map(|o| o as u64)).collect::<Option<_>>()?,
+        (None, Some(co64)) => (0..be_u32(co64, 4)? as usize).map(|i| be_u64(co64, 8 + i * 8)).collect::<Option<_>>()?,
+        _ => return None
+    };
+
+    // (first chunk, samples per chunk), chunks are numbered from 1
+    let stsc = find_atom(stbl, &[b"stsc"])?;
+    let runs: Vec<(usize, usize)> = (0..be_u32(stsc, 4)? as usize)
+        .map(|i| Some((be_u32(stsc, 8 + i * 12)? as usize, be_u32(stsc, 12 + i * 12)? as usize)))
+        .collect::<Option<_>>()?;
+
+    let mut offsets = Vec::with_capacity(sizes.len());
+    for (chunk, chunk_offset) in chunk_offsets.iter().enumerate() {
+        let per_chunk = runs.iter().rev().find(|(first, _)| *first <= chunk + 1).map(|(_, n)| *n).unwrap_or(1);
+        let mut offset = *chunk_offset;
+        for _ in 0..per_chunk {
+            let Some(size) = sizes.get(offsets.len()) else { return Some(offsets) };
+            offsets.push(offset);
+            offset += *size as u64;
+        }
+    }
+    Some(offsets)
+}
+
+// Text samples: length (2) | text, UTF-8 or UTF-16 with a BOM
+fn read_text_sample(file: &mut File, offset: u64, size: u32) -> Option<String> {
+    let mut sample = vec![0u8; size.min(MAX_TITLE_SAMPLE) as usize];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut sample).ok()?;
+
+    let len = u16::from_be_bytes([*sample.first()?, *sample.get(1)?]) as usize;
+    let text = sample.get(2..(2 + len).min(sample.len()))?;
+    Some(match text {
+        [0xfe, 0xff, ..] | [0xff, 0xfe, ..] => utf16_with_bom(text),
+        _ => String::from_utf8_lossy(text).to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{ self, File };
+
+    use super::{ ChapterMark, finish_chapters, read_id3_chapters };
+    use crate::test_helpers::test_dir;
+
+    fn synchsafe(size: usize) -> [u8; 4] {
+        [(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]
+    }
+
+    fn frame(id: &[u8; 4], body: &[u8], version: u8) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        match version {
+            3 => frame.extend_from_slice(&(body.len() as u32).to_be_bytes()),
+            _ => frame.extend_from_slice(&synchsafe(body.len()))
+        }
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    // The start and end offsets are 0xFFFFFFFF (not used), so every chapter has bytes to unsynchronise
+    fn chap(id: &str, start: u32, end: u32, title: &str, version: u8) -> Vec<u8> {
+        let mut body = id.as_bytes().to_vec();
+        body.push(0);
+        body.extend_from_slice(&start.to_be_bytes());
+        body.extend_from_slice(&end.to_be_bytes());
+        body.extend_from_slice(&[0xff; 8]);
+        // UTF-8 title
+        let mut text = vec![3];
+        text.extend_from_slice(title.as_bytes());
+        body.extend(frame(b"TIT2", &text, version));
+        frame(b"CHAP", &body, version)
+    }
+
+    fn tag(version: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+        let mut tag = b"ID3".to_vec();
+        tag.extend_from_slice(&[version, 0, flags]);
+        tag.extend_from_slice(&synchsafe(body.len()));
+        tag.extend_from_slice(body);
+        // Padding, then the audio
+        tag.extend_from_slice(&[0; 16]);
+        tag
+    }
+
+    fn unsync(data: &[u8]) -> Vec<u8> {
+        data.iter().flat_map(|b| if *b == 0xff { vec![0xff, 0] } else { vec![*b] }).collect()
+    }
+
+    // Each case gets its own folder, the tests run at the same time
+    fn read(name: &str, bytes: &[u8]) -> Option<Vec<ChapterMark>> {
+        let dir = test_dir(&format!("id3-{}", name.replace(' ', "-")));
+        let path = dir.join("song.mp3");
+        fs::write(&path, bytes).unwrap();
+        let chapters = read_id3_chapters(&mut File::open(&path).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+        chapters
+    }
+
+    #[test]
+    fn id3_chapters_are_read_from_every_tag_layout() {
+        let chapters = |version: u8| [chap("ch1", 60_000, 120_000, "Second", version), chap("ch0", 0, 60_000, "First", version)].concat();
+        let v3_extended = [&[0, 0, 0, 6, 0, 0, 0, 0, 0, 0][..], &chapters(3)[..]].concat();
+        let v4_extended = [&[0, 0, 0, 6, 1, 0][..], &chapters(4)[..]].concat();
+
+        let cases = [
+            ("v2.3", tag(3, 0, &chapters(3))),
+            ("v2.4", tag(4, 0, &chapters(4))),
+            ("v2.3 unsynchronised", tag(3, 0x80, &unsync(&chapters(3)))),
+            ("v2.4 unsynchronised", tag(4, 0x80, &unsync(&chapters(4)))),
+            // v2.3 doesn't count the size bytes of the extended header, v2.4 does
+            ("v2.3 extended header", tag(3, 0x40, &v3_extended)),
+            ("v2.4 extended header", tag(4, 0x40, &v4_extended))
+        ];
+        for (name, bytes) in cases {
+            assert_eq!(read(name, &bytes), Some(vec![
+                ("Second".to_string(), 60_000, Some(120_000)),
+                ("First".to_string(), 0, Some(60_000))
+            ]), "{}", name);
+        }
+    }
+
+    #[test]
+    fn files_without_id3_chapters_have_none() {
+        assert_eq!(read("no chapters", &tag(3, 0, &frame(b"TIT2", b"\x03Title", 3))), Some(vec![]));
+        // v2.2 has no CHAP frame
+        assert_eq!(read("v2.2", &tag(2, 0, &[])), None);
+        assert_eq!(read("not id3", b"fLaC and not an ID3 tag"), None);
+    }
+
+    #[test]
+    fn chapters_are_sorted_and_fill_the_song() {
+        let mark = |title: &str, start: i64, end: Option<i64>| (title.to_string(), start, end);
+        let cases = [
+            // Each chapter ends where the next one starts, the last one at the end of the song
+            (vec![mark("Two", 5000, None), mark("One", 0, None)], 9000, vec![("One", 0, 5000), ("Two", 5000, 9000)]),
+            // An end past the next start is cut short
+            (vec![mark("A", 0, Some(8000)), mark("B", 4000, Some(6000))], 10000, vec![("A", 0, 4000), ("B", 4000, 6000)]),
+            // Two chapters at the same spot keep the first, blank titles are numbered
+            (vec![mark(" ", 0, None), mark("Dupe", 0, None), mark("Next", 3000, None)], 6000, vec![("Chapter 1", 0, 3000), ("Next", 3000, 6000)]),
+            // Marks past the end of the song are dropped
+            (vec![mark("In", 0, None), mark("Late", 20000, None)], 10000, vec![("In", 0, 10000)]),
+            (vec![], 10000, vec![])
+        ];
+        for (marks, duration, expected) in cases {
+            let chapters: Vec<(String, i64, i64)> = finish_chapters(marks, duration).into_iter()
+                .map(|c| (c.title, c.start_ms, c.end_ms))
+                .collect();
+            let expected: Vec<(String, i64, i64)> = expected.into_iter().map(|(t, s, e)| (t.to_string(), s, e)).collect();
+            assert_eq!(chapters, expected);
+        }
+    }
+}
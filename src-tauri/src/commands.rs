@@ -1,11 +1,14 @@
 // Imports
 use crate::{
-    AppState, db::{self, create_playlist, get_playlist}, helper::{self},
-    types::{DirsTable, DoesExist, GetArtistList, GetCurrentSong, GetPlaylistList, LrclibLyrics, PlaylistFull, SongTable }
+    AppState, GetScanStatus, audio::{ PlayerCommand, PlayerHandle }, ScanProgress, bookmarks::BookmarkChange, chapters, db::{self, create_playlist, get_playlist}, channels, equalizer, helper::{self}, loudness, music::{ QueueEdit, QueueSnapshot }, output, podcast, ramp, radio, sleep_timer::{self, SleepTimer}, tempo, waveform,
+    types::{AbLoopState, Bookmark, ChannelSettings, Chapter, CrossfadeSettings, DirsTable, DoesExist, EqualizerPreset, EqualizerSettings, GetArtistList, GetCurrentSong, GetPlaylistList, LrclibLyrics, OutputDevice, PlaybackError, PlaybackSpeed, PlaylistFull, PodcastEpisode, PodcastFeed, RadioNowPlaying, RadioStation, RestoredSession, ResumeSettings, SleepTimerStatus, SongTable }
 };
 
 // Core Libraries
 use std::{fs::{self, File}, io::Read, path::Path};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+use sqlx::{Pool, Sqlite};
 use std::path::{PathBuf};
 use std::io::Write;
 use std::{io};
@@ -25,42 +28,43 @@ use m3u8_rs::{MediaPlaylist, MediaSegment, Playlist};
 
 #[tauri::command]
 pub fn player_set_queue(state: State<AppState, '_>, queue: Vec<SongTable>) -> Result<(), String> {
-    state.player.lock().unwrap().set_queue(queue);
+    state.player.send(PlayerCommand::SetQueue(queue));
     Ok(())   
 }
 
 #[tauri::command]
 pub fn player_get_queue(state: State<AppState, '_>) -> Result<Vec<SongTable>, String> {
-    let q: Vec<SongTable> = state.player.lock().unwrap().get_current_queue().to_vec();
+    let q: Vec<SongTable> = state.player.request(PlayerCommand::GetQueue)?;
     Ok(q)
 }
 
 #[tauri::command]
 pub fn player_add_to_queue(state: State<AppState, '_>, queue: Vec<SongTable>) -> Result<(), String> {
-    state.player.lock().unwrap().add_to_queue(queue);
+    state.player.send(PlayerCommand::AddToQueue(queue));
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_remove_from_queue(state: State<AppState, '_>, app: tauri::AppHandle, index: usize) -> Result<(), String> {
-    let _ = state.player.lock().unwrap().remove_from_queue(index, state.clone(), app);    
+    let removed_current = state.player.request(|reply| PlayerCommand::RemoveFromQueue(index, reply))?;
+    if removed_current {
+        update_current_song_played(state, app);
+    }
     Ok(())
 }
 
 #[tauri::command]
 pub async fn player_remove_multiple_songs(state: State<AppState, '_>, app: tauri::AppHandle, songs: Vec<SongTable>) -> Result<(), String> {
     
-    let old_song: SongTable = state.player.lock().unwrap().get_current_song().unwrap();
+    let old_song: SongTable = state.player.request(PlayerCommand::GetCurrentSong)?.map_err(|_| "Nothing is playing".to_string())?;
 
     let mut is_song_removed: bool = false;
 
-    println!("{:?}", &is_song_removed);
-
     for song in songs {
         if old_song.path == song.path {
             is_song_removed = true;
         }
-        let _ = state.player.lock().unwrap().remove_from_queue_by_value(song.path);
+        state.player.send(PlayerCommand::RemoveFromQueueByValue(song.path));
     }
 
     if is_song_removed == true {
@@ -73,17 +77,18 @@ pub async fn player_remove_multiple_songs(state: State<AppState, '_>, app: tauri
 
 #[tauri::command]
 pub async fn player_setup_queue_and_song(state: State<AppState, '_>, queue: Vec<SongTable>, index: usize) -> Result<(), ()> {
-    state.player.lock().unwrap().clear_queue();
-    state.player.lock().unwrap().stop_song();
+    state.player.send(PlayerCommand::ClearQueue);
+    state.player.send(PlayerCommand::Stop);
 
     // let q_length = queue.len();
-    state.player.lock().unwrap().set_queue(queue);
-    let _ = state.player.lock().unwrap().update_current_index(index);
+    state.player.send(PlayerCommand::SetQueue(queue));
+    state.player.send(PlayerCommand::SetAlbumQueue(false));
+    let _ = state.player.request(|reply| PlayerCommand::UpdateIndex(index, reply));
     // Setup the first two songs to ready to play
-    let res = state.player.lock().unwrap().load_song(index);
+    let res = state.player.request(|reply| PlayerCommand::LoadSong(index, reply)).and_then(|r| r);
 
     if res.is_err() {
-        state.player.lock().unwrap().clear_queue();
+        state.player.send(PlayerCommand::ClearQueue);
         let _ = db::clear_queue(state.clone()).await;
         return Err(());
     }
@@ -93,75 +98,58 @@ pub async fn player_setup_queue_and_song(state: State<AppState, '_>, queue: Vec<
 
 #[tauri::command]
 pub fn player_get_queue_length(state: State<AppState, '_>) -> Result<usize, String> {
-    let q_length = state.player.lock().unwrap().get_queue_length();    
+    let q_length = state.player.request(PlayerCommand::GetQueueLength)?;    
     Ok(q_length)
 }
 
 #[tauri::command]
 pub fn player_update_queue_and_pos(state: State<AppState, '_>, queue: Vec<SongTable>, index: usize) -> Result<(), String>  {
-    state.player.lock().unwrap().set_queue(queue);
-    let _ = state.player.lock().unwrap().update_current_index(index);
+    state.player.send(PlayerCommand::SetQueue(queue));
+    let _ = state.player.request(|reply| PlayerCommand::UpdateIndex(index, reply));
     Ok(())    
 }
 
 #[tauri::command]
 pub fn player_update_pos(state: State<AppState, '_>, index: usize) -> Result<(), String>  {
-    let _ = state.player.lock().unwrap().update_current_index(index);
+    let _ = state.player.request(|reply| PlayerCommand::UpdateIndex(index, reply));
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_clear_queue(app: tauri::AppHandle, state: State<AppState, '_>) -> Result<(), String>  {
     let _ = app.emit("queue-cleared", true);
-    state.player.lock().unwrap().clear_queue();  
+    state.player.send(PlayerCommand::ClearQueue);  
     Ok(())
 }
 
 #[tauri::command]
-pub async fn player_load_album(state: State<AppState, '_>, app: tauri::AppHandle, queue: Vec<SongTable>, index: usize) -> Result<(), String> {
-    let q = queue.clone();
-    let _ = state.player.lock().unwrap().clear_queue();
-
-    state.player.lock().unwrap().stop_song();
-    state.player.lock().unwrap().set_queue(queue);
-    
+pub async fn player_load_album(state: State<AppState, '_>, queue: Vec<SongTable>, index: usize) -> Result<(), String> {
+    load_queue(state, queue, index, false).await
+}
 
-    let song_status = state.player.lock().unwrap().load_song(index);
-    if song_status.is_err() {
-        let q_length = state.player.lock().unwrap().get_queue_length();
+// Replace the queue and start playing at the index
+// Songs that can't be loaded are skipped by the player (and kept in the playback errors), following the repeat mode
+// is_album - the queue is a single album, used by ReplayGain
+pub async fn load_queue(state: State<'_, AppState>, queue: Vec<SongTable>, index: usize, is_album: bool) -> Result<(), String> {
+    state.player.send(PlayerCommand::ClearQueue);
 
-        for i in index..q_length {
-            if *state.songs_being_added.lock().unwrap() > 0 { break; }
+    state.player.send(PlayerCommand::Stop);
+    state.player.send(PlayerCommand::SetQueue(queue));
+    state.player.send(PlayerCommand::SetAlbumQueue(is_album));
+    state.player.send(PlayerCommand::JumpToSong(index));
 
-            let s_status = state.player.lock().unwrap().load_song(i);
-            if s_status.is_ok() {
-                let _ = state.player.lock().unwrap().update_current_index(i);
-                state.player.lock().unwrap().play_song();
-                break;
-            }
-            else {
-                let _ = app.emit("remove-song", GetCurrentSong{q: q[i].clone() });
-                let _ = db::remove_song(&state.pool, q[i].clone()).await;
-            }
-        }
-    }
-    else {
-        let _ = state.player.lock().unwrap().update_current_index(index);
-        state.player.lock().unwrap().play_song();
-    }
-    
-    Ok(())    
+    Ok(())
 }
 
 #[tauri::command]
 pub fn player_load_song(state: State<AppState, '_>, index: usize) -> Result<Result<(), String>, String> {
-    let res = state.player.lock().unwrap().load_song(index);    
+    let res = state.player.request(|reply| PlayerCommand::LoadSong(index, reply)).and_then(|r| r);    
     Ok(res)
 }
 
 #[tauri::command]
 pub fn player_get_current_song(state: State<AppState, '_>) -> Result<SongTable, String> {
-    let current_song = state.player.lock().unwrap().get_current_song();    
+    let current_song = state.player.request(PlayerCommand::GetCurrentSong)?;    
     if current_song.is_ok() {
         Ok(current_song.unwrap())
     }
@@ -173,12 +161,65 @@ pub fn player_get_current_song(state: State<AppState, '_>) -> Result<SongTable,
 
 #[tauri::command]
 pub fn player_get_current_position(state: State<AppState, '_>) -> Result<usize, String> {
-    let current_position = state.player.lock().unwrap().get_current_position();    
+    let current_position = state.player.request(PlayerCommand::GetCurrentPosition)?;    
     Ok(current_position)
 }
 
 
 
+// ----------------- Queue Edit Commands
+// The player plans the edit, both queue tables are rewritten here and the player only changes its queue once they are saved
+
+// Play the songs right after the one that is playing
+#[tauri::command]
+pub async fn player_play_next(state: State<AppState, '_>, app: tauri::AppHandle, songs: Vec<SongTable>) -> Result<(), String> {
+    if let Some(edit) = state.player.request(|reply| PlayerCommand::PlanPlayNext(songs, reply))? {
+        save_queue_edit(&state.player, &state.pool, edit).await?;
+        let _ = app.emit("queue-changed", false);
+    }
+    Ok(())
+}
+
+// Moves the songs at the indexes to `to` (an index from before the move), returns the new index of the playing song
+#[tauri::command]
+pub async fn player_move_in_queue(state: State<AppState, '_>, app: tauri::AppHandle, indexes: Vec<usize>, to: usize) -> Result<usize, String> {
+    let Some(edit) = state.player.request(|reply| PlayerCommand::PlanMoveInQueue(indexes, to, reply))?? else {
+        return state.player.request(PlayerCommand::GetCurrentPosition);
+    };
+    let snapshot = save_queue_edit(&state.player, &state.pool, edit).await?;
+    let _ = app.emit("queue-changed", false);
+    Ok(snapshot.position)
+}
+
+// Returns false when there was nothing to undo
+#[tauri::command]
+pub async fn player_undo_queue_edit(state: State<AppState, '_>, app: tauri::AppHandle) -> Result<bool, String> {
+    let Some(edit) = state.player.request(PlayerCommand::PlanUndoQueueEdit)? else {
+        return Ok(false);
+    };
+    save_queue_edit(&state.player, &state.pool, edit).await?;
+    let _ = app.emit("queue-changed", false);
+    Ok(true)
+}
+
+// A failed write leaves the player's queue as it was
+// If the queue changed while the tables were written, they are written again from the player's queue
+pub async fn save_queue_edit(player: &PlayerHandle, pool: &Pool<Sqlite>, edit: QueueEdit) -> Result<QueueSnapshot, String> {
+    db::sync_queue(pool, &edit.queue.songs, edit.queue.shuffled).await
+        .map_err(|e| format!("Error saving the queue: {}", e))?;
+
+    match player.request(|reply| PlayerCommand::ApplyQueueEdit(edit, reply))? {
+        Ok(snapshot) => Ok(snapshot),
+        Err(e) => {
+            let queue = player.request(PlayerCommand::GetQueueSnapshot)?;
+            db::sync_queue(pool, &queue.songs, queue.shuffled).await?;
+            Err(e)
+        }
+    }
+}
+
+
+
 // ----------------- Play Commands
 
 #[tauri::command(rename_all = "snake_case")]
@@ -250,7 +291,7 @@ pub async fn play_album(state: State<AppState, '_>, app: tauri::AppHandle, album
 
     if shuffled {
         helper::shuffle(&mut album);
-        let res = player_load_album(state.clone(), app.clone(), album.clone(), index).await;
+        let res = load_queue(state.clone(), album.clone(), index, true).await;
 
         if res.is_ok() {
             update_current_song_played(state.clone(), app.clone());
@@ -262,7 +303,7 @@ pub async fn play_album(state: State<AppState, '_>, app: tauri::AppHandle, album
         }        
     }
     else {
-        let res = player_load_album(state.clone(), app.clone(), album.clone(), index).await;
+        let res = load_queue(state.clone(), album.clone(), index, true).await;
 
         if res.is_ok() {
             update_current_song_played(state.clone(), app.clone());
@@ -401,7 +442,7 @@ pub async fn play_selection(state: State<AppState, '_>, app: tauri::AppHandle, s
 #[tauri::command(rename_all = "snake_case")]
 pub async fn play_song_in_queue(state: State<AppState, '_>, index: usize) -> Result<(), String> {
 
-    state.player.lock().unwrap().jump_to_song(index);
+    state.player.send(PlayerCommand::JumpToSong(index));
     
     Ok(())
 
@@ -413,102 +454,815 @@ pub async fn play_song_in_queue(state: State<AppState, '_>, index: usize) -> Res
 
 #[tauri::command]
 pub fn player_play(state: State<AppState, '_>) -> Result<(), String> {
-    state.player.lock().unwrap().play_song();
+    state.player.send(PlayerCommand::Play);
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_pause(state: State<AppState, '_>) -> Result<(), String> {
-    state.player.lock().unwrap().pause_song();
+    state.player.send(PlayerCommand::Pause);
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_stop(state: State<AppState, '_>) -> Result<(), String> {
-    state.player.lock().unwrap().stop_song();
+    state.player.send(PlayerCommand::Stop);
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_set_current(state: State<AppState, '_>, index: usize) -> Result<(), String> {
-    state.player.lock().unwrap().update_current_index(index)?;
+    state.player.request(|reply| PlayerCommand::UpdateIndex(index, reply))??;
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_is_paused(state: State<AppState, '_>) -> Result<bool, String> {
-    let res = state.player.lock().unwrap().check_is_paused();
+    let res = state.player.request(PlayerCommand::IsPaused)?;
     Ok(res)
 }
 
 #[tauri::command]
 pub fn player_check_repeat(state: State<AppState, '_>) -> Result<i64, String> {
-    let res = state.player.lock().unwrap().check_repeat_mode();
+    let res = state.player.request(PlayerCommand::GetRepeatMode)?;
     Ok(res)
 }
 
 #[tauri::command]
 pub fn player_set_repeat_mode(state: State<AppState, '_>, mode: i64) -> Result<(), String> {
-    state.player.lock().unwrap().set_repeat_mode(mode);
+    state.player.send(PlayerCommand::SetRepeatMode(mode));
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_set_volume(state: State<AppState, '_>, volume: f32) -> Result<(), String> {
-    state.player.lock().unwrap().set_volume(volume);
+    state.player.send(PlayerCommand::SetVolume(volume));
     Ok(())
 }
 
 #[tauri::command]
-pub fn player_set_seek(state: State<AppState, '_>, pos: u64) -> Result<(), String> {
-    state.player.lock().unwrap().seek(pos);
+pub fn player_set_seek(state: State<AppState, '_>, pos: f64) -> Result<(), String> {
+    // Seconds, with the fraction kept down to the millisecond
+    if !pos.is_finite() || pos < 0.0 {
+        return Err("Invalid seek position".to_string());
+    }
+    state.player.send(PlayerCommand::Seek(Duration::from_millis((pos * 1000.0).round() as u64)));
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_next_song(state: State<AppState, '_>) -> Result<(), String> {
-    state.player.lock().unwrap().next_song();
+    state.player.send(PlayerCommand::NextSong);
     Ok(())
 }
 
 #[tauri::command]
 pub fn player_previous_song(state: State<AppState, '_>) -> Result<(), String> {
-    state.player.lock().unwrap().previous_song();
+    state.player.send(PlayerCommand::PreviousSong);
     Ok(())
 }
 
 #[tauri::command]
-pub fn player_get_song_pos(state: State<AppState, '_>) -> Result<(), String> {
-    state.player.lock().unwrap().get_song_pos();
-    Ok(())
+// Milliseconds into the current song
+pub fn player_get_song_pos(state: State<AppState, '_>) -> Result<u64, String> {
+    Ok(state.player.request(PlayerCommand::GetSongPos)?.as_millis() as u64)
 }
 
 #[tauri::command]
 pub fn player_get_sink_length(state: State<AppState, '_>) -> Result<usize, String> {
-    Ok(state.player.lock().unwrap().get_sink_length())
+    state.player.request(PlayerCommand::GetSinkLength)
 }
 
 #[tauri::command]
 pub fn player_get_shuffle(state: State<AppState, '_>) -> Result<bool, String> {
-    Ok(state.player.lock().unwrap().get_shuffle())
+    state.player.request(PlayerCommand::GetShuffle)
 }
 
 #[tauri::command]
 pub fn player_set_shuffle(state: State<AppState, '_>, mode: bool) -> Result<(), String> {
-    let _ = state.player.lock().unwrap().set_shuffle(mode);
+    state.player.send(PlayerCommand::SetShuffle(mode));
+    Ok(())
+}
+
+
+
+// ----------------- Equalizer Commands
+
+#[tauri::command]
+pub fn player_get_equalizer(state: State<AppState, '_>) -> Result<EqualizerSettings, String> {
+    state.player.request(PlayerCommand::GetEqualizer)
+}
+
+// Apply and save the bands
+#[tauri::command]
+pub async fn player_set_equalizer(state: State<AppState, '_>, settings: EqualizerSettings) -> Result<(), String> {
+    let settings = EqualizerSettings { bands: equalizer::clamp_bands(&settings.bands), ..settings };
+    state.player.send(PlayerCommand::SetEqualizer(settings.clone()));
+    db::set_equalizer_settings(&state.pool, &settings).await
+}
+
+// Apply the bands to the current song without saving them, used while dragging the sliders
+#[tauri::command]
+pub fn player_preview_equalizer(state: State<AppState, '_>, settings: EqualizerSettings) -> Result<(), String> {
+    let settings = EqualizerSettings { bands: equalizer::clamp_bands(&settings.bands), ..settings };
+    state.player.send(PlayerCommand::SetEqualizer(settings));
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_equalizer_presets(state: State<AppState, '_>) -> Result<Vec<EqualizerPreset>, String> {
+    let mut presets = equalizer::built_in_presets();
+    presets.append(&mut db::get_equalizer_presets(&state.pool).await?);
+    Ok(presets)
+}
+
+// Save a custom preset, replaces any custom preset with the same name
+#[tauri::command]
+pub async fn save_equalizer_preset(state: State<AppState, '_>, preset: EqualizerPreset) -> Result<(), String> {
+    if equalizer::built_in_presets().iter().any(|p| p.name == preset.name) {
+        return Err("A built in preset already uses that name".to_string());
+    }
+
+    let mut presets = db::get_equalizer_presets(&state.pool).await?;
+    presets.retain(|p| p.name != preset.name);
+    presets.push(EqualizerPreset {
+        bands: equalizer::clamp_bands(&preset.bands),
+        built_in: false,
+        ..preset
+    });
+
+    db::set_equalizer_presets(&state.pool, &presets).await
+}
+
+#[tauri::command]
+pub async fn delete_equalizer_preset(state: State<AppState, '_>, name: String) -> Result<(), String> {
+    let mut presets = db::get_equalizer_presets(&state.pool).await?;
+    presets.retain(|p| p.name != name);
+    db::set_equalizer_presets(&state.pool, &presets).await
+}
+
+// Switch to a preset and keep the equalizer's enabled state
+#[tauri::command]
+pub async fn use_equalizer_preset(state: State<AppState, '_>, name: String) -> Result<EqualizerSettings, String> {
+    let presets = get_equalizer_presets(state.clone()).await?;
+
+    if let Some(preset) = presets.into_iter().find(|p| p.name == name) {
+        let settings = EqualizerSettings {
+            enabled: state.player.request(PlayerCommand::GetEqualizer)?.enabled,
+            preamp: preset.preamp,
+            bands: preset.bands,
+            preset: preset.name
+        };
+        player_set_equalizer(state, settings.clone()).await?;
+        Ok(settings)
+    }
+    else {
+        log::error!("Use Equalizer Preset - Preset does not exist: {:?}", &name);
+        Err("Preset does not exist".to_string())
+    }
+}
+
+
+
+// ----------------- Channel Commands
+
+#[tauri::command]
+pub fn player_get_channels(state: State<AppState, '_>) -> Result<ChannelSettings, String> {
+    state.player.request(PlayerCommand::GetChannels)
+}
+
+// Balance is -1 (left) to 1 (right), crossfeed is 0 (off) to 3
+#[tauri::command]
+pub async fn player_set_channels(state: State<AppState, '_>, settings: ChannelSettings) -> Result<(), String> {
+    let settings = channels::clamp_settings(settings);
+    state.player.send(PlayerCommand::SetChannels(settings.clone()));
+    db::set_channel_settings(&state.pool, &settings).await
+}
+
+
+
+// ----------------- ReplayGain Commands
+
+// 0 - Off, 1 - Track, 2 - Album
+#[tauri::command]
+pub fn player_get_replaygain_mode(state: State<AppState, '_>) -> Result<i64, String> {
+    state.player.request(PlayerCommand::GetReplayGainMode)
+}
+
+#[tauri::command]
+pub async fn player_set_replaygain_mode(state: State<AppState, '_>, mode: i64) -> Result<(), String> {
+    if !(0..=2).contains(&mode) {
+        return Err("Invalid ReplayGain mode".to_string());
+    }
+    state.player.send(PlayerCommand::SetReplayGainMode(mode));
+    db::set_replaygain_mode(&state.pool, mode).await
+}
+
+
+
+// ----------------- Crossfade Commands
+
+#[tauri::command]
+pub fn player_get_crossfade(state: State<AppState, '_>) -> Result<CrossfadeSettings, String> {
+    state.player.request(PlayerCommand::GetCrossfade)
+}
+
+// Duration is in seconds (0 - 12), 0 turns crossfade off
+#[tauri::command]
+pub async fn player_set_crossfade(state: State<AppState, '_>, settings: CrossfadeSettings) -> Result<(), String> {
+    if !settings.duration.is_finite() {
+        return Err("Invalid crossfade duration".to_string());
+    }
+    let settings = CrossfadeSettings { duration: settings.duration.clamp(0.0, 12.0), ..settings };
+
+    state.player.send(PlayerCommand::SetCrossfade(settings.clone()));
+    db::set_crossfade_settings(&state.pool, &settings).await
+}
+
+
+
+// ----------------- Volume Ramp Commands
+
+// Length of the fade on play, pause, seek and skips in ms
+#[tauri::command]
+pub fn player_get_volume_ramp(state: State<AppState, '_>) -> Result<u64, String> {
+    Ok(state.player.request(PlayerCommand::GetVolumeRamp)?.as_millis() as u64)
+}
+
+// 0 - 500 ms, 0 turns the ramps off
+#[tauri::command(rename_all = "snake_case")]
+pub async fn player_set_volume_ramp(state: State<AppState, '_>, duration_ms: u64) -> Result<(), String> {
+    let duration_ms = duration_ms.min(ramp::MAX_RAMP_MS);
+    state.player.send(PlayerCommand::SetVolumeRamp(Duration::from_millis(duration_ms)));
+    db::set_volume_ramp(&state.pool, duration_ms).await
+}
+
+
+
+// ----------------- Speed Commands
+
+#[tauri::command]
+pub fn player_get_speed(state: State<AppState, '_>) -> Result<PlaybackSpeed, String> {
+    state.player.request(PlayerCommand::GetSpeed)
+}
+
+// Speed is clamped to 0.5x - 3x, keep_pitch stops voices from sounding like chipmunks
+#[tauri::command(rename_all = "snake_case")]
+pub fn player_set_speed(state: State<AppState, '_>, speed: f32, keep_pitch: bool) -> Result<(), String> {
+    if !speed.is_finite() {
+        return Err("Invalid playback speed".to_string());
+    }
+    state.player.send(PlayerCommand::SetSpeed(speed.clamp(tempo::MIN_SPEED, tempo::MAX_SPEED), keep_pitch));
     Ok(())
 }
 
+// Seconds into the current song, stays correct at any speed
+#[tauri::command]
+pub fn player_get_position(state: State<AppState, '_>) -> Result<f64, String> {
+    state.player.request(PlayerCommand::GetPosition)
+}
+
+
+
+// ----------------- Output Device Commands
+
+#[tauri::command]
+pub fn get_output_devices() -> Result<Vec<OutputDevice>, String> {
+    Ok(output::list_output_devices())
+}
+
+// The saved device, None when following the system default
+#[tauri::command]
+pub async fn get_output_device(state: State<AppState, '_>) -> Result<Option<String>, String> {
+    db::get_output_device(&state.pool).await
+}
+
+// Switch playback to another device without restarting, None goes back to the system default
+#[tauri::command]
+pub async fn set_output_device(state: State<AppState, '_>, name: Option<String>) -> Result<(), String> {
+    let mixer = state.output.open(name.clone())?;
+    let radio = state.player.request(|reply| PlayerCommand::SwitchOutput(mixer, reply))?;
+    db::set_output_device(&state.pool, name.as_deref()).await?;
+
+    // A live stream can't move to the new device, it is opened again there
+    if let Some((station, paused)) = radio {
+        let source = radio::open_stream(&station.url).await?;
+        state.player.send(PlayerCommand::PlayRadio(station, source));
+        if paused {
+            state.player.send(PlayerCommand::Pause);
+        }
+    }
+    Ok(())
+}
+
+
+
+// ----------------- Visualizer Commands
+
+// While on, the spectrum and levels of what is playing are sent as "visualizer-frame" events
+#[tauri::command]
+pub fn player_set_visualizer(state: State<AppState, '_>, enabled: bool) -> Result<(), String> {
+    state.player.send(PlayerCommand::SetVisualizer(enabled));
+    Ok(())
+}
+
+#[tauri::command]
+pub fn player_get_visualizer(state: State<AppState, '_>) -> Result<bool, String> {
+    state.player.request(PlayerCommand::GetVisualizer)
+}
+
+
+
+// ----------------- A-B Loop Commands
+
+// position_ms is optional, the current position is used without it
+#[tauri::command(rename_all = "snake_case")]
+pub fn player_set_loop_a(state: State<AppState, '_>, position_ms: Option<u64>) -> Result<(), String> {
+    state.player.request(|reply| PlayerCommand::SetLoopA(position_ms.map(Duration::from_millis), reply))?
+}
+
+// Setting B starts the loop
+#[tauri::command(rename_all = "snake_case")]
+pub fn player_set_loop_b(state: State<AppState, '_>, position_ms: Option<u64>) -> Result<(), String> {
+    state.player.request(|reply| PlayerCommand::SetLoopB(position_ms.map(Duration::from_millis), reply))?
+}
+
+#[tauri::command]
+pub fn player_clear_loop(state: State<AppState, '_>) -> Result<(), String> {
+    state.player.send(PlayerCommand::ClearLoop);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn player_get_loop(state: State<AppState, '_>) -> Result<AbLoopState, String> {
+    state.player.request(PlayerCommand::GetLoop)
+}
+
+
+
+// ----------------- Chapter Commands
+
+#[tauri::command]
+pub async fn get_chapters(state: State<AppState, '_>, path: String) -> Result<Vec<Chapter>, String> {
+    db::get_chapters(&state.pool, &path).await
+}
+
+// The playing song's chapters and where it is now
+async fn current_chapters(state: &State<'_, AppState>) -> Result<(Vec<Chapter>, Duration), String> {
+    let song = state.player.request(PlayerCommand::GetCurrentSong)?.map_err(|_| "Nothing is playing".to_string())?;
+    let chapters = db::get_chapters(&state.pool, &song.path).await?;
+    if chapters.is_empty() {
+        return Err("This song has no chapters".to_string());
+    }
+    let position = state.player.request(PlayerCommand::GetSongPos)?;
+    Ok((chapters, position))
+}
+
+fn seek_to_chapter(state: &State<'_, AppState>, chapters: &[Chapter], index: usize) -> Result<usize, String> {
+    let chapter = chapters.get(index).ok_or("There is no chapter with that number")?;
+    state.player.send(PlayerCommand::Seek(Duration::from_millis(chapter.start_ms.max(0) as u64)));
+    Ok(index)
+}
+
+// The commands below return the index of the chapter they went to
+#[tauri::command(rename_all = "snake_case")]
+pub async fn player_seek_chapter(state: State<AppState, '_>, index: usize) -> Result<usize, String> {
+    let (chapters, _) = current_chapters(&state).await?;
+    seek_to_chapter(&state, &chapters, index)
+}
+
+#[tauri::command]
+pub async fn player_next_chapter(state: State<AppState, '_>) -> Result<usize, String> {
+    let (chapters, position) = current_chapters(&state).await?;
+    let next = chapters::chapter_at(&chapters, position).map(|i| i + 1).unwrap_or(0);
+    if next >= chapters.len() {
+        return Err("This is the last chapter".to_string());
+    }
+    seek_to_chapter(&state, &chapters, next)
+}
+
+// Goes back to the start of the chapter, or to the one before if it only just started
+#[tauri::command]
+pub async fn player_previous_chapter(state: State<AppState, '_>) -> Result<usize, String> {
+    let (chapters, position) = current_chapters(&state).await?;
+    let current = chapters::chapter_at(&chapters, position).unwrap_or(0);
+    let into_chapter = position.saturating_sub(Duration::from_millis(chapters[current].start_ms.max(0) as u64));
+
+    let index = if into_chapter < chapters::PREVIOUS_RESTART { current.saturating_sub(1) } else { current };
+    seek_to_chapter(&state, &chapters, index)
+}
+
+
+
+// ----------------- Radio Commands
+
+#[tauri::command]
+pub async fn get_radio_stations(state: State<AppState, '_>) -> Result<Vec<RadioStation>, String> {
+    db::get_radio_stations(&state.pool).await
+}
+
+#[tauri::command]
+pub async fn add_radio_station(state: State<AppState, '_>, name: String, url: String) -> Result<(), String> {
+    let url = url.trim();
+    if !radio::is_stream_url(url) {
+        return Err("Radio stations need an http or https address".to_string());
+    }
+    let name = if name.trim().is_empty() { url } else { name.trim() };
+
+    match db::add_radio_station(&state.pool, name, url).await? {
+        true => Ok(()),
+        false => Err("This station is already saved".to_string())
+    }
+}
+
+#[tauri::command]
+pub async fn remove_radio_station(state: State<AppState, '_>, id: i64) -> Result<(), String> {
+    db::remove_radio_station(&state.pool, id).await
+}
+
+// Add every station in an M3U or PLS playlist, returns how many were new
+#[tauri::command]
+pub async fn import_radio_stations(state: State<AppState, '_>, path: String) -> Result<usize, String> {
+    let contents = fs::read_to_string(&path).map_err(|e| format!("Error reading the playlist: {:?}", e))?;
+    let mut added = 0;
+
+    for (name, url) in radio::parse_playlist(&path, &contents) {
+        if db::add_radio_station(&state.pool, &name, &url).await? {
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+// The queue is left where it was, playing anything from it stops the radio
+// "radio-now-playing" is sent when the station or its title changes
+#[tauri::command(rename_all = "snake_case")]
+pub async fn player_play_radio(state: State<AppState, '_>, station_id: i64) -> Result<(), String> {
+    let station = db::get_radio_station(&state.pool, station_id).await?;
+    let source = radio::open_stream(&station.url).await?;
+    state.player.send(PlayerCommand::PlayRadio(station, source));
+    Ok(())
+}
+
+#[tauri::command]
+pub fn player_get_radio(state: State<AppState, '_>) -> Result<Option<RadioNowPlaying>, String> {
+    state.player.request(PlayerCommand::GetRadio)
+}
+
+
+
+// ----------------- Podcast Commands
+
+#[tauri::command]
+pub async fn get_podcast_feeds(state: State<AppState, '_>) -> Result<Vec<PodcastFeed>, String> {
+    db::get_podcast_feeds(&state.pool).await
+}
+
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_podcast_episodes(state: State<AppState, '_>, feed_id: i64) -> Result<Vec<PodcastEpisode>, String> {
+    db::get_podcast_episodes(&state.pool, feed_id).await
+}
+
+// Saves the feed and every episode in it, nothing is downloaded until asked for
+#[tauri::command]
+pub async fn podcast_subscribe(state: State<AppState, '_>, url: String) -> Result<PodcastFeed, String> {
+    let url = url.trim();
+    if !radio::is_stream_url(url) {
+        return Err("Podcast feeds need an http or https address".to_string());
+    }
+
+    let feed = podcast::fetch_feed(url).await?;
+    let id = db::add_podcast_feed(&state.pool, url, &feed).await?.ok_or("This podcast is already subscribed to")?;
+    db::add_podcast_episodes(&state.pool, id, &feed).await?;
+    db::get_podcast_feed(&state.pool, id).await
+}
+
+// Removes the feed, its episodes and any downloaded files
+#[tauri::command(rename_all = "snake_case")]
+pub async fn podcast_unsubscribe(state: State<AppState, '_>, feed_id: i64) -> Result<(), String> {
+    db::remove_podcast_feed(&state.pool, feed_id).await?;
+    let _ = fs::remove_dir_all(podcast::feed_dir(feed_id));
+    Ok(())
+}
+
+// Fetch one feed again, or all of them if no feed_id is given, returns how many episodes are new
+// A feed that can't be fetched when refreshing all of them is logged and skipped
+#[tauri::command(rename_all = "snake_case")]
+pub async fn podcast_refresh(state: State<AppState, '_>, feed_id: Option<i64>) -> Result<usize, String> {
+    let feeds = match feed_id {
+        Some(id) => vec![db::get_podcast_feed(&state.pool, id).await?],
+        None => db::get_podcast_feeds(&state.pool).await?
+    };
+
+    let mut added = 0;
+    for saved in feeds {
+        let feed = match podcast::fetch_feed(&saved.url).await {
+            Ok(feed) => feed,
+            Err(e) if feed_id.is_some() => return Err(e),
+            Err(e) => {
+                log::error!("Podcast Refresh - {} - {}", saved.url, e);
+                continue;
+            }
+        };
+        db::update_podcast_feed(&state.pool, saved.id, &feed).await?;
+        added += db::add_podcast_episodes(&state.pool, saved.id, &feed).await?;
+    }
+    Ok(added)
+}
+
+// "podcast-download-progress" is sent while it downloads
+#[tauri::command(rename_all = "snake_case")]
+pub async fn podcast_download_episode(state: State<AppState, '_>, app: tauri::AppHandle, episode_id: i64) -> Result<PodcastEpisode, String> {
+    let episode = db::get_podcast_episode(&state.pool, episode_id).await?;
+    if episode.file_path.as_ref().is_some_and(|path| Path::new(path).exists()) {
+        return Ok(episode);
+    }
+
+    let dest = podcast::episode_path(episode.feed_id, episode.id, &episode.audio_url);
+    podcast::download_episode(&app, episode.id, &episode.audio_url, &dest).await?;
+    db::set_podcast_episode_file(&state.pool, episode.id, Some(dest.to_string_lossy().to_string())).await?;
+    db::get_podcast_episode(&state.pool, episode_id).await
+}
+
+#[tauri::command(rename_all = "snake_case")]
+pub async fn podcast_delete_download(state: State<AppState, '_>, episode_id: i64) -> Result<(), String> {
+    let episode = db::get_podcast_episode(&state.pool, episode_id).await?;
+    if let Some(path) = episode.file_path {
+        let _ = fs::remove_file(path);
+    }
+    db::set_podcast_episode_file(&state.pool, episode_id, None).await
+}
+
+// Episodes are also marked played by the player once they are listened to the end
+#[tauri::command(rename_all = "snake_case")]
+pub async fn podcast_mark_played(state: State<AppState, '_>, episode_id: i64, played: bool) -> Result<(), String> {
+    db::set_podcast_episode_played(&state.pool, episode_id, played).await
+}
+
+// Replaces the queue with the episode, it isn't saved with the queue since it isn't part of the library
+#[tauri::command(rename_all = "snake_case")]
+pub async fn play_podcast_episode(state: State<AppState, '_>, app: tauri::AppHandle, episode_id: i64) -> Result<(), String> {
+    let episode = db::get_podcast_episode(&state.pool, episode_id).await?;
+    let song = podcast::episode_song(&episode).ok_or("Download the episode before playing it")?;
+
+    // The queue tables are emptied too, so a restored session doesn't pick a song from the old queue
+    db::sync_queue(&state.pool, &[song.clone()], false).await?;
+    load_queue(state.clone(), vec![song], 0, false).await?;
+    update_current_song_played(state.clone(), app.clone());
+    let _ = app.emit("queue-changed", false);
+    Ok(())
+}
+
+
+
+// ----------------- Sleep Timer Commands
+
+// mode - "minutes" (amount minutes), "end_of_track", or "tracks" (amount more songs after this one)
+// The volume fades out over the last fade_seconds before playback stops
+#[tauri::command(rename_all = "snake_case")]
+pub fn player_start_sleep_timer(state: State<AppState, '_>, mode: String, amount: u32, fade_seconds: f32) -> Result<(), String> {
+    if !fade_seconds.is_finite() {
+        return Err("Invalid fade length".to_string());
+    }
+    let fade = Duration::from_secs_f32(fade_seconds.clamp(0.0, sleep_timer::MAX_FADE_SECS));
+
+    let timer = match mode.as_str() {
+        "minutes" if amount > 0 => SleepTimer::minutes(amount, fade),
+        "end_of_track" => SleepTimer::tracks(0, fade),
+        "tracks" => SleepTimer::tracks(amount, fade),
+        _ => return Err(format!("Invalid sleep timer: {} {}", mode, amount))
+    };
+    state.player.send(PlayerCommand::StartSleepTimer(timer));
+    Ok(())
+}
+
+// Adds minutes or songs, depending on how the timer was started
+#[tauri::command]
+pub fn player_extend_sleep_timer(state: State<AppState, '_>, amount: u32) -> Result<(), String> {
+    state.player.request(|reply| PlayerCommand::ExtendSleepTimer(amount, reply))?
+}
+
+#[tauri::command]
+pub fn player_cancel_sleep_timer(state: State<AppState, '_>) -> Result<(), String> {
+    state.player.send(PlayerCommand::CancelSleepTimer);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn player_get_sleep_timer(state: State<AppState, '_>) -> Result<Option<SleepTimerStatus>, String> {
+    state.player.request(PlayerCommand::GetSleepTimer)
+}
+
+
+
+// ----------------- Session Commands
+
+// Called once on startup, loads the saved queue with the song paused where it was left
+#[tauri::command]
+pub async fn player_restore_session(state: State<AppState, '_>) -> Result<RestoredSession, String> {
+    let session = db::get_session(&state.pool).await?;
+    let queue = db::get_queue(state.clone(), session.shuffle).await?;
+
+    state.player.request(|reply| PlayerCommand::RestoreSession(queue, session.clone(), reply))??;
+    let song = state.player.request(PlayerCommand::GetCurrentSong)?.ok();
+
+    Ok(RestoredSession { session, song })
+}
+
+// The player also saves the session every few seconds, and it is saved when the app closes
+#[tauri::command]
+pub async fn player_save_session(state: State<AppState, '_>) -> Result<(), String> {
+    save_session(&state.player, &state.pool).await
+}
+
+pub async fn save_session(player: &PlayerHandle, pool: &Pool<Sqlite>) -> Result<(), String> {
+    // Bookmarks are written here too, the player thread might not get to them before the app closes
+    for change in player.request(PlayerCommand::FlushBookmarks)? {
+        db::save_bookmark(pool, change).await?;
+    }
+
+    match player.request(PlayerCommand::GetSession)? {
+        Some(session) => db::set_session(pool, &session).await,
+        None => Ok(())
+    }
+}
+
+
+
+// ----------------- Resume Bookmark Commands
+
+#[tauri::command]
+pub fn player_get_resume_settings(state: State<AppState, '_>) -> Result<ResumeSettings, String> {
+    state.player.request(PlayerCommand::GetResumeSettings)
+}
+
+// Songs at least min_duration_minutes long (0 is off) or in one of the genres pick up where they were left off
+#[tauri::command]
+pub async fn player_set_resume_settings(state: State<AppState, '_>, settings: ResumeSettings) -> Result<(), String> {
+    let settings = ResumeSettings {
+        genres: settings.genres.iter().map(|g| g.trim().to_string()).filter(|g| !g.is_empty()).collect(),
+        ..settings
+    };
+    state.player.send(PlayerCommand::SetResumeSettings(settings.clone()));
+    db::set_resume_settings(&state.pool, &settings).await
+}
+
+// Mark a single song to remember its position, whatever the settings are
+#[tauri::command]
+pub async fn player_set_song_resume(state: State<AppState, '_>, path: String, remember: bool) -> Result<(), String> {
+    state.player.send(PlayerCommand::SetSongResume(path.clone(), remember));
+    db::set_resume_song(&state.pool, path, remember).await
+}
+
+#[tauri::command]
+pub async fn get_resume_songs(state: State<AppState, '_>) -> Result<Vec<String>, String> {
+    db::get_resume_songs(&state.pool).await
+}
+
+// Songs that were left part way through, most recent first
+#[tauri::command]
+pub async fn get_bookmarks(state: State<AppState, '_>) -> Result<Vec<Bookmark>, String> {
+    db::get_bookmarks(&state.pool).await
+}
+
+// The song starts from the beginning next time
+#[tauri::command]
+pub async fn clear_bookmark(state: State<AppState, '_>, path: String) -> Result<(), String> {
+    state.player.send(PlayerCommand::ClearBookmark(path.clone()));
+    db::save_bookmark(&state.pool, BookmarkChange { path, position_ms: None, duration: 0 }).await
+}
+
+
+
+// ----------------- Playback Error Commands
+
+// Songs the player skipped because they couldn't be opened or decoded, newest first
+#[tauri::command]
+pub async fn get_playback_errors(state: State<AppState, '_>) -> Result<Vec<PlaybackError>, String> {
+    db::get_playback_errors(&state.pool).await
+}
+
+// Called once a song has been fixed, a song that still fails is added again the next time it is played
+#[tauri::command(rename_all = "snake_case")]
+pub async fn clear_playback_error(state: State<AppState, '_>, path: String) -> Result<(), String> {
+    db::clear_playback_error(&state.pool, path).await
+}
+
+
+
+// ----------------- Waveform Commands
+
+// Peaks (0 - 255) for the seek bar, decoded off the main thread the first time a song is asked for, then cached
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_waveform(state: State<AppState, '_>, path: String) -> Result<Vec<u8>, String> {
+    // Cue sheet tracks need their file and span from the library
+    let song = db::find_song(&state.pool, &path).await?.unwrap_or(SongTable { path, ..SongTable::default() });
+    tauri::async_runtime::spawn_blocking(move || waveform::get_waveform(&song))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+
+
+// ----------------- Loudness Analysis Commands
+
+// Measure the loudness of every song that hasn't been measured yet
+// Each song is marked when it finishes, so a cancelled or interrupted analysis continues where it stopped
+#[tauri::command]
+pub async fn analyze_loudness(state: State<AppState, '_>, app: tauri::AppHandle) -> Result<i32, String> {
+    if *state.is_loudness_scan_ongoing.lock().unwrap() {
+        log::info!("There is a Loudness Analysis already active");
+        return Ok(0);
+    }
+    *state.is_loudness_scan_ongoing.lock().unwrap() = true;
+    let _ = app.emit("loudness-started", GetScanStatus { res: true });
+
+    let songs = db::get_songs_without_loudness(&state.pool).await.unwrap_or_default();
+    let scan_length = songs.len();
+    let mut num_scanned = 0;
+    let mut num_error = 0;
+
+    log::info!("Loudness Analysis has started - {:?} songs", &scan_length);
+    let _ = app.emit("loudness-length", ScanProgress { length: scan_length, current: 0 });
+
+    // Decoding is slow, split the songs between a few threads and save the results as they come back
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = flume::unbounded();
+    let pool = threadpool::ThreadPool::new(4);
+
+    for song in songs {
+        let tx1 = tx.clone();
+        let cancelled1 = cancelled.clone();
+        pool.execute(move || {
+            if cancelled1.load(Ordering::Relaxed) {
+                return;
+            }
+            let res = loudness::analyze_file(&song);
+            let _ = tx1.send((song.path, res));
+        });
+    }
+    drop(tx);
+
+    for (path, res) in rx.iter() {
+        match res {
+            Ok(result) => {
+                let _ = db::set_song_loudness(&state.pool, &path, result.integrated, Some(result.true_peak)).await;
+            },
+            Err(e) => {
+                log::error!("Loudness Analysis - {:?} - {:?}", e, &path);
+                // Still mark it, so a broken file isn't decoded on every run
+                let _ = db::set_song_loudness(&state.pool, &path, None, None).await;
+                num_error += 1;
+            }
+        }
+
+        num_scanned += 1;
+        if num_scanned % 25 == 0 {
+            let _ = app.emit("loudness-length", ScanProgress { length: scan_length, current: num_scanned });
+        }
+
+        // Cancelled by the user, the songs left over are picked up next time
+        if !*state.is_loudness_scan_ongoing.lock().unwrap() {
+            cancelled.store(true, Ordering::Relaxed);
+            break;
+        }
+    }
+
+    *state.is_loudness_scan_ongoing.lock().unwrap() = false;
+    let _ = app.emit("loudness-length", ScanProgress { length: scan_length, current: num_scanned });
+    let _ = app.emit("loudness-finished", GetScanStatus { res: false });
+
+    log::info!("Loudness Analysis - Results ---> Total Scanned: {:?} -- Errors: {:?}", &num_scanned, &num_error);
+    Ok(num_scanned)
+}
+
+#[tauri::command]
+pub fn cancel_loudness_analysis(state: State<AppState, '_>) -> Result<(), String> {
+    *state.is_loudness_scan_ongoing.lock().unwrap() = false;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn check_for_ongoing_loudness_analysis(state: State<AppState, '_>) -> bool {
+    return *state.is_loudness_scan_ongoing.lock().unwrap()
+}
+
 
 
 // ----------------- Event Listener Commands
 #[tauri::command]
 pub fn update_current_song_played(state: State<AppState, '_>, app: tauri::AppHandle) {
     // Tell the music controls that there is a new song to look at
-    let q =  state.player.lock().unwrap().get_current_song();
+    let q = state.player.request(PlayerCommand::GetCurrentSong).unwrap_or(Err(false));
 
     if q.is_ok() {
         let res = q.unwrap();
+        // Add it to the history, the same as when the player moves on by itself
+        let pool = state.pool.clone();
+        let path = res.path.clone();
+        tauri::async_runtime::spawn(async move {
+            let _ = db::record_play(&pool, path).await;
+        });
         app.emit("get-current-song", GetCurrentSong { q: res }).unwrap();
     }
     else {
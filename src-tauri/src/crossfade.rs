@@ -0,0 +1,196 @@
+use std::{ f32::consts::FRAC_PI_2, sync::{ Arc, Mutex, atomic::{ AtomicU64, AtomicUsize, Ordering } }, time::Duration };
+use rodio::{ ChannelCount, SampleRate, Source, source::{ SeekError, UniformSourceIterator } };
+
+// Crossfade works with the gapless setup, the next song is still appended to the sink ahead of time
+// When the playing song reaches the fade, it ends early and hands the rest of itself to the next song
+// The next song then mixes that tail in while it fades in, so the sink still sees one song after another
+
+pub type BoxedSource = Box<dyn Source + Send>;
+
+struct Tail {
+    source: BoxedSource,
+    remaining: Duration
+}
+
+// ------------------- Shared Controls -------------------
+
+pub struct CrossfadeControls {
+    // Fade length in ms, 0 when crossfade is turned off (or not allowed right now)
+    duration: AtomicU64,
+    // Songs appended to the sink that haven't started playing yet
+    queued: AtomicUsize,
+    tail: Mutex<Option<Tail>>
+}
+
+impl CrossfadeControls {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            duration: AtomicU64::new(0),
+            queued: AtomicUsize::new(0),
+            tail: Mutex::new(None)
+        })
+    }
+
+    pub fn set_duration(&self, duration: Duration) {
+        self.duration.store(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration.load(Ordering::Relaxed))
+    }
+
+    // Called when a song is appended to the sink
+    pub fn track_queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn track_started(&self) {
+        let _ = self.queued.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |q| Some(q.saturating_sub(1)));
+    }
+
+    // Called whenever the sink is cleared, anything waiting to be faded is thrown away
+    pub fn reset(&self) {
+        self.queued.store(0, Ordering::Relaxed);
+        *self.tail.lock().unwrap() = None;
+    }
+}
+
+
+// ------------------- Crossfade Source -------------------
+
+pub struct Crossfade {
+    input: Option<BoxedSource>,
+    controls: Arc<CrossfadeControls>,
+    started: bool,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    total_duration: Option<Duration>,
+    // Interleaved samples played so far, used to know how close we are to the end
+    samples_played: u64,
+    // The end of the last song, mixed in while this one fades in
+    tail: Option<UniformSourceIterator<BoxedSource>>,
+    tail_len: u64,
+    tail_played: u64
+}
+
+impl Crossfade {
+    pub fn new(input: BoxedSource, controls: Arc<CrossfadeControls>) -> Self {
+        Self {
+            channels: input.channels(),
+            sample_rate: input.sample_rate(),
+            total_duration: input.total_duration(),
+            input: Some(input),
+            controls,
+            started: false,
+            samples_played: 0,
+            tail: None,
+            tail_len: 0,
+            tail_played: 0
+        }
+    }
+
+    fn position(&self) -> Duration {
+        let frames = self.samples_played / u16::from(self.channels) as u64;
+        Duration::from_secs_f64(frames as f64 / u32::from(self.sample_rate) as f64)
+    }
+
+    fn start_tail(&mut self, tail: Tail) {
+        let frames = tail.remaining.as_secs_f64() * u32::from(self.sample_rate) as f64;
+        self.tail_len = (frames as u64 * u16::from(self.channels) as u64).max(1);
+        self.tail_played = 0;
+        // The last song might have a different sample rate or channel count
+        self.tail = Some(UniformSourceIterator::new(tail.source, self.channels, self.sample_rate));
+    }
+
+    // Hand the rest of this song to the next one, only when there is a next song in the sink
+    fn should_hand_off(&self) -> Option<Duration> {
+        let fade = self.controls.duration();
+        let total = self.total_duration?;
+
+        if fade.is_zero() || self.controls.queued.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        let remaining = total.saturating_sub(self.position());
+        if remaining <= fade { Some(remaining) } else { None }
+    }
+}
+
+impl Iterator for Crossfade {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if !self.started {
+            self.started = true;
+            self.controls.track_started();
+            let tail = self.controls.tail.lock().unwrap().take();
+            if let Some(t) = tail {
+                self.start_tail(t);
+            }
+        }
+
+        // Only hand off on frame boundaries so the channels stay lined up
+        if self.samples_played % u16::from(self.channels) as u64 == 0 {
+            if let Some(remaining) = self.should_hand_off() {
+                if let Some(source) = self.input.take() {
+                    *self.controls.tail.lock().unwrap() = Some(Tail { source, remaining });
+                }
+                return None;
+            }
+        }
+
+        let sample = self.input.as_mut()?.next()?;
+        self.samples_played += 1;
+
+        if let Some(tail) = self.tail.as_mut() {
+            match tail.next() {
+                Some(tail_sample) => {
+                    // Equal power fade, keeps the volume steady through the middle of the fade
+                    let t = (self.tail_played as f32 / self.tail_len as f32).min(1.0);
+                    self.tail_played += 1;
+                    if self.tail_played >= self.tail_len {
+                        self.tail = None;
+                    }
+                    return Some(sample * (t * FRAC_PI_2).sin() + tail_sample * (t * FRAC_PI_2).cos());
+                },
+                None => {
+                    self.tail = None;
+                }
+            }
+        }
+
+        Some(sample)
+    }
+}
+
+impl Source for Crossfade {
+    fn current_span_len(&self) -> Option<usize> {
+        match &self.input {
+            Some(input) => input.current_span_len(),
+            None => Some(0)
+        }
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        if let Some(input) = self.input.as_mut() {
+            input.try_seek(pos)?;
+        }
+        // Seeking during a fade cuts the last song off
+        self.tail = None;
+        let frames = (pos.as_secs_f64() * u32::from(self.sample_rate) as f64) as u64;
+        self.samples_played = frames * u16::from(self.channels) as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,475 @@
+use std::{ fs, path::{ Path, PathBuf }, time::Duration };
+use rodio::{ ChannelCount, SampleRate, Source, source::SeekError };
+
+use crate::{ helper::{ get_section_marker, parse_replaygain }, types::{ SongTable, SongTableUpload } };
+
+// Cue sheets, one big file (usually a whole album rip) split into its tracks
+// Each track is saved as its own song with the path "<file>#<track number>", the audio is read from source_path
+// and the player only plays the part between start_ms and end_ms
+// Sheets come from the file's CUESHEET tag, or a .cue next to it ("album.cue" or "album.flac.cue")
+
+// Cue times are minutes:seconds:frames, with 75 frames a second
+const FRAMES_PER_SECOND: i64 = 75;
+
+
+// ------------------- Finding Sheets -------------------
+
+pub fn find_cue_file(path: &str) -> Option<PathBuf> {
+    [Path::new(path).with_extension("cue"), PathBuf::from(format!("{}.cue", path))]
+        .into_iter()
+        .find(|p| p.is_file())
+}
+
+// The embedded sheet wins, it always belongs to this file
+pub fn read_cue_sheet(path: &str, embedded: Option<&str>) -> Option<String> {
+    if let Some(sheet) = embedded.filter(|s| !s.trim().is_empty()) {
+        return Some(sheet.to_string());
+    }
+    let bytes = fs::read(find_cue_file(path)?).ok()?;
+    Some(decode_text(&bytes))
+}
+
+fn decode_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        // Older sheets are usually Windows-1252, close enough to Latin-1 for names
+        Err(_) => bytes.iter().map(|&b| b as char).collect()
+    }
+}
+
+
+// ------------------- Cue Sheet Parsing -------------------
+
+#[derive(Default)]
+struct CueTrack {
+    number: i32,
+    // Data tracks on mixed mode discs have no audio
+    audio: bool,
+    title: Option<String>,
+    performer: Option<String>,
+    // INDEX 01, where the track starts (INDEX 00 is the pregap, played at the end of the track before)
+    start_ms: Option<i64>,
+    replaygain_gain: Option<f32>,
+    replaygain_peak: Option<f32>
+}
+
+#[derive(Default)]
+struct CueSheet {
+    title: Option<String>,
+    performer: Option<String>,
+    genre: Option<String>,
+    date: Option<String>,
+    replaygain_album_gain: Option<f32>,
+    replaygain_album_peak: Option<f32>,
+    // (file name, the tracks in that file)
+    files: Vec<(String, Vec<CueTrack>)>
+}
+
+fn parse_cue_sheet(text: &str) -> CueSheet {
+    let mut sheet = CueSheet::default();
+    // Lines before the first TRACK of a file belong to the whole sheet
+    let mut in_track = false;
+
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                sheet.files.push((file_name(rest), vec![]));
+                in_track = false;
+            },
+            "TRACK" => {
+                let mut parts = rest.split_whitespace();
+                let number = parts.next().and_then(|n| n.parse().ok()).unwrap_or(0);
+                let audio = parts.next().is_none_or(|t| t.eq_ignore_ascii_case("AUDIO"));
+                if let Some((_, tracks)) = sheet.files.last_mut() {
+                    tracks.push(CueTrack { number, audio, ..CueTrack::default() });
+                    in_track = true;
+                }
+            },
+            "INDEX" => {
+                let mut parts = rest.split_whitespace();
+                if parts.next().and_then(|n| n.parse::<i32>().ok()) == Some(1) {
+                    if let Some(track) = current_track(&mut sheet, in_track) {
+                        track.start_ms = parts.next().and_then(parse_time);
+                    }
+                }
+            },
+            "TITLE" => match current_track(&mut sheet, in_track) {
+                Some(track) => track.title = Some(unquote(rest)),
+                None => sheet.title = Some(unquote(rest))
+            },
+            "PERFORMER" => match current_track(&mut sheet, in_track) {
+                Some(track) => track.performer = Some(unquote(rest)),
+                None => sheet.performer = Some(unquote(rest))
+            },
+            "REM" => {
+                let (key, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                let value = unquote(value);
+                match key.to_ascii_uppercase().as_str() {
+                    "GENRE" => sheet.genre = Some(value),
+                    "DATE" => sheet.date = Some(value),
+                    "REPLAYGAIN_ALBUM_GAIN" => sheet.replaygain_album_gain = parse_replaygain(&value),
+                    "REPLAYGAIN_ALBUM_PEAK" => sheet.replaygain_album_peak = parse_replaygain(&value),
+                    "REPLAYGAIN_TRACK_GAIN" => if let Some(track) = current_track(&mut sheet, in_track) {
+                        track.replaygain_gain = parse_replaygain(&value);
+                    },
+                    "REPLAYGAIN_TRACK_PEAK" => if let Some(track) = current_track(&mut sheet, in_track) {
+                        track.replaygain_peak = parse_replaygain(&value);
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    }
+    sheet
+}
+
+fn current_track(sheet: &mut CueSheet, in_track: bool) -> Option<&mut CueTrack> {
+    if !in_track {
+        return None;
+    }
+    sheet.files.last_mut()?.1.last_mut()
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    match value.strip_prefix('"') {
+        Some(rest) => rest.rsplit_once('"').map_or(rest, |(inner, _)| inner).to_string(),
+        None => value.to_string()
+    }
+}
+
+// FILE "name.wav" WAVE, only the name is kept (some sheets have a folder in front of it)
+fn file_name(value: &str) -> String {
+    let name = if value.starts_with('"') {
+        unquote(value)
+    }
+    else {
+        value.rsplit_once(char::is_whitespace).map_or(value, |(name, _)| name).trim().to_string()
+    };
+    name.rsplit(['/', '\\']).next().unwrap_or_default().to_string()
+}
+
+// mm:ss:ff -> ms
+fn parse_time(value: &str) -> Option<i64> {
+    let mut parts = value.split(':').map(|p| p.trim().parse::<i64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    Some((minutes * 60 + seconds) * 1000 + (frames * 1000 + FRAMES_PER_SECOND / 2) / FRAMES_PER_SECOND)
+}
+
+// Matched by name, then by name without the extension (rips are often converted, ex. wav to flac, without fixing the sheet)
+// A sheet with only one file is taken as this file
+fn tracks_for_file<'a>(sheet: &'a CueSheet, path: &str) -> Option<&'a Vec<CueTrack>> {
+    let file = Path::new(path);
+    let name = file.file_name()?.to_str()?;
+    let stem = file.file_stem()?.to_str()?;
+
+    sheet.files.iter()
+        .find(|(cue_file, _)| cue_file.eq_ignore_ascii_case(name))
+        .or_else(|| sheet.files.iter().find(|(cue_file, _)| {
+            Path::new(cue_file).file_stem().and_then(|s| s.to_str()).is_some_and(|s| s.eq_ignore_ascii_case(stem))
+        }))
+        .or_else(|| sheet.files.first().filter(|_| sheet.files.len() == 1))
+        .map(|(_, tracks)| tracks)
+}
+
+
+// ------------------- Splitting Songs -------------------
+
+fn section(value: &Option<String>) -> Option<i32> {
+    value.as_ref()?.chars().next().and_then(|c| get_section_marker(c.to_ascii_uppercase()))
+}
+
+// One song per track in the sheet, empty if there is no sheet or it doesn't split the file
+// Tags from the file describe the whole album, the sheet gives the track titles and performers
+pub fn split_song(song: &SongTableUpload) -> Vec<SongTableUpload> {
+    let Some(text) = song.cue_sheet.as_deref() else { return vec![] };
+    let sheet = parse_cue_sheet(text);
+    let Some(tracks) = tracks_for_file(&sheet, &song.path) else { return vec![] };
+
+    let tracks: Vec<&CueTrack> = tracks.iter().filter(|t| t.audio && t.start_ms.is_some()).collect();
+    // A sheet with one track is just the whole file
+    if tracks.len() < 2 {
+        return vec![];
+    }
+    let duration_ms = song.duration.parse::<i64>().unwrap_or(0) * 1000;
+
+    tracks.iter().enumerate().filter_map(|(i, track)| {
+        let start_ms = track.start_ms.unwrap_or(0);
+        // Every track ends where the next one starts, the last one at the end of the file
+        let end_ms = tracks.get(i + 1).and_then(|t| t.start_ms);
+        let length_ms = end_ms.unwrap_or(duration_ms) - start_ms;
+        if length_ms <= 0 {
+            return None;
+        }
+
+        let name = Some(track.title.clone().unwrap_or_else(|| format!("Track {:02}", track.number)));
+        let album = song.album.clone().or(sheet.title.clone());
+        let album_artist = song.album_artist.clone().or(sheet.performer.clone());
+        let genre = song.genre.clone().or(sheet.genre.clone());
+
+        Some(SongTableUpload {
+            path: format!("{}#{:02}", song.path, track.number),
+            song_section: section(&name),
+            album_section: section(&album),
+            artist_section: section(&album_artist),
+            genre_section: section(&genre),
+            name,
+            album,
+            album_artist,
+            genre,
+            cover: song.cover.clone(),
+            release: song.release.clone().or(sheet.date.clone()),
+            track: Some(track.number),
+            artist: track.performer.clone().or(sheet.performer.clone()).or(song.artist.clone()),
+            disc: song.disc,
+            duration: (length_ms / 1000).to_string(),
+            // The gain of the whole file is the album gain
+            replaygain_track_gain: track.replaygain_gain,
+            replaygain_track_peak: track.replaygain_peak,
+            replaygain_album_gain: sheet.replaygain_album_gain.or(song.replaygain_album_gain).or(song.replaygain_track_gain),
+            replaygain_album_peak: sheet.replaygain_album_peak.or(song.replaygain_album_peak).or(song.replaygain_track_peak),
+            chapters: vec![],
+            source_path: Some(song.path.clone()),
+            start_ms: Some(start_ms),
+            end_ms,
+            cue_sheet: None
+        })
+    }).collect()
+}
+
+// The part of the file to play, (start, end), an end of None plays to the end of the file
+pub fn song_span(song: &SongTable) -> (Duration, Option<Duration>) {
+    let start = Duration::from_millis(song.start_ms.unwrap_or(0).max(0) as u64);
+    let end = song.end_ms.map(|ms| Duration::from_millis(ms.max(0) as u64));
+    (start, end)
+}
+
+
+// ------------------- Span Source -------------------
+// Plays only a part of the decoded file, sits right on the decoder so everything after it sees a normal song
+// starting at 0 (positions, seeking, crossfade and the A-B loop all work in the track's own time)
+// Each track gets its own decoder, so the next track is preloaded and played gaplessly like any other song
+
+pub struct Span<S: Source> {
+    input: S,
+    start: Duration,
+    end: Option<Duration>,
+    // Samples left before the end, None plays to the end of the file
+    remaining: Option<u64>
+}
+
+impl<S: Source> Span<S> {
+    pub fn new(mut input: S, start: Duration, end: Option<Duration>) -> Result<Self, SeekError> {
+        if !start.is_zero() {
+            input.try_seek(start)?;
+        }
+        let mut span = Self { input, start, end, remaining: None };
+        span.remaining = span.samples_left(start);
+        Ok(span)
+    }
+
+    // Counted in whole frames from the start of the file, so the end of one track lines up with the start of the next
+    fn samples_left(&self, from: Duration) -> Option<u64> {
+        let rate = u32::from(self.input.sample_rate()) as f64;
+        let channels = u16::from(self.input.channels()) as u64;
+        let frame = |time: Duration| (time.as_secs_f64() * rate).round() as u64;
+        self.end.map(|end| frame(end).saturating_sub(frame(from)) * channels)
+    }
+}
+
+impl<S: Source> Iterator for Span<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(remaining) = self.remaining.as_mut() {
+            if *remaining == 0 {
+                return None;
+            }
+            *remaining -= 1;
+        }
+        self.input.next()
+    }
+}
+
+impl<S: Source> Source for Span<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        match self.remaining {
+            Some(remaining) => Some(self.input.current_span_len().map_or(remaining as usize, |len| len.min(remaining as usize))),
+            None => self.input.current_span_len()
+        }
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.end.or(self.input.total_duration()).map(|end| end.saturating_sub(self.start))
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(self.start + pos)?;
+        self.remaining = self.samples_left(self.start + pos);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ CueSheet, parse_cue_sheet, parse_time, split_song, tracks_for_file };
+    use crate::types::SongTableUpload;
+
+    const SHEET: &str = r#"REM GENRE Rock
+REM DATE 1999
+REM REPLAYGAIN_ALBUM_GAIN -7.50 dB
+PERFORMER "The Band"
+TITLE "The Album"
+FILE "C:\Rips\The Album.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "Intro"
+    PERFORMER "Guest"
+    REM REPLAYGAIN_TRACK_GAIN -6.00 dB
+    INDEX 01 00:00:00
+  TRACK 02 MODE1/2352
+    TITLE "Data"
+    INDEX 01 01:00:00
+  TRACK 03 AUDIO
+    TITLE "Song"
+    INDEX 00 02:59:00
+    INDEX 01 03:00:00
+  TRACK 04 AUDIO
+    INDEX 01 05:00:00
+"#;
+
+    #[test]
+    fn times_are_rounded_to_the_nearest_ms() {
+        let cases = [
+            ("00:00:00", Some(0)),
+            ("00:01:00", Some(1000)),
+            // 13.33 ms
+            ("00:00:01", Some(13)),
+            // 986.67 ms
+            ("00:00:74", Some(987)),
+            ("03:25:38", Some(205_507)),
+            ("90:00:00", Some(5_400_000)),
+            ("01:02", None),
+            ("aa:00:00", None)
+        ];
+        for (time, expected) in cases {
+            assert_eq!(parse_time(time), expected, "{}", time);
+        }
+    }
+
+    #[test]
+    fn sheet_is_parsed() {
+        let sheet = parse_cue_sheet(SHEET);
+        assert_eq!(sheet.title.as_deref(), Some("The Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+        assert_eq!(sheet.genre.as_deref(), Some("Rock"));
+        assert_eq!(sheet.date.as_deref(), Some("1999"));
+        assert_eq!(sheet.replaygain_album_gain, Some(-7.5));
+
+        // The folder in front of the file name is dropped
+        assert_eq!(sheet.files.len(), 1);
+        let (file, tracks) = &sheet.files[0];
+        assert_eq!(file, "The Album.wav");
+
+        let summary: Vec<(i32, bool, Option<&str>, Option<i64>)> = tracks.iter()
+            .map(|t| (t.number, t.audio, t.title.as_deref(), t.start_ms))
+            .collect();
+        assert_eq!(summary, vec![
+            (1, true, Some("Intro"), Some(0)),
+            (2, false, Some("Data"), Some(60_000)),
+            // INDEX 00 is the pregap, the track starts at INDEX 01
+            (3, true, Some("Song"), Some(180_000)),
+            (4, true, None, Some(300_000))
+        ]);
+        assert_eq!(tracks[0].performer.as_deref(), Some("Guest"));
+        assert_eq!(tracks[0].replaygain_gain, Some(-6.0));
+    }
+
+    fn first_track(sheet: &CueSheet, path: &str) -> Option<i32> {
+        tracks_for_file(sheet, path).map(|tracks| tracks[0].number)
+    }
+
+    #[test]
+    fn tracks_are_matched_to_their_file() {
+        let sheet = parse_cue_sheet("FILE \"Disc 1.wav\" WAVE\n  TRACK 01 AUDIO\nFILE \"Disc 2.wav\" WAVE\n  TRACK 02 AUDIO\n");
+        let cases = [
+            ("/music/Disc 2.wav", Some(2)),
+            ("/music/disc 1.WAV", Some(1)),
+            // Converted after the sheet was made
+            ("/music/Disc 2.flac", Some(2)),
+            ("/music/Other.flac", None)
+        ];
+        for (path, expected) in cases {
+            assert_eq!(first_track(&sheet, path), expected, "{}", path);
+        }
+
+        // A sheet with one file is taken as this file, whatever it is called
+        let sheet = parse_cue_sheet("FILE rip.wav WAVE\n  TRACK 01 AUDIO\n");
+        assert_eq!(first_track(&sheet, "/music/Other.flac"), Some(1));
+    }
+
+    fn album(sheet: &str) -> SongTableUpload {
+        SongTableUpload {
+            path: "/music/The Album.flac".to_string(),
+            artist: Some("File Artist".to_string()),
+            duration: "420".to_string(),
+            replaygain_track_gain: Some(-5.0),
+            cue_sheet: Some(sheet.to_string()),
+            ..SongTableUpload::default()
+        }
+    }
+
+    #[test]
+    fn songs_are_split_into_audio_tracks() {
+        let tracks = split_song(&album(SHEET));
+
+        // The data track is skipped, the track before it runs on to the next audio track
+        let summary: Vec<(&str, Option<&str>, Option<i64>, Option<i64>, &str)> = tracks.iter()
+            .map(|t| (t.path.as_str(), t.name.as_deref(), t.start_ms, t.end_ms, t.duration.as_str()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("/music/The Album.flac#01", Some("Intro"), Some(0), Some(180_000), "180"),
+            ("/music/The Album.flac#03", Some("Song"), Some(180_000), Some(300_000), "120"),
+            ("/music/The Album.flac#04", Some("Track 04"), Some(300_000), None, "120")
+        ]);
+
+        let artists: Vec<Option<&str>> = tracks.iter().map(|t| t.artist.as_deref()).collect();
+        assert_eq!(artists, vec![Some("Guest"), Some("The Band"), Some("The Band")]);
+        for track in &tracks {
+            assert_eq!(track.source_path.as_deref(), Some("/music/The Album.flac"));
+            assert_eq!(track.album.as_deref(), Some("The Album"));
+            assert_eq!(track.genre.as_deref(), Some("Rock"));
+            assert_eq!(track.release.as_deref(), Some("1999"));
+            assert_eq!(track.replaygain_album_gain, Some(-7.5));
+        }
+        assert_eq!(tracks[0].replaygain_track_gain, Some(-6.0));
+        assert_eq!(tracks[1].replaygain_track_gain, None);
+    }
+
+    #[test]
+    fn songs_without_a_usable_sheet_are_not_split() {
+        let one_track = "FILE \"The Album.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n";
+        let other_files = "FILE \"a.wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\nFILE \"b.wav\" WAVE\n  TRACK 02 AUDIO\n    INDEX 01 00:00:00\n";
+        let cases = [
+            ("no sheet", SongTableUpload { cue_sheet: None, ..album("") }),
+            ("one track", album(one_track)),
+            ("not this file", album(other_files))
+        ];
+        for (name, song) in cases {
+            assert!(split_song(&song).is_empty(), "{}", name);
+        }
+    }
+}
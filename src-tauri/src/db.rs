@@ -1,6 +1,7 @@
 use std::ffi::OsStr;
 use std::{fs};
 use std::path::{Path};
+use std::collections::HashMap;
 use chrono::{Utc};
 use sqlx::sqlite::SqlitePool;
 use tauri_plugin_log::log;
@@ -10,11 +11,11 @@ use sqlx::{sqlite::SqliteQueryResult, Executor, Pool, Sqlite};
 use tauri::{Emitter, State};
 
 use crate::types::{
-    QueueFormat, AllAlbumResults, AllArtistResults, AllGenreResults, ArtistDetailsResults, DirsTable,
-    DoesExist, GenreDetailsResults, History, LrclibLyrics, PlaylistFull, PlaylistTable, SettingsScanDate,
+    QueueFormat, AllAlbumResults, Bookmark, Chapter, AllArtistResults, AllGenreResults, ArtistDetailsResults, DirsTable,
+    ChannelSettings, CrossfadeSettings, DoesExist, EqualizerPreset, EqualizerSettings, GenreDetailsResults, History, LrclibLyrics, PlaybackError, PlaybackSession, PlaylistFull, PlaylistTable, PodcastEpisode, PodcastFeed, RadioStation, ResumeSettings, SettingsScanDate,
     SongHistory, SongTable, SongTableUpload
 };
-use crate::{AppState, commands};
+use crate::{AppState, audio::PlayerCommand, bookmarks::BookmarkChange, commands, podcast::{self, ParsedFeed}, waveform};
 
 
 // ---------------------------------------- Initilize Database and Check if Database exists ----------------------------------------
@@ -42,6 +43,12 @@ pub fn init() {
     let artist_covers_dir = dirs::home_dir().unwrap().to_str().unwrap().to_string() + "/.config/robintuk_player/artist_covers";
     let artist_dir = Path::new(&artist_covers_dir);
     fs::create_dir_all(artist_dir).unwrap();
+
+    // Create the waveform cache folder
+    let _ = fs::create_dir_all(waveform::waveform_dir());
+
+    // Create the podcast download folder
+    let _ = fs::create_dir_all(podcast::podcast_dir());
 }
 
 // Create the database file.
@@ -85,6 +92,20 @@ async fn apply_initial_migrations() -> Result<(), String> {
     }
 
     let _ = pool.execute(include_str!("../migrations/0003_artists.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0004_equalizer.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0005_replaygain.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0006_loudness.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0007_crossfade.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0008_output_device.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0009_session.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0010_volume_ramp.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0011_channels.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0012_playback_errors.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0013_radio.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0014_podcasts.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0015_bookmarks.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0016_chapters.sql")).await;
+    let _ = pool.execute(include_str!("../migrations/0017_cue.sql")).await;
 
     let settings: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM settings")
         .fetch_one(&pool)
@@ -136,10 +157,14 @@ pub async fn reset_database(state: State<AppState, '_>, app: tauri::AppHandle) -
     let _ = fs::remove_dir_all(&covers_dir);
     let _ = fs::remove_dir_all(&playlist_cover_dir);
     let _ = fs::remove_dir_all(&artist_cover_dir);
+    let _ = fs::remove_dir_all(waveform::waveform_dir());
+    let _ = fs::remove_dir_all(podcast::podcast_dir());
     // Recreate the directories
     let _ = fs::create_dir_all(&covers_dir);
     let _ = fs::create_dir_all(&playlist_cover_dir);
     let _ = fs::create_dir_all(&artist_cover_dir);
+    let _ = fs::create_dir_all(waveform::waveform_dir());
+    let _ = fs::create_dir_all(podcast::podcast_dir());
 
     // Trigger global refresh of all data in the app
     let _ = app.emit("ending-reset", false);
@@ -187,7 +212,8 @@ pub async fn remove_directory(state: State<AppState, '_>, directory_name: String
 
 pub async fn does_entry_exist(pool: &Pool<Sqlite>, path: &String) -> Result<bool, String>{
 
-    let res: DoesExist = sqlx::query_as::<_, DoesExist>("SELECT EXISTS(SELECT 1 FROM songs WHERE path = ?) AS does_exist")
+    // Files split by a cue sheet are only saved as their tracks
+    let res: DoesExist = sqlx::query_as::<_, DoesExist>("SELECT EXISTS(SELECT 1 FROM songs WHERE path = ?1 OR source_path = ?1) AS does_exist")
         .bind(path)
         .fetch_one(pool)
         .await
@@ -208,7 +234,7 @@ pub async fn set_keep(pool: &Pool<Sqlite>) -> Result<(), String> {
 
 pub async fn set_keep_single(pool: &Pool<Sqlite>, boolean: bool, path: &String) -> Result<(), String> {
 
-    let _ = sqlx::query("UPDATE songs SET keep = ?1 WHERE path = ?2")
+    let _ = sqlx::query("UPDATE songs SET keep = ?1 WHERE path = ?2 OR source_path = ?2")
         .bind(boolean)
         .bind(&path)
         .execute(pool)
@@ -268,6 +294,272 @@ pub async fn set_last_scan_date(pool: &Pool<Sqlite>) -> Result<(), String> {
     Ok(())
 }
 
+// ------------------------------------ Equalizer Functions ------------------------------------
+
+pub async fn get_equalizer_settings(pool: &Pool<Sqlite>) -> Result<EqualizerSettings, String> {
+
+    let res: Result<(bool, f64, Option<String>, Option<String>), sqlx::Error> = sqlx::query_as(
+        "SELECT eq_enabled, eq_preamp, eq_bands, eq_preset FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    if res.is_ok() {
+        let (enabled, preamp, bands, preset) = res.unwrap();
+        let mut settings = EqualizerSettings {
+            enabled,
+            preamp: preamp as f32,
+            ..EqualizerSettings::default()
+        };
+        if let Some(b) = bands {
+            settings.bands = serde_json::from_str::<Vec<f32>>(&b).unwrap_or(settings.bands);
+        }
+        if let Some(p) = preset {
+            settings.preset = p;
+        }
+        Ok(settings)
+    }
+    else {
+        log::error!("Get Equalizer Settings - {:?}", res.unwrap_err());
+        Err("Error getting equalizer settings".to_string())
+    }
+}
+
+pub async fn set_equalizer_settings(pool: &Pool<Sqlite>, settings: &EqualizerSettings) -> Result<(), String> {
+
+    let bands = serde_json::to_string(&settings.bands).map_err(|e| e.to_string())?;
+
+    let _ = sqlx::query("UPDATE settings SET eq_enabled = ?1, eq_preamp = ?2, eq_bands = ?3, eq_preset = ?4 WHERE id = 1")
+        .bind(settings.enabled)
+        .bind(settings.preamp)
+        .bind(bands)
+        .bind(&settings.preset)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+// Only the user made presets, the built in ones live in the equalizer module
+pub async fn get_equalizer_presets(pool: &Pool<Sqlite>) -> Result<Vec<EqualizerPreset>, String> {
+
+    let res: Result<(Option<String>,), sqlx::Error> = sqlx::query_as("SELECT eq_presets FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    match res {
+        Ok((Some(presets),)) => Ok(serde_json::from_str::<Vec<EqualizerPreset>>(&presets).unwrap_or_default()),
+        Ok((None,)) => Ok(vec![]),
+        Err(e) => {
+            log::error!("Get Equalizer Presets - {:?}", e);
+            Err("Error getting equalizer presets".to_string())
+        }
+    }
+}
+
+pub async fn set_equalizer_presets(pool: &Pool<Sqlite>, presets: &Vec<EqualizerPreset>) -> Result<(), String> {
+
+    let value = serde_json::to_string(presets).map_err(|e| e.to_string())?;
+
+    let _ = sqlx::query("UPDATE settings SET eq_presets = ?1 WHERE id = 1")
+        .bind(value)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_replaygain_mode(pool: &Pool<Sqlite>) -> Result<i64, String> {
+
+    let res: Result<(Option<i64>,), sqlx::Error> = sqlx::query_as("SELECT replaygain_mode FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    if res.is_ok() {
+        Ok(res.unwrap().0.unwrap_or(0))
+    }
+    else {
+        Ok(0)
+    }
+}
+
+pub async fn set_replaygain_mode(pool: &Pool<Sqlite>, mode: i64) -> Result<(), String> {
+
+    let _ = sqlx::query("UPDATE settings SET replaygain_mode = ?1 WHERE id = 1")
+        .bind(mode)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_crossfade_settings(pool: &Pool<Sqlite>) -> Result<CrossfadeSettings, String> {
+
+    let res: Result<(Option<f64>, Option<bool>), sqlx::Error> = sqlx::query_as("SELECT crossfade_duration, crossfade_album_gapless FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    if res.is_ok() {
+        let (duration, album_gapless) = res.unwrap();
+        Ok(CrossfadeSettings {
+            duration: duration.unwrap_or(0.0) as f32,
+            album_gapless: album_gapless.unwrap_or(true)
+        })
+    }
+    else {
+        Ok(CrossfadeSettings::default())
+    }
+}
+
+pub async fn set_crossfade_settings(pool: &Pool<Sqlite>, settings: &CrossfadeSettings) -> Result<(), String> {
+
+    let _ = sqlx::query("UPDATE settings SET crossfade_duration = ?1, crossfade_album_gapless = ?2 WHERE id = 1")
+        .bind(settings.duration)
+        .bind(settings.album_gapless)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_channel_settings(pool: &Pool<Sqlite>) -> Result<ChannelSettings, String> {
+
+    let res: Result<(Option<f64>, Option<bool>, Option<bool>, Option<i64>), sqlx::Error> = sqlx::query_as("SELECT channel_balance, channel_mono, channel_swap, channel_crossfeed FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    if res.is_ok() {
+        let (balance, mono, swap, crossfeed) = res.unwrap();
+        Ok(ChannelSettings {
+            balance: balance.unwrap_or(0.0) as f32,
+            mono: mono.unwrap_or(false),
+            swap: swap.unwrap_or(false),
+            crossfeed: crossfeed.unwrap_or(0)
+        })
+    }
+    else {
+        Ok(ChannelSettings::default())
+    }
+}
+
+pub async fn set_channel_settings(pool: &Pool<Sqlite>, settings: &ChannelSettings) -> Result<(), String> {
+
+    let _ = sqlx::query("UPDATE settings SET channel_balance = ?1, channel_mono = ?2, channel_swap = ?3, channel_crossfeed = ?4 WHERE id = 1")
+        .bind(settings.balance)
+        .bind(settings.mono)
+        .bind(settings.swap)
+        .bind(settings.crossfeed)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_volume_ramp(pool: &Pool<Sqlite>) -> Result<u64, String> {
+
+    let res: Result<(Option<i64>,), sqlx::Error> = sqlx::query_as("SELECT volume_ramp_ms FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    match res {
+        Ok((ms,)) => Ok(ms.unwrap_or(40).max(0) as u64),
+        Err(_) => Ok(40)
+    }
+}
+
+pub async fn set_volume_ramp(pool: &Pool<Sqlite>, ms: u64) -> Result<(), String> {
+
+    let _ = sqlx::query("UPDATE settings SET volume_ramp_ms = ?1 WHERE id = 1")
+        .bind(ms as i64)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_resume_settings(pool: &Pool<Sqlite>) -> Result<ResumeSettings, String> {
+
+    let res: Result<(Option<i64>, Option<String>), sqlx::Error> = sqlx::query_as("SELECT resume_min_minutes, resume_genres FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    match res {
+        Ok((minutes, genres)) => Ok(ResumeSettings {
+            min_duration_minutes: minutes.unwrap_or(0).max(0) as u64,
+            genres: genres.unwrap_or_default().split(',').map(|g| g.trim().to_string()).filter(|g| !g.is_empty()).collect()
+        }),
+        Err(_) => Ok(ResumeSettings::default())
+    }
+}
+
+pub async fn set_resume_settings(pool: &Pool<Sqlite>, settings: &ResumeSettings) -> Result<(), String> {
+
+    let _ = sqlx::query("UPDATE settings SET resume_min_minutes = ?1, resume_genres = ?2 WHERE id = 1")
+        .bind(settings.min_duration_minutes as i64)
+        .bind(settings.genres.join(","))
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_output_device(pool: &Pool<Sqlite>) -> Result<Option<String>, String> {
+
+    let res: Result<(Option<String>,), sqlx::Error> = sqlx::query_as("SELECT output_device FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    match res {
+        Ok((device,)) => Ok(device),
+        Err(_) => Ok(None)
+    }
+}
+
+pub async fn set_output_device(pool: &Pool<Sqlite>, device: Option<&str>) -> Result<(), String> {
+
+    let _ = sqlx::query("UPDATE settings SET output_device = ?1 WHERE id = 1")
+        .bind(device)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_session(pool: &Pool<Sqlite>) -> Result<PlaybackSession, String> {
+
+    let res: Result<(Option<i64>, Option<i64>, Option<f64>, Option<i64>, Option<bool>), sqlx::Error> = sqlx::query_as(
+        "SELECT session_index, session_position_ms, session_volume, session_repeat_mode, session_shuffle FROM settings WHERE id = 1")
+        .fetch_one(pool)
+        .await;
+
+    match res {
+        Ok((index, position_ms, volume, repeat_mode, shuffle)) => {
+            let default = PlaybackSession::default();
+            Ok(PlaybackSession {
+                index: index.unwrap_or(0).max(0) as usize,
+                position_ms: position_ms.unwrap_or(0).max(0) as u64,
+                volume: volume.map(|v| v as f32).unwrap_or(default.volume),
+                repeat_mode: repeat_mode.unwrap_or(default.repeat_mode),
+                shuffle: shuffle.unwrap_or(false)
+            })
+        },
+        Err(_) => Ok(PlaybackSession::default())
+    }
+}
+
+pub async fn set_session(pool: &Pool<Sqlite>, session: &PlaybackSession) -> Result<(), String> {
+
+    let _ = sqlx::query("UPDATE settings SET session_index = ?1, session_position_ms = ?2, session_volume = ?3, session_repeat_mode = ?4, session_shuffle = ?5 WHERE id = 1")
+        .bind(session.index as i64)
+        .bind(session.position_ms as i64)
+        .bind(session.volume)
+        .bind(session.repeat_mode)
+        .bind(session.shuffle)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
 // ------------------------------------ Song Functions ------------------------------------
 
 // Get all songs from the database and all of their data
@@ -282,17 +574,27 @@ pub async fn get_all_songs(state: State<AppState, '_>) -> Result<Vec<SongTable>,
     Ok(temp)
 }
 
+// None when the path isn't in the library (ex. a podcast episode)
+pub async fn find_song(pool: &Pool<Sqlite>, path: &str) -> Result<Option<SongTable>, String> {
+    sqlx::query_as::<_, SongTable>("SELECT * FROM songs WHERE path = ?")
+        .bind(path)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 // Get a single song from the database
 // And all of their data
 #[tauri::command(rename_all = "snake_case")]
 pub async fn get_song(state: State<AppState, '_>, song_path: String) -> Result<SongTable, String> {
 
-    let temp: SongTable = sqlx::query_as::<_, SongTable>("SELECT * FROM songs WHERE path = ?")
+    let mut temp: SongTable = sqlx::query_as::<_, SongTable>("SELECT * FROM songs WHERE path = ?")
         .bind(&song_path)
         .fetch_one(&state.pool)
         .await
         .unwrap();
 
+    temp.chapters = get_chapters(&state.pool, &song_path).await.unwrap_or_default();
     Ok(temp)
 }
 
@@ -314,8 +616,9 @@ pub async fn get_songs_with_limit(state: State<AppState, '_>, limit: i64) -> Res
 pub async fn add_song(entry: SongTableUpload, pool: &Pool<Sqlite> ) -> Result<SqliteQueryResult, String> {
     
     let res: Result<SqliteQueryResult, sqlx::Error> = sqlx::query("INSERT OR IGNORE INTO songs
-        (name, path, cover, release, track, album, artist, genre, album_artist, disc_number, duration, favorited, song_section, album_section, artist_section, genre_section, keep) 
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)")
+        (name, path, cover, release, track, album, artist, genre, album_artist, disc_number, duration, favorited, song_section, album_section, artist_section, genre_section, keep,
+        replaygain_track_gain, replaygain_track_peak, replaygain_album_gain, replaygain_album_peak, source_path, start_ms, end_ms) 
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24)")
         .bind(&entry.name)
         .bind(&entry.path)
         .bind(&entry.cover)
@@ -333,9 +636,17 @@ pub async fn add_song(entry: SongTableUpload, pool: &Pool<Sqlite> ) -> Result<Sq
         .bind(&entry.artist_section)
         .bind(&entry.genre_section)
         .bind(true)
+        .bind(&entry.replaygain_track_gain)
+        .bind(&entry.replaygain_track_peak)
+        .bind(&entry.replaygain_album_gain)
+        .bind(&entry.replaygain_album_peak)
+        .bind(&entry.source_path)
+        .bind(&entry.start_ms)
+        .bind(&entry.end_ms)
         .execute(pool)
         .await;
 
+    let _ = set_chapters(pool, &entry.path, &entry.chapters).await;
     Ok(res.unwrap())
 }
 
@@ -344,8 +655,10 @@ pub async fn update_song(entry: SongTableUpload, pool: &Pool<Sqlite> ) -> Result
     
     let res: Result<SqliteQueryResult, sqlx::Error> = sqlx::query("UPDATE songs
         SET name = ?1, cover = ?2, release = ?3, track = ?4, album = ?5, artist = ?6, genre = ?7,
-        album_artist = ?8, disc_number = ?9, duration = ?10, song_section = ?11, album_section = ?12, artist_section = ?13, genre_section = ?14, keep = ?15
-        WHERE path = ?16
+        album_artist = ?8, disc_number = ?9, duration = ?10, song_section = ?11, album_section = ?12, artist_section = ?13, genre_section = ?14, keep = ?15,
+        replaygain_track_gain = ?16, replaygain_track_peak = ?17, replaygain_album_gain = ?18, replaygain_album_peak = ?19,
+        source_path = ?20, start_ms = ?21, end_ms = ?22
+        WHERE path = ?23
         ")
         .bind(&entry.name)
         .bind(&entry.cover)
@@ -362,48 +675,120 @@ pub async fn update_song(entry: SongTableUpload, pool: &Pool<Sqlite> ) -> Result
         .bind(&entry.artist_section)
         .bind(&entry.genre_section)
         .bind(true)
+        .bind(&entry.replaygain_track_gain)
+        .bind(&entry.replaygain_track_peak)
+        .bind(&entry.replaygain_album_gain)
+        .bind(&entry.replaygain_album_peak)
+        .bind(&entry.source_path)
+        .bind(&entry.start_ms)
+        .bind(&entry.end_ms)
 
-        .bind(entry.path)
+        .bind(&entry.path)
         .execute(pool)
         .await;
 
+    let _ = set_chapters(pool, &entry.path, &entry.chapters).await;
     Ok(res.unwrap())
 }
 
-#[derive(sqlx::FromRow, Default, serde::Serialize)]
-struct Covers {
-    cover: String
+// Adds the song, or updates it if it is already saved under this exact path
+pub async fn save_song(entry: SongTableUpload, pool: &Pool<Sqlite>) -> Result<SqliteQueryResult, String> {
+
+    let res: DoesExist = sqlx::query_as::<_, DoesExist>("SELECT EXISTS(SELECT 1 FROM songs WHERE path = ?) AS does_exist")
+        .bind(&entry.path)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if res.does_exist {
+        update_song(entry, pool).await
+    }
+    else {
+        add_song(entry, pool).await
+    }
 }
 
-pub async fn remove_songs(pool: &Pool<Sqlite>) -> Result<(), String> {
+// The whole file was saved before it had a cue sheet, its tracks replace it
+pub async fn remove_split_file(pool: &Pool<Sqlite>, path: &str) -> Result<(), String> {
 
-    let covers_to_delete: Vec<Covers> = sqlx::query_as::<_, Covers>("SELECT DISTINCT cover FROM songs WHERE keep = false AND cover IS NOT NULL").fetch_all(pool).await.unwrap();
+    let _ = sqlx::query("DELETE FROM songs WHERE path = ?1 AND source_path IS NULL")
+        .bind(path)
+        .execute(pool)
+        .await;
+    waveform::remove_cache(path);
 
-    for covers in covers_to_delete {
-        let res: (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM songs WHERE cover = ? AND keep = true)")
-            .bind(&covers.cover)
-            .fetch_one(pool)
-            .await.unwrap();
+    let _ = remove_orphan_chapters(pool).await;
+    Ok(())
+}
 
-        // There are no songs using the image
-        if res.0 == false {
-            let _ = fs::remove_file(covers.cover);
-        }      
+// Replaces the song's chapters with the ones from the latest scan
+pub async fn set_chapters(pool: &Pool<Sqlite>, path: &str, chapters: &[Chapter]) -> Result<(), String> {
+    let _ = sqlx::query("DELETE FROM chapters WHERE song_id = ?")
+        .bind(path)
+        .execute(pool)
+        .await;
+
+    for (i, chapter) in chapters.iter().enumerate() {
+        let _ = sqlx::query("INSERT INTO chapters (song_id, position, title, start_ms, end_ms) VALUES (?1, ?2, ?3, ?4, ?5)")
+            .bind(path)
+            .bind(i as i64)
+            .bind(&chapter.title)
+            .bind(chapter.start_ms)
+            .bind(chapter.end_ms)
+            .execute(pool)
+            .await;
     }
-    
-    let _ = sqlx::query("DELETE FROM songs WHERE keep = false")
+
+    Ok(())
+}
+
+pub async fn get_chapters(pool: &Pool<Sqlite>, path: &str) -> Result<Vec<Chapter>, String> {
+    sqlx::query_as::<_, Chapter>("SELECT title, start_ms, end_ms FROM chapters WHERE song_id = ? ORDER BY position ASC")
+        .bind(path)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Chapters of songs that were removed from the library
+pub async fn remove_orphan_chapters(pool: &Pool<Sqlite>) -> Result<(), String> {
+    let _ = sqlx::query("DELETE FROM chapters WHERE song_id NOT IN (SELECT path FROM songs)")
         .execute(pool)
         .await;
 
     Ok(())
 }
 
-pub async fn remove_song(pool: &Pool<Sqlite>, song: SongTable) -> Result<(), String> {
+// Songs that haven't been through the loudness analysis yet
+// Cue sheet tracks come with their offsets, each one is measured on its own
+pub async fn get_songs_without_loudness(pool: &Pool<Sqlite>) -> Result<Vec<SongTable>, String> {
 
-    let covers_to_delete: Vec<Covers> = sqlx::query_as::<_, Covers>("SELECT cover FROM songs WHERE path = ?1")
-        .bind(&song.path)
+    sqlx::query_as::<_, SongTable>("SELECT * FROM songs WHERE loudness_scanned IS NOT true ORDER BY path ASC")
         .fetch_all(pool)
-        .await.unwrap();
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn set_song_loudness(pool: &Pool<Sqlite>, path: &String, lufs: Option<f64>, peak: Option<f64>) -> Result<(), String> {
+
+    let _ = sqlx::query("UPDATE songs SET loudness_lufs = ?1, loudness_peak = ?2, loudness_scanned = true WHERE path = ?3")
+        .bind(lufs)
+        .bind(peak)
+        .bind(path)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+#[derive(sqlx::FromRow, Default, serde::Serialize)]
+struct Covers {
+    cover: String
+}
+
+pub async fn remove_songs(pool: &Pool<Sqlite>) -> Result<(), String> {
+
+    let covers_to_delete: Vec<Covers> = sqlx::query_as::<_, Covers>("SELECT DISTINCT cover FROM songs WHERE keep = false AND cover IS NOT NULL").fetch_all(pool).await.unwrap();
 
     for covers in covers_to_delete {
         let res: (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM songs WHERE cover = ? AND keep = true)")
@@ -414,18 +799,23 @@ pub async fn remove_song(pool: &Pool<Sqlite>, song: SongTable) -> Result<(), Str
         // There are no songs using the image
         if res.0 == false {
             let _ = fs::remove_file(covers.cover);
-        }
+        }      
     }
     
-    let _ = sqlx::query("DELETE FROM songs WHERE path = ?1")
-        .bind(&song.path)
+    let removed: Vec<(String,)> = sqlx::query_as("SELECT path FROM songs WHERE keep = false").fetch_all(pool).await.unwrap_or_default();
+    for (path,) in removed {
+        waveform::remove_cache(&path);
+    }
+
+    let _ = sqlx::query("DELETE FROM songs WHERE keep = false")
         .execute(pool)
         .await;
 
+    let _ = remove_orphan_chapters(pool).await;
+
     Ok(())
 }
 
-
 // ------------------------------------ Album Functions ------------------------------------
 
 #[tauri::command]
@@ -632,7 +1022,9 @@ pub async fn get_playlist(state: State<AppState, '_>, id: i64) -> Result<Playlis
 
     // Get the playlist tracks
     let song_arr: Vec<SongTable> = sqlx::query_as::<_, SongTable>("    
-            SELECT s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section
+            SELECT s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section,
+            s.replaygain_track_gain, s.replaygain_track_peak, s.replaygain_album_gain, s.replaygain_album_peak, s.loudness_lufs, s.loudness_peak,
+            s.source_path, s.start_ms, s.end_ms
             FROM playlist_tracks p 
             INNER JOIN songs s ON s.path = p.track_id 
             WHERE p.playlist_id = ?1 ORDER BY p.position ASC
@@ -844,7 +1236,9 @@ pub async fn remove_multiple_songs_from_playlist(state: State<AppState, '_>, pla
         .await;
 
     let res: Vec<SongTable> = sqlx::query_as::<_, SongTable>("    
-            SELECT s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section
+            SELECT s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section,
+            s.replaygain_track_gain, s.replaygain_track_peak, s.replaygain_album_gain, s.replaygain_album_peak, s.loudness_lufs, s.loudness_peak,
+            s.source_path, s.start_ms, s.end_ms
             FROM playlist_tracks p 
             INNER JOIN songs s ON s.path = p.track_id 
             WHERE p.playlist_id = ?1 ORDER BY p.position ASC
@@ -928,14 +1322,18 @@ pub async fn get_queue(state: State<AppState, '_>, shuffled: bool) -> Result<Vec
 
     if shuffled == true {
         let list: Vec<SongTable> = sqlx::query_as::<_, SongTable>("
-            SELECT q.position, s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section
+            SELECT q.position, s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section,
+            s.replaygain_track_gain, s.replaygain_track_peak, s.replaygain_album_gain, s.replaygain_album_peak, s.loudness_lufs, s.loudness_peak,
+            s.source_path, s.start_ms, s.end_ms
             FROM queue_shuffled q 
             INNER JOIN songs s ON s.path = q.song_id ORDER BY q.position ASC").fetch_all(&state.pool).await.unwrap();
         Ok(list)
     }
     else {
         let list: Vec<SongTable> = sqlx::query_as::<_, SongTable>("
-            SELECT q.position, s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section
+            SELECT q.position, s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section,
+            s.replaygain_track_gain, s.replaygain_track_peak, s.replaygain_album_gain, s.replaygain_album_peak, s.loudness_lufs, s.loudness_peak,
+            s.source_path, s.start_ms, s.end_ms
             FROM queue q 
             INNER JOIN songs s ON s.path = q.song_id ORDER BY q.position ASC").fetch_all(&state.pool).await.unwrap();
         Ok(list)
@@ -980,6 +1378,58 @@ pub async fn create_queue_shuffled(state: State<'_, AppState>, songs: &Vec<SongT
     Ok(())
 }
 
+// Rewrites both queue tables after the player's queue was edited
+// The table being played gets the player's order, the other one keeps its own order with the same songs
+pub async fn sync_queue(pool: &Pool<Sqlite>, songs: &[SongTable], shuffled: bool) -> Result<(), String> {
+    let (played, other) = if shuffled { ("queue_shuffled", "queue") } else { ("queue", "queue_shuffled") };
+
+    let other_order: Vec<(String,)> = sqlx::query_as(&format!("SELECT song_id FROM {} ORDER BY position ASC", other))
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    let played_order: Vec<&str> = songs.iter().map(|song| song.path.as_str()).filter(|path| !podcast::is_episode(path)).collect();
+    let other_order = match_queue_order(other_order.iter().map(|r| r.0.as_str()).collect(), &played_order);
+
+    // Both tables change together, or not at all
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    for (table, order) in [(played, &played_order), (other, &other_order)] {
+        sqlx::query(&format!("DELETE FROM {}", table)).execute(&mut *tx).await.map_err(|e| e.to_string())?;
+        for (i, path) in order.iter().enumerate() {
+            sqlx::query(&format!("INSERT INTO {} (position, song_id) VALUES (?1, ?2)", table))
+                .bind(i as i64)
+                .bind(*path)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+// Songs no longer in the played order are dropped, new ones go in after the song they follow in the played order
+fn match_queue_order<'a>(other: Vec<&'a str>, played: &[&'a str]) -> Vec<&'a str> {
+    let mut missing: HashMap<&str, usize> = HashMap::new();
+    for path in played {
+        *missing.entry(*path).or_default() += 1;
+    }
+
+    let mut order: Vec<&str> = other.into_iter().filter(|path| match missing.get_mut(path) {
+        Some(count) if *count > 0 => { *count -= 1; true },
+        _ => false
+    }).collect();
+
+    for (i, path) in played.iter().enumerate() {
+        let Some(count) = missing.get_mut(path).filter(|count| **count > 0) else { continue };
+        *count -= 1;
+        let index = match i {
+            0 => 0,
+            _ => order.iter().rposition(|p| *p == played[i - 1]).map_or(order.len(), |p| p + 1)
+        };
+        order.insert(index, *path);
+    }
+    order
+}
+
 #[tauri::command(rename_all = "snake_case")]
 pub async fn add_to_queue(state: State<AppState, '_>, songs: Vec<SongTable>) -> Result<(), String> {
     
@@ -1031,7 +1481,7 @@ pub async fn clear_queue(state: State<AppState, '_>) -> Result<(), String> {
 #[tauri::command(rename_all = "snake_case")]
 pub async fn remove_from_queue(state: State<AppState, '_>, song_id: String) -> Result<(), String> {
 
-    let is_shuffled = state.player.lock().unwrap().get_shuffle();
+    let is_shuffled = state.player.request(PlayerCommand::GetShuffle)?;
 
     if is_shuffled == false {
         // Remove the song from Queue
@@ -1085,7 +1535,7 @@ pub async fn remove_from_queue(state: State<AppState, '_>, song_id: String) -> R
 #[tauri::command(rename_all = "snake_case")]
 pub async fn remove_multiple_songs_from_queue(state: State<AppState, '_>, songs: Vec<SongTable>) -> Result<(), String> {
 
-    let is_shuffled = state.player.lock().unwrap().get_shuffle();
+    let is_shuffled = state.player.request(PlayerCommand::GetShuffle)?;
 
     if is_shuffled == false {
         let mut test_string: String = "DELETE FROM queue WHERE song_id IN (".to_string();
@@ -1195,9 +1645,293 @@ pub async fn remove_multiple_songs_from_queue(state: State<AppState, '_>, songs:
 }
 
 
+// Songs that failed to load, the latest reason replaces the old one
+pub async fn record_playback_error(pool: &Pool<Sqlite>, path: String, reason: String) -> Result<(), String> {
+    if podcast::is_episode(&path) {
+        return Ok(());
+    }
+    let _ = sqlx::query("INSERT INTO playback_errors (song_id, reason, failed_at) VALUES (?1, ?2, ?3)
+        ON CONFLICT(song_id) DO UPDATE SET reason = excluded.reason, failed_at = excluded.failed_at, fail_count = fail_count + 1")
+        .bind(path)
+        .bind(reason)
+        .bind(Utc::now().to_rfc3339())
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_playback_errors(pool: &Pool<Sqlite>) -> Result<Vec<PlaybackError>, String> {
+    sqlx::query_as::<_, PlaybackError>(
+        "SELECT p.song_id AS path, s.name, s.artist, s.album, p.reason, p.failed_at, p.fail_count
+        FROM playback_errors p LEFT JOIN songs s ON s.path = p.song_id
+        ORDER BY p.failed_at DESC")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn clear_playback_error(pool: &Pool<Sqlite>, path: String) -> Result<(), String> {
+    let _ = sqlx::query("DELETE FROM playback_errors WHERE song_id = ?")
+        .bind(path)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_radio_stations(pool: &Pool<Sqlite>) -> Result<Vec<RadioStation>, String> {
+    sqlx::query_as::<_, RadioStation>("SELECT id, name, url FROM radio_stations ORDER BY name COLLATE NOCASE ASC")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn get_radio_station(pool: &Pool<Sqlite>, id: i64) -> Result<RadioStation, String> {
+    sqlx::query_as::<_, RadioStation>("SELECT id, name, url FROM radio_stations WHERE id = ?")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .map_err(|_| "Radio station not found".to_string())
+}
+
+// Returns false if a station with the same url is already saved
+pub async fn add_radio_station(pool: &Pool<Sqlite>, name: &str, url: &str) -> Result<bool, String> {
+    let res = sqlx::query("INSERT OR IGNORE INTO radio_stations (name, url, created_at) VALUES (?1, ?2, ?3)")
+        .bind(name)
+        .bind(url)
+        .bind(Utc::now().to_rfc3339())
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn remove_radio_station(pool: &Pool<Sqlite>, id: i64) -> Result<(), String> {
+    let _ = sqlx::query("DELETE FROM radio_stations WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+const PODCAST_FEED_COLUMNS: &str = "SELECT f.id, f.url, f.title, f.description, f.image_url, f.refreshed_at,
+    (SELECT COUNT(*) FROM podcast_episodes e WHERE e.feed_id = f.id AND e.played = false) AS unplayed
+    FROM podcast_feeds f";
+
+const PODCAST_EPISODE_COLUMNS: &str = "SELECT e.id, e.feed_id, f.title AS feed_title, e.title, e.description, e.audio_url, e.published,
+    e.duration, e.file_path, e.played
+    FROM podcast_episodes e JOIN podcast_feeds f ON f.id = e.feed_id";
+
+pub async fn get_podcast_feeds(pool: &Pool<Sqlite>) -> Result<Vec<PodcastFeed>, String> {
+    sqlx::query_as::<_, PodcastFeed>(&format!("{} ORDER BY f.title COLLATE NOCASE ASC", PODCAST_FEED_COLUMNS))
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn get_podcast_feed(pool: &Pool<Sqlite>, id: i64) -> Result<PodcastFeed, String> {
+    sqlx::query_as::<_, PodcastFeed>(&format!("{} WHERE f.id = ?", PODCAST_FEED_COLUMNS))
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .map_err(|_| "Podcast not found".to_string())
+}
+
+// Returns None if the feed is already subscribed to
+pub async fn add_podcast_feed(pool: &Pool<Sqlite>, url: &str, feed: &ParsedFeed) -> Result<Option<i64>, String> {
+    let now = Utc::now().to_rfc3339();
+    let res = sqlx::query("INSERT OR IGNORE INTO podcast_feeds (url, title, description, image_url, subscribed_at, refreshed_at) VALUES (?1, ?2, ?3, ?4, ?5, ?5)")
+        .bind(url)
+        .bind(&feed.title)
+        .bind(&feed.description)
+        .bind(&feed.image_url)
+        .bind(now)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if res.rows_affected() == 0 {
+        return Ok(None);
+    }
+    Ok(Some(res.last_insert_rowid()))
+}
+
+pub async fn update_podcast_feed(pool: &Pool<Sqlite>, id: i64, feed: &ParsedFeed) -> Result<(), String> {
+    let _ = sqlx::query("UPDATE podcast_feeds SET title = ?1, description = ?2, image_url = ?3, refreshed_at = ?4 WHERE id = ?5")
+        .bind(&feed.title)
+        .bind(&feed.description)
+        .bind(&feed.image_url)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+// Episodes that were already saved are left alone, returns how many were new
+pub async fn add_podcast_episodes(pool: &Pool<Sqlite>, feed_id: i64, feed: &ParsedFeed) -> Result<usize, String> {
+    let mut added = 0;
+    for episode in &feed.episodes {
+        let res = sqlx::query("INSERT OR IGNORE INTO podcast_episodes (feed_id, guid, title, description, audio_url, published, duration)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")
+            .bind(feed_id)
+            .bind(&episode.guid)
+            .bind(&episode.title)
+            .bind(&episode.description)
+            .bind(&episode.audio_url)
+            .bind(&episode.published)
+            .bind(episode.duration)
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if res.rows_affected() > 0 {
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+pub async fn remove_podcast_feed(pool: &Pool<Sqlite>, id: i64) -> Result<(), String> {
+    let _ = sqlx::query("DELETE FROM podcast_episodes WHERE feed_id = ?")
+        .bind(id)
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("DELETE FROM podcast_feeds WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+// Newest first, episodes without a date go last
+pub async fn get_podcast_episodes(pool: &Pool<Sqlite>, feed_id: i64) -> Result<Vec<PodcastEpisode>, String> {
+    sqlx::query_as::<_, PodcastEpisode>(&format!("{} WHERE e.feed_id = ? ORDER BY e.published IS NULL, e.published DESC, e.id DESC", PODCAST_EPISODE_COLUMNS))
+        .bind(feed_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn get_podcast_episode(pool: &Pool<Sqlite>, id: i64) -> Result<PodcastEpisode, String> {
+    sqlx::query_as::<_, PodcastEpisode>(&format!("{} WHERE e.id = ?", PODCAST_EPISODE_COLUMNS))
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .map_err(|_| "Episode not found".to_string())
+}
+
+// None when the download is deleted
+pub async fn set_podcast_episode_file(pool: &Pool<Sqlite>, id: i64, file_path: Option<String>) -> Result<(), String> {
+    let _ = sqlx::query("UPDATE podcast_episodes SET file_path = ?1 WHERE id = ?2")
+        .bind(file_path)
+        .bind(id)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn set_podcast_episode_played(pool: &Pool<Sqlite>, id: i64, played: bool) -> Result<(), String> {
+    let _ = sqlx::query("UPDATE podcast_episodes SET played = ?1 WHERE id = ?2")
+        .bind(played)
+        .bind(id)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+// Used by the player when a song plays to the end, does nothing if it isn't a podcast episode
+pub async fn mark_podcast_file_played(pool: &Pool<Sqlite>, path: String) -> Result<(), String> {
+    let _ = sqlx::query("UPDATE podcast_episodes SET played = true WHERE file_path = ?")
+        .bind(path)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+pub async fn get_resume_songs(pool: &Pool<Sqlite>) -> Result<Vec<String>, String> {
+    let rows: Vec<(String,)> = sqlx::query_as("SELECT song_id FROM resume_songs")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(rows.into_iter().map(|(path,)| path).collect())
+}
+
+pub async fn set_resume_song(pool: &Pool<Sqlite>, path: String, remember: bool) -> Result<(), String> {
+    let query = if remember { "INSERT OR IGNORE INTO resume_songs (song_id) VALUES (?)" } else { "DELETE FROM resume_songs WHERE song_id = ?" };
+    let _ = sqlx::query(query)
+        .bind(path)
+        .execute(pool)
+        .await;
+
+    Ok(())
+}
+
+// (path, position in ms) of every bookmark, handed to the player at startup
+pub async fn get_bookmark_positions(pool: &Pool<Sqlite>) -> Result<Vec<(String, u64)>, String> {
+    let rows: Vec<(String, i64)> = sqlx::query_as("SELECT song_id, position_ms FROM bookmarks")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(rows.into_iter().map(|(path, ms)| (path, ms.max(0) as u64)).collect())
+}
+
+// Most recently listened to first
+pub async fn get_bookmarks(pool: &Pool<Sqlite>) -> Result<Vec<Bookmark>, String> {
+    sqlx::query_as::<_, Bookmark>(
+        "SELECT b.song_id AS path, COALESCE(s.name, e.title) AS name, COALESCE(s.artist, f.title) AS artist, COALESCE(s.album, f.title) AS album,
+        b.position_ms, b.duration, b.updated_at
+        FROM bookmarks b
+        LEFT JOIN songs s ON s.path = b.song_id
+        LEFT JOIN podcast_episodes e ON e.file_path = b.song_id
+        LEFT JOIN podcast_feeds f ON f.id = e.feed_id
+        ORDER BY b.updated_at DESC")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn save_bookmark(pool: &Pool<Sqlite>, change: BookmarkChange) -> Result<(), String> {
+    let _ = match change.position_ms {
+        Some(position_ms) => sqlx::query("INSERT INTO bookmarks (song_id, position_ms, duration, updated_at) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(song_id) DO UPDATE SET position_ms = excluded.position_ms, duration = excluded.duration, updated_at = excluded.updated_at")
+            .bind(change.path)
+            .bind(position_ms as i64)
+            .bind(change.duration as i64)
+            .bind(Utc::now().to_rfc3339())
+            .execute(pool)
+            .await,
+        None => sqlx::query("DELETE FROM bookmarks WHERE song_id = ?")
+            .bind(change.path)
+            .execute(pool)
+            .await
+    };
+
+    Ok(())
+}
+
 // Create a history of songs played -- no idea what for yet
 #[tauri::command(rename_all = "snake_case")]
 pub async fn add_song_to_history(state: State<AppState, '_>, path: String) -> Result<(), String> {
+    record_play(&state.pool, path).await
+}
+
+// Also used by the player when it moves to the next song by itself
+pub async fn record_play(pool: &Pool<Sqlite>, path: String) -> Result<(), String> {
+    if podcast::is_episode(&path) {
+        return Ok(());
+    }
     let history: History = History {
         id: Utc::now().timestamp_millis().to_string(),
         date_played: Utc::now(),
@@ -1207,18 +1941,18 @@ pub async fn add_song_to_history(state: State<AppState, '_>, path: String) -> Re
     // Remove the song from the history if it is in the history, no repeats
     let _ = sqlx::query("DELETE FROM history WHERE song_id = ?")
         .bind(&history.song_id)
-        .execute(&state.pool)
+        .execute(pool)
         .await;
     
     let _ = sqlx::query("INSERT INTO history (id, created_at, song_id) VALUES (?1, ?2, ?3)")
         .bind(history.id)
         .bind(history.date_played.to_rfc3339())
         .bind(history.song_id)
-        .execute(&state.pool)
+        .execute(pool)
         .await;
 
     let _ = sqlx::query("DELETE FROM history WHERE id NOT IN (SELECT id FROM history ORDER BY created_at DESC LIMIT 100)")
-        .execute(&state.pool)
+        .execute(pool)
         .await;
 
     Ok(())
@@ -1228,7 +1962,9 @@ pub async fn add_song_to_history(state: State<AppState, '_>, path: String) -> Re
 pub async fn get_play_history(state: State<AppState, '_>, limit: i64) -> Result<Vec<SongHistory>, String> {
     if limit == -1 {
         let history: Vec<SongHistory> = sqlx::query_as::<_, SongHistory>("
-            SELECT h.id, s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section
+            SELECT h.id, s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section,
+                s.replaygain_track_gain, s.replaygain_track_peak, s.replaygain_album_gain, s.replaygain_album_peak, s.loudness_lufs, s.loudness_peak,
+                s.source_path, s.start_ms, s.end_ms
             FROM history h 
             INNER JOIN songs s ON s.path = h.song_id ORDER BY h.id DESC")
         .fetch_all(&state.pool)
@@ -1238,7 +1974,9 @@ pub async fn get_play_history(state: State<AppState, '_>, limit: i64) -> Result<
     }
     else {
         let history: Vec<SongHistory> = sqlx::query_as::<_, SongHistory>("
-            SELECT h.id, s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section
+            SELECT h.id, s.name, s.path, s.album, s.artist, s.duration, s.genre, s.cover, s.release, s.album_artist, s.track, s.disc_number, s.song_section,
+                s.replaygain_track_gain, s.replaygain_track_peak, s.replaygain_album_gain, s.replaygain_album_peak, s.loudness_lufs, s.loudness_peak,
+                s.source_path, s.start_ms, s.end_ms
             FROM history h 
             INNER JOIN songs s ON s.path = h.song_id ORDER BY h.id DESC LIMIT $1")
         .bind(limit)
@@ -0,0 +1,255 @@
+use std::{ f32::consts::PI, sync::{ Arc, Mutex, atomic::{ AtomicUsize, Ordering } }, time::Duration };
+use rodio::{ ChannelCount, SampleRate, Source, source::SeekError };
+
+use crate::types::{ EqualizerPreset, EqualizerSettings };
+
+// Center frequencies (Hz) of the 10 bands, standard octave spacing
+pub const EQ_BANDS: [f32; 10] = [31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0];
+// Each band can be boosted or cut by this many dB
+pub const EQ_MAX_GAIN: f32 = 12.0;
+// Roughly one octave wide for each band
+const EQ_Q: f32 = 1.41;
+// How many samples to play before checking if the bands were changed
+const EQ_UPDATE_INTERVAL: usize = 1024;
+
+// Presets that ship with the app, user presets are saved in the settings table
+pub fn built_in_presets() -> Vec<EqualizerPreset> {
+    let preset = |name: &str, preamp: f32, bands: [f32; 10]| EqualizerPreset {
+        name: name.to_string(),
+        preamp,
+        bands: bands.to_vec(),
+        built_in: true
+    };
+
+    vec![
+        preset("Flat", 0.0, [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+        preset("Bass Boost", -4.0, [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+        preset("Treble Boost", -4.0, [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 4.0, 5.0, 6.0]),
+        preset("Rock", -3.0, [4.0, 3.0, 2.0, 0.0, -1.0, -1.0, 1.0, 2.0, 3.0, 3.0]),
+        preset("Pop", -2.0, [-1.0, 0.0, 2.0, 3.0, 3.0, 2.0, 0.0, -1.0, -1.0, -1.0]),
+        preset("Jazz", -2.0, [3.0, 2.0, 1.0, 2.0, -1.0, -1.0, 0.0, 1.0, 2.0, 3.0]),
+        preset("Classical", -2.0, [3.0, 2.0, 1.0, 0.0, 0.0, 0.0, -1.0, -1.0, 1.0, 2.0]),
+        preset("Electronic", -4.0, [5.0, 4.0, 1.0, 0.0, -2.0, 1.0, 0.0, 1.0, 4.0, 5.0]),
+        preset("Vocal", -2.0, [-2.0, -2.0, -1.0, 1.0, 3.0, 3.0, 2.0, 1.0, 0.0, -1.0]),
+    ]
+}
+
+// Keep the bands inside of the allowed range, and always return 10 of them
+pub fn clamp_bands(bands: &[f32]) -> Vec<f32> {
+    let mut res: Vec<f32> = vec![0.0; EQ_BANDS.len()];
+    for (i, value) in bands.iter().take(EQ_BANDS.len()).enumerate() {
+        res[i] = value.clamp(-EQ_MAX_GAIN, EQ_MAX_GAIN);
+    }
+    res
+}
+
+
+// ------------------- Shared Controls -------------------
+// The player and the commands hold onto these controls, the audio source reads them while playing
+// The version is bumped on each change so the source knows to rebuild the filters
+
+pub struct EqualizerControls {
+    settings: Mutex<EqualizerSettings>,
+    version: AtomicUsize
+}
+
+impl EqualizerControls {
+    pub fn new(settings: EqualizerSettings) -> Arc<Self> {
+        Arc::new(Self {
+            settings: Mutex::new(settings),
+            version: AtomicUsize::new(0)
+        })
+    }
+
+    pub fn get(&self) -> EqualizerSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, settings: EqualizerSettings) {
+        *self.settings.lock().unwrap() = settings;
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    fn version(&self) -> usize {
+        self.version.load(Ordering::Acquire)
+    }
+}
+
+
+// ------------------- Filter -------------------
+// Peaking filter from the RBJ Audio EQ Cookbook
+
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32
+}
+
+impl Biquad {
+    fn peaking(sample_rate: f32, freq: f32, q: f32, gain_db: f32) -> Self {
+        // Bands above nyquist can't be filtered, leave them flat
+        if gain_db == 0.0 || freq >= sample_rate / 2.0 {
+            return Self { b0: 1.0, ..Self::default() };
+        }
+
+        let a = 10f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * freq / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha / a;
+
+        Self {
+            b0: (1.0 + alpha * a) / a0,
+            b1: (-2.0 * cos_w0) / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: (-2.0 * cos_w0) / a0,
+            a2: (1.0 - alpha / a) / a0
+        }
+    }
+}
+
+// Filter memory for a single band on a single channel (Transposed Direct Form II)
+#[derive(Clone, Copy, Default)]
+struct BiquadState {
+    z1: f32,
+    z2: f32
+}
+
+impl BiquadState {
+    fn process(&mut self, f: &Biquad, input: f32) -> f32 {
+        let output = f.b0 * input + self.z1;
+        self.z1 = f.b1 * input - f.a1 * output + self.z2;
+        self.z2 = f.b2 * input - f.a2 * output;
+        output
+    }
+}
+
+
+// ------------------- Equalizer Source -------------------
+// Wraps the decoder and runs every sample through the enabled bands
+
+pub struct Equalizer<S: Source> {
+    input: S,
+    controls: Arc<EqualizerControls>,
+    version: usize,
+    enabled: bool,
+    preamp: f32,
+    filters: Vec<Biquad>,
+    // One set of band states for each channel
+    states: Vec<Vec<BiquadState>>,
+    channel: usize,
+    samples_until_update: usize
+}
+
+impl<S: Source> Equalizer<S> {
+    pub fn new(input: S, controls: Arc<EqualizerControls>) -> Self {
+        let mut eq = Self {
+            input,
+            controls,
+            version: 0,
+            enabled: false,
+            preamp: 1.0,
+            filters: vec![],
+            states: vec![],
+            channel: 0,
+            samples_until_update: 0
+        };
+        eq.update_filters();
+        eq
+    }
+
+    fn update_filters(&mut self) {
+        self.version = self.controls.version();
+        let settings = self.controls.get();
+        let sample_rate = u32::from(self.input.sample_rate()) as f32;
+        let channels = u16::from(self.input.channels()) as usize;
+
+        self.enabled = settings.enabled;
+        self.preamp = 10f32.powf(settings.preamp / 20.0);
+        self.filters = EQ_BANDS.iter()
+            .zip(clamp_bands(&settings.bands))
+            .map(|(freq, gain)| Biquad::peaking(sample_rate, *freq, EQ_Q, gain))
+            .collect();
+
+        // Keep the filter memory if the layout didn't change, prevents clicks when moving a band
+        if self.states.len() != channels {
+            self.states = vec![vec![BiquadState::default(); EQ_BANDS.len()]; channels];
+            self.channel = 0;
+        }
+    }
+
+    fn reset_states(&mut self) {
+        for channel in self.states.iter_mut() {
+            for state in channel.iter_mut() {
+                *state = BiquadState::default();
+            }
+        }
+        self.channel = 0;
+    }
+}
+
+impl<S: Source> Iterator for Equalizer<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // Only check on frame boundaries so the channels stay lined up
+        if self.samples_until_update == 0 && self.channel == 0 {
+            if self.controls.version() != self.version {
+                self.update_filters();
+            }
+            self.samples_until_update = EQ_UPDATE_INTERVAL;
+        }
+        self.samples_until_update = self.samples_until_update.saturating_sub(1);
+
+        let sample = self.input.next()?;
+
+        if self.states.is_empty() {
+            return Some(sample);
+        }
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.states.len();
+
+        if !self.enabled {
+            return Some(sample);
+        }
+
+        let mut value = sample * self.preamp;
+        for (state, filter) in self.states[channel].iter_mut().zip(self.filters.iter()) {
+            value = state.process(filter, value);
+        }
+
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<S: Source> Source for Equalizer<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        // Old filter memory belongs to a different part of the song
+        self.reset_states();
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::{ fs::File, io::{ BufReader, Read, Seek }, path::Path };
+use rodio::Decoder;
+
+// Every file type the app can play, shared by the library scan and the player
+// The hint tells symphonia which format reader to try first, files without a known extension are probed by their content
+
+pub struct AudioFormat {
+    pub extension: &'static str,
+    pub hint: &'static str
+}
+
+const fn format(extension: &'static str, hint: &'static str) -> AudioFormat {
+    AudioFormat { extension, hint }
+}
+
+pub const AUDIO_FORMATS: [AudioFormat; 14] = [
+    format("mp3", "mp3"),
+    format("flac", "flac"),
+    // AAC or ALAC inside of an MP4 container
+    format("m4a", "m4a"),
+    format("m4b", "m4a"),
+    format("aac", "aac"),
+    format("aiff", "aiff"),
+    format("aif", "aiff"),
+    format("ogg", "ogg"),
+    format("oga", "ogg"),
+    // Decoded by the libopus adapter
+    format("opus", "ogg"),
+    format("wav", "wav"),
+    format("mka", "mka"),
+    format("webm", "webm"),
+    format("caf", "caf")
+];
+
+pub fn find_format(path: &str) -> Option<&'static AudioFormat> {
+    let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+    AUDIO_FORMATS.iter().find(|f| f.extension == extension)
+}
+
+// Used by the scan to pick out music files
+pub fn is_supported(path: &str) -> bool {
+    find_format(path).is_some()
+}
+
+// None lets symphonia work out the format from the file itself
+pub fn decoder_hint(path: &str) -> Option<&'static str> {
+    find_format(path).map(|f| f.hint)
+}
+
+// Every song file is decoded the same way, the length is needed for backwards seeking
+// `seekable` can be off for files that are only read from front to back
+pub fn decoder(file: File, path: &str, seekable: bool) -> Result<Decoder<BufReader<File>>, String> {
+    let len = file.metadata().map_err(|e| e.to_string())?.len();
+    stream_decoder(BufReader::new(file), Some(len), decoder_hint(path), seekable)
+}
+
+// For streams without a path (the radio), the hint comes from elsewhere
+pub fn stream_decoder<R>(data: R, len: Option<u64>, hint: Option<&str>, seekable: bool) -> Result<Decoder<R>, String>
+where
+    R: Read + Seek + Send + Sync + 'static
+{
+    let mut builder = Decoder::builder().with_data(data)
+        .with_decoder::<symphonia_adapter_libopus::OpusDecoder>()
+        .with_seekable(seekable)
+        .with_gapless(true);
+    if let Some(len) = len {
+        builder = builder.with_byte_len(len);
+    }
+    if let Some(hint) = hint {
+        builder = builder.with_hint(hint);
+    }
+    builder.build().map_err(|e| format!("Error decoding audio file: {}", e))
+}
@@ -1,6 +1,6 @@
 // Libraries
 use std::{
-    fs::{self}, hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState}, os::windows::fs::MetadataExt, path::{PathBuf}
+    fs::{self}, hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState}, path::{PathBuf}
 };
 use tauri_plugin_log::log;
 
@@ -9,7 +9,7 @@ use lofty::{config::{ParseOptions, ParsingMode}, file::TaggedFileExt, tag::ItemK
 use lofty::prelude::*;
 
 // How you import in files that aren't lib or main
-use crate::{ types::{ SongTable, SongTableUpload }};
+use crate::{ chapters, cue, types::{ SongTable, SongTableUpload }};
 
 // import keys from https://docs.rs/lofty/latest/lofty/tag/enum.ItemKey.html
 
@@ -32,7 +32,7 @@ fn remove_special_characters(string: String) -> String {
 pub fn shuffle(vec: &mut Vec<SongTable>) {
 
     let n: usize = vec.len();
-    for i in 0..(n - 1) {
+    for i in 0..n.saturating_sub(1) {
         // Generate random index j, such that: i <= j < n
         // The remainder (`%`) after division is always less than the divisor.
         let j = (rand() as usize) % (n - i) + i;
@@ -44,7 +44,18 @@ fn rand() -> u64 {
     RandomState::new().build_hasher().finish()
 }
 
-fn get_section_marker(first_char: char) -> Option<i32> {    
+// ReplayGain values are saved as text, ex. "-6.54 dB" or "0.988547"
+pub fn parse_replaygain(value: &str) -> Option<f32> {
+    value.trim()
+        .trim_end_matches("dB")
+        .trim_end_matches("db")
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|v| v.is_finite())
+}
+
+pub fn get_section_marker(first_char: char) -> Option<i32> {    
     // Special Characters
     if first_char == '#' || first_char == '!' || first_char == '[' || first_char == ']' || first_char == '\\' || first_char == '-'
         || first_char == '_' || first_char == '\"' || first_char == '\'' || first_char == '&' || first_char == '$'
@@ -70,7 +81,7 @@ fn get_section_marker(first_char: char) -> Option<i32> {
 // Get the song metadata for the database
 pub async fn get_song_data(path: String) -> Result<SongTableUpload, ()> {
 
-    let file_size = fs::metadata(&path).unwrap().file_size();
+    let file_size = fs::metadata(&path).unwrap().len();
 
     let mut song_data: SongTableUpload = SongTableUpload {
         path: path.to_string(),
@@ -96,6 +107,9 @@ pub async fn get_song_data(path: String) -> Result<SongTableUpload, ()> {
                 None => tagged.first_tag().expect("Error no tags found")
             };
 
+            // Cue sheet, the file is split into its tracks when it is saved
+            song_data.cue_sheet = cue::read_cue_sheet(&path, tag.get_string(&ItemKey::Unknown("CUESHEET".to_string())));
+
             //  Get title tag
             if let Some(value) = tag.title().as_deref() {
                 // println!("Title: {:?}", value);
@@ -118,7 +132,8 @@ pub async fn get_song_data(path: String) -> Result<SongTableUpload, ()> {
                 }
             }
             // This song has no title tag - will not be added to the app
-            else {
+            // Unless it has a cue sheet, its tracks get their titles from the sheet
+            else if song_data.cue_sheet.is_none() {
                 return Err(());
             }
 
@@ -273,11 +288,20 @@ pub async fn get_song_data(path: String) -> Result<SongTableUpload, ()> {
                 }
             }
 
+            // Get ReplayGain tags, used for volume normalization
+            song_data.replaygain_track_gain = tag.get_string(&ItemKey::ReplayGainTrackGain).and_then(parse_replaygain);
+            song_data.replaygain_track_peak = tag.get_string(&ItemKey::ReplayGainTrackPeak).and_then(parse_replaygain);
+            song_data.replaygain_album_gain = tag.get_string(&ItemKey::ReplayGainAlbumGain).and_then(parse_replaygain);
+            song_data.replaygain_album_peak = tag.get_string(&ItemKey::ReplayGainAlbumPeak).and_then(parse_replaygain);
+
             // Get duration tag
             let properties = tagged.properties();
             let duration = properties.duration();
             song_data.duration = duration.as_secs().to_string();
 
+            // Chapter markers, for audiobooks and long mixes
+            song_data.chapters = chapters::read_chapters(&path, duration);
+
 
             // Get the directory where all the data is stored
             let image_dir = dirs::home_dir().unwrap().to_str().unwrap().to_string() + "/.config/robintuk_player/covers/";
@@ -8,10 +8,9 @@ use tauri::{Builder, Manager, Emitter, State};
 use tauri_plugin_prevent_default::Flags;
 
 // Rust Libraries
-use rodio::mixer::Mixer;
-use rodio::{DeviceSinkBuilder, MixerDeviceSink, Player as Sink};
+use rodio::Player as Sink;
 use sqlx::{Pool, Sqlite, prelude::FromRow};
-use std::{path::Path, sync::{Arc, Mutex}};
+use std::{path::Path, sync::Mutex, time::Duration};
 use tokio::runtime::Runtime;
 use chrono::{DateTime, Utc};
 use std::fs;
@@ -22,20 +21,43 @@ mod helper;
 mod types;
 mod music;
 mod db;
+mod equalizer;
+mod loudness;
+mod crossfade;
+mod tempo;
+mod output;
+mod audio;
+mod ab_loop;
+mod sleep_timer;
+mod ramp;
+mod channels;
+mod formats;
+mod visualizer;
+mod waveform;
+mod radio;
+mod podcast;
+mod bookmarks;
+mod chapters;
+mod cue;
+#[cfg(test)]
+mod test_helpers;
 
 use crate::db::{get_last_scan_date, set_last_scan_date};
 use crate::{
     db::establish_connection,
-    helper::get_song_data, music::MusicPlayer,  
-    types::{ GetCurrentSong }
+    audio::{ PlayerCommand, PlayerHandle },
+    helper::get_song_data, music::MusicPlayer, output::{ Backend, OutputHandle },
+    types::{ GetCurrentSong, SongTableUpload }
 };
 
 pub struct AppState {
-    player:  Arc<Mutex<MusicPlayer>>,
+    player: PlayerHandle,
+    output: OutputHandle,
     pool: Pool<Sqlite>,
     is_scan_ongoing: Mutex<bool>,
     is_back_restore_ongoing: Mutex<i64>,
     is_lyric_scan_ongoing: Mutex<bool>,
+    is_loudness_scan_ongoing: Mutex<bool>,
     songs_being_added: Mutex<i64>
 }
 
@@ -44,33 +66,54 @@ pub struct AppState {
 pub fn run() -> Result<(), String> {
     db::init();
 
+    // Generate the pool for the database, so it can be reused
+    let runtime = Runtime::new().unwrap();
+    let pool: Pool<Sqlite> = runtime.block_on(establish_connection())?;
+
     // Allow for output device switching without needing to restart the app, Vleer rodio used
-    let existing_mixer: Option<Mixer> = None;
-
-    let (_new_device, mixer) = if let Some(m) = existing_mixer {
-        (None, m)
-    } else {
-        let mut device: MixerDeviceSink = DeviceSinkBuilder::from_default_device()
-            .and_then(|b| {
-                b
-                .with_buffer_size(rodio::cpal::BufferSize::Fixed(1024))
-                .open_stream()
-            })
-            .or_else(|_| DeviceSinkBuilder::open_default_sink())
-            .inspect_err(|f| {
-                log::error!("Device Sink Error: {:?}", f);
-                println!("Device Sink Error: {:?}", f);
-            })
-            .unwrap();
-        device.log_on_drop(false);
-        let m = device.mixer().clone();
-        (Some(device), m)
-    };
+    // Open the saved device, or the default one if it was unplugged
+    // Without any device the app still runs, playing into the null backend
+    let output = OutputHandle::new();
+    let saved_device = runtime.block_on(db::get_output_device(&pool)).unwrap_or(None);
+    let mixer = match (Backend::from_env(), saved_device) {
+        (Some(backend), _) => output.open_backend(backend),
+        (None, Some(name)) => output.open(Some(name)).or_else(|e| {
+            log::error!("Output Device - {}", e);
+            output.open(None)
+        }),
+        (None, None) => output.open(None)
+    }.or_else(|e| {
+        log::error!("Output Device - {}, using the null output", e);
+        output.open_backend(Backend::Null)
+    })?;
 
     let sink = Sink::connect_new(&mixer);
-    let player = Arc::new(Mutex::new(MusicPlayer::new(sink)?));
-    // Generate the pool for the database, so it can be reused
-    let pool: Pool<Sqlite> = Runtime::new().unwrap().block_on(establish_connection())?;
+    let music_player = MusicPlayer::new(sink)?;
+    let analyzer = music_player.analyzer.clone();
+    let player = PlayerHandle::new(music_player)?;
+
+    // Restore the saved equalizer before anything is played
+    if let Ok(eq) = runtime.block_on(db::get_equalizer_settings(&pool)) {
+        player.send(PlayerCommand::SetEqualizer(eq));
+    }
+    if let Ok(channel_settings) = runtime.block_on(db::get_channel_settings(&pool)) {
+        player.send(PlayerCommand::SetChannels(channels::clamp_settings(channel_settings)));
+    }
+    if let Ok(mode) = runtime.block_on(db::get_replaygain_mode(&pool)) {
+        player.send(PlayerCommand::SetReplayGainMode(mode));
+    }
+    if let Ok(crossfade) = runtime.block_on(db::get_crossfade_settings(&pool)) {
+        player.send(PlayerCommand::SetCrossfade(crossfade));
+    }
+    if let Ok(ramp_ms) = runtime.block_on(db::get_volume_ramp(&pool)) {
+        player.send(PlayerCommand::SetVolumeRamp(Duration::from_millis(ramp_ms.min(ramp::MAX_RAMP_MS))));
+    }
+    // Resume bookmarks have to be there before the session is restored
+    if let Ok(resume) = runtime.block_on(db::get_resume_settings(&pool)) {
+        let songs = runtime.block_on(db::get_resume_songs(&pool)).unwrap_or_default();
+        let positions = runtime.block_on(db::get_bookmark_positions(&pool)).unwrap_or_default();
+        player.send(PlayerCommand::LoadBookmarks(resume, songs, positions));
+    }
 
     // Datetime stampes for error log files
     let now = chrono::Local::now();
@@ -107,12 +150,18 @@ pub fn run() -> Result<(), String> {
         )
         .setup(|app: &mut tauri::App| {
             
+            // Let the player thread send "player-state" events
+            player.send(PlayerCommand::AttachApp(app.handle().clone()));
+            visualizer::start(app.handle().clone(), analyzer);
+
             app.manage(AppState { 
                 player,
+                output,
                 pool,
                 is_scan_ongoing: Mutex::new(false),
                 is_back_restore_ongoing: Mutex::new(0),
                 is_lyric_scan_ongoing: Mutex::new(false),
+                is_loudness_scan_ongoing: Mutex::new(false),
                 songs_being_added: Mutex::new(0),
             });
 
@@ -123,34 +172,26 @@ pub fn run() -> Result<(), String> {
                     .with_handler(move |_app, shortcut, event| {
 
                         if event.state == ShortcutState::Pressed {
-                            let app_clone = _app.state::<AppState>().clone();
-                            let mut player = app_clone.player.lock().unwrap();
+                            let player = _app.state::<AppState>().player.clone();
                             
                             if shortcut.matches(Modifiers::FN, Code::MediaPlayPause) {
-                                if player.check_is_paused() {
-                                    player.play_song();
-                                    let _ = _app.emit("controls-play-pause", true);
+                                if let Ok(is_playing) = player.request(PlayerCommand::TogglePlay) {
+                                    let _ = _app.emit("controls-play-pause", is_playing);
                                 }
-                                else {
-                                    player.pause_song();
-                                    let _ = _app.emit("controls-play-pause", false);
-                                }                                
                             }
                             if shortcut.matches(Modifiers::FN, Code::MediaTrackNext) {
-                                if player.check_is_loaded() {
-                                    player.next_song();
-                                    let q = player.get_current_song();
-                                    if q.is_ok() {
-                                        let _ = _app.emit("get-current-song", GetCurrentSong { q: q.unwrap() });
+                                if player.request(PlayerCommand::IsLoaded).unwrap_or(false) {
+                                    player.send(PlayerCommand::NextSong);
+                                    if let Ok(Ok(q)) = player.request(PlayerCommand::GetCurrentSong) {
+                                        let _ = _app.emit("get-current-song", GetCurrentSong { q });
                                     }
                                 }                                
                             }
                             if shortcut.matches(Modifiers::FN, Code::MediaTrackPrevious) {
-                                if player.check_is_loaded() {
-                                    player.previous_song();
-                                    let q = player.get_current_song();
-                                    if q.is_ok() {
-                                        let _ = _app.emit("get-current-song", GetCurrentSong { q: q.unwrap() });
+                                if player.request(PlayerCommand::IsLoaded).unwrap_or(false) {
+                                    player.send(PlayerCommand::PreviousSong);
+                                    if let Ok(Ok(q)) = player.request(PlayerCommand::GetCurrentSong) {
+                                        let _ = _app.emit("get-current-song", GetCurrentSong { q });
                                     }
                                 }
                             }
@@ -227,6 +268,9 @@ pub fn run() -> Result<(), String> {
             commands::player_clear_queue,
             commands::shuffle_queue,
             commands::player_update_pos,
+            commands::player_play_next,
+            commands::player_move_in_queue,
+            commands::player_undo_queue_edit,
             db::get_queue,
             db::add_to_queue,
             db::clear_queue,
@@ -241,6 +285,87 @@ pub fn run() -> Result<(), String> {
             commands::player_set_repeat_mode,
             commands::player_stop,
             commands::player_get_sink_length,
+            // Equalizer Functions
+            commands::player_get_equalizer,
+            commands::player_set_equalizer,
+            commands::player_preview_equalizer,
+            commands::get_equalizer_presets,
+            commands::save_equalizer_preset,
+            commands::delete_equalizer_preset,
+            commands::use_equalizer_preset,
+            // Channel Functions
+            commands::player_get_channels,
+            commands::player_set_channels,
+            // ReplayGain Functions
+            commands::player_get_replaygain_mode,
+            commands::player_set_replaygain_mode,
+            commands::analyze_loudness,
+            commands::cancel_loudness_analysis,
+            commands::check_for_ongoing_loudness_analysis,
+            // Crossfade Functions
+            commands::player_get_crossfade,
+            commands::player_set_crossfade,
+            // Volume Ramp Functions
+            commands::player_get_volume_ramp,
+            commands::player_set_volume_ramp,
+            // Speed Functions
+            commands::player_get_speed,
+            commands::player_set_speed,
+            commands::player_get_position,
+            // Output Device Functions
+            commands::get_output_devices,
+            commands::get_output_device,
+            commands::set_output_device,
+            // Visualizer Functions
+            commands::player_set_visualizer,
+            commands::player_get_visualizer,
+            // A-B Loop Functions
+            commands::player_set_loop_a,
+            commands::player_set_loop_b,
+            commands::player_clear_loop,
+            commands::player_get_loop,
+            // Chapter Functions
+            commands::get_chapters,
+            commands::player_seek_chapter,
+            commands::player_next_chapter,
+            commands::player_previous_chapter,
+            // Radio Functions
+            commands::get_radio_stations,
+            commands::add_radio_station,
+            commands::remove_radio_station,
+            commands::import_radio_stations,
+            commands::player_play_radio,
+            commands::player_get_radio,
+            // Podcast Functions
+            commands::get_podcast_feeds,
+            commands::get_podcast_episodes,
+            commands::podcast_subscribe,
+            commands::podcast_unsubscribe,
+            commands::podcast_refresh,
+            commands::podcast_download_episode,
+            commands::podcast_delete_download,
+            commands::podcast_mark_played,
+            commands::play_podcast_episode,
+            // Sleep Timer Functions
+            commands::player_start_sleep_timer,
+            commands::player_extend_sleep_timer,
+            commands::player_cancel_sleep_timer,
+            commands::player_get_sleep_timer,
+            // Session Functions
+            commands::player_restore_session,
+            commands::player_save_session,
+            // Resume Bookmark Functions
+            commands::player_get_resume_settings,
+            commands::player_set_resume_settings,
+            commands::player_set_song_resume,
+            commands::get_resume_songs,
+            commands::get_bookmarks,
+            commands::clear_bookmark,
+            // Waveform Functions
+            commands::get_waveform,
+            // Playback Error Functions
+            commands::get_playback_errors,
+            commands::clear_playback_error,
             // Event Caller Functions
             commands::update_current_song_played,
             commands::new_playlist_added,
@@ -271,8 +396,15 @@ pub fn run() -> Result<(), String> {
             db::add_artist_cover,
             scan_for_deleted
         ])        
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            // Save where the player was, so the next start picks up from there
+            if let tauri::RunEvent::Exit = event {
+                let state = app.state::<AppState>();
+                let _ = tauri::async_runtime::block_on(commands::save_session(&state.player, &state.pool));
+            }
+        });
     Ok(())
 }
 
@@ -289,9 +421,9 @@ struct ScanResults {
 }
 
 #[derive(Clone, serde::Serialize)]
-struct ScanProgress {
-    length: usize,
-    current: i32
+pub struct ScanProgress {
+    pub length: usize,
+    pub current: i32
 }
 
 #[derive(Clone, serde::Serialize)]
@@ -299,6 +431,25 @@ pub struct GetScanStatus {
   pub res: bool
 }
 
+// Files with a cue sheet are saved as one song per track instead of the whole file
+async fn save_song_data(song: SongTableUpload, pool: &Pool<Sqlite>) -> Result<(), String> {
+    let tracks = cue::split_song(&song);
+    if tracks.is_empty() {
+        // Only the sheet had titles, but it didn't split the file
+        if song.name.is_none() {
+            log::error!("Scan Music - No title tag and no cue sheet tracks: {:?}", &song.path);
+            return Err("No title tag".to_string());
+        }
+        db::save_song(song, pool).await?;
+        return Ok(());
+    }
+
+    for track in tracks {
+        db::save_song(track, pool).await?;
+    }
+    db::remove_split_file(pool, &song.path).await
+}
+
 // use the path value to check, since that is a unique value in each entry (files cannot share paths)
 #[tauri::command]
 async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Result<ScanResults, String> {
@@ -326,12 +477,7 @@ async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Re
         
         for p in &directories {
             let t = jwalk::WalkDir::new(p.dir_path.clone()).into_iter().filter_map(|e| e.ok()).filter(|x|
-                x.path().display().to_string().contains(".mp3")
-                || x.path().display().to_string().contains(".flac")
-                || x.path().display().to_string().contains(".m4a")
-                || x.path().display().to_string().contains(".aiff")
-                || x.path().display().to_string().contains(".ogg")
-                || x.path().display().to_string().contains(".wav")
+                x.file_type().is_file() && formats::is_supported(&x.path().display().to_string())
             ).count();
             scan_length += t;
         }
@@ -346,14 +492,8 @@ async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Re
             pool.execute(move || {
                 // walk through the entire directory, sub folders and all
                 for entry in jwalk::WalkDir::new(path.dir_path).into_iter().filter_map(|e| e.ok()).filter(|x| x.file_type().is_file())  {
-                    // if the files are music files (For now only grab mp3 and wav files \ flac to be added later)
-                    if entry.path().display().to_string().contains(".mp3")
-                        || entry.path().display().to_string().contains(".flac")
-                        || entry.path().display().to_string().contains(".m4a")
-                        || entry.path().display().to_string().contains(".aiff")
-                        || entry.path().display().to_string().contains(".ogg")
-                        || entry.path().display().to_string().contains(".wav")
-                    {
+                    // if the files are music files, the formats are listed in formats.rs
+                    if formats::is_supported(&entry.path().display().to_string()) {
                         tx1.send(entry.path().display().to_string()).unwrap();
                     }
                 }
@@ -366,6 +506,10 @@ async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Re
             let last_res = last_scan_res.clone();
 
             let last_modified: DateTime<Utc> = fs::metadata(&received).unwrap().modified().unwrap().into();
+            // A cue sheet added or edited next to the file counts as a change to the file
+            let last_modified = cue::find_cue_file(&received)
+                .and_then(|cue_file| fs::metadata(cue_file).ok()?.modified().ok())
+                .map_or(last_modified, |cue_modified| last_modified.max(DateTime::<Utc>::from(cue_modified)));
 
             let does_exist = db::does_entry_exist(&state.pool, &received).await.unwrap();
 
@@ -382,11 +526,11 @@ async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Re
 
                         if song_res.is_ok() {
                             if does_exist {
-                                let _ = db::update_song(song_res.unwrap(), &state.pool).await;
+                                let _ = save_song_data(song_res.unwrap(), &state.pool).await;
                                 num_updated += 1;
                             }
                             else {
-                                let _ = db::add_song(song_res.unwrap(), &state.pool).await;
+                                let _ = save_song_data(song_res.unwrap(), &state.pool).await;
                                 num_added += 1;                    
                             }
                         }
@@ -404,7 +548,7 @@ async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Re
                     let song_res = get_song_data(received).await;
 
                     if song_res.is_ok() {
-                        let _ = db::add_song(song_res.unwrap(), &state.pool).await;
+                        let _ = save_song_data(song_res.unwrap(), &state.pool).await;
                         num_added += 1;                    
                         
                     }
@@ -422,11 +566,11 @@ async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Re
 
                     if song_res.is_ok() {
                         if does_exist {
-                            let _ = db::update_song(song_res.unwrap(), &state.pool).await;
+                            let _ = save_song_data(song_res.unwrap(), &state.pool).await;
                             num_updated += 1;
                         }
                         else {
-                            let _ = db::add_song(song_res.unwrap(), &state.pool).await;
+                            let _ = save_song_data(song_res.unwrap(), &state.pool).await;
                             num_added += 1;                    
                         }
                     }
@@ -439,7 +583,7 @@ async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Re
                     let song_res = get_song_data(received).await;
 
                     if song_res.is_ok() {
-                        let _ = db::add_song(song_res.unwrap(), &state.pool).await;
+                        let _ = save_song_data(song_res.unwrap(), &state.pool).await;
                         num_added += 1;                    
                         
                     }
@@ -488,25 +632,29 @@ async fn scan_directory(state: State<AppState, '_>, app: tauri::AppHandle) -> Re
 
 #[derive(serde::Serialize, FromRow)]
 struct SongPath {
-    path: String
+    path: String,
+    // The file of a cue sheet track
+    source_path: Option<String>
 }
 
 #[tauri::command]
 async fn scan_for_deleted(state: State<AppState, '_>, app: tauri::AppHandle) -> Result<(), String> {
 
-    let songs: Vec<SongPath> = sqlx::query_as::<_, SongPath>("SELECT path FROM songs")
+    let songs: Vec<SongPath> = sqlx::query_as::<_, SongPath>("SELECT path, source_path FROM songs")
         .fetch_all(&state.pool)
         .await.unwrap();
 
     for entry in songs {
         // Check if path exists
-        if Path::new(&entry.path).exists() == false {
+        if Path::new(entry.source_path.as_ref().unwrap_or(&entry.path)).exists() == false {
             let _ = sqlx::query("DELETE FROM songs WHERE path = $1")
                 .bind(&entry.path)
                 .execute(&state.pool)
                 .await;
+            waveform::remove_cache(&entry.path);
         }
     }    
+    let _ = db::remove_orphan_chapters(&state.pool).await;
     app.emit("remove-song", false).unwrap();
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,229 @@
+use std::{ f64::consts::PI, fs::File };
+use rodio::Source;
+
+use crate::{ cue, formats, types::SongTable };
+
+// EBU R128 / ITU-R BS.1770 loudness measurement
+// Used for songs without ReplayGain tags, the results are saved next to the song in the database
+
+// ReplayGain 2.0 plays everything at this loudness
+pub const REFERENCE_LUFS: f64 = -18.0;
+// Blocks quieter than this are ignored (silence)
+const ABSOLUTE_GATE: f64 = -70.0;
+// Blocks this far below the average are ignored (quiet intros, fades)
+const RELATIVE_GATE: f64 = -10.0;
+// Number of taps used for each oversampled point of the true peak
+const PEAK_TAPS: usize = 16;
+
+pub struct LoudnessResult {
+    // Integrated loudness in LUFS, None if the song is silent
+    pub integrated: Option<f64>,
+    // Linear true peak (1.0 = full scale)
+    pub true_peak: f64
+}
+
+// Decode the whole song and measure it, a cue sheet track is measured over its own span of the file
+pub fn analyze_file(song: &SongTable) -> Result<LoudnessResult, String> {
+    let path = song.source_path.as_deref().unwrap_or(&song.path);
+    let (start, end) = cue::song_span(song);
+    let file = File::open(path).map_err(|e| format!("Song does not exist: {:?}", e))?;
+    // Only a cue sheet track that starts part way in has to seek
+    let decoder = formats::decoder(file, path, !start.is_zero())?;
+    let decoder = cue::Span::new(decoder, start, end).map_err(|e| format!("Error seeking to the cue track: {}", e))?;
+
+    let channels = u16::from(decoder.channels()) as usize;
+    let sample_rate = u32::from(decoder.sample_rate()) as f64;
+    let mut meter = LoudnessMeter::new(channels, sample_rate);
+
+    for sample in decoder {
+        meter.push(sample as f64);
+    }
+
+    Ok(meter.finish())
+}
+
+
+// ------------------- Filters -------------------
+
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2]
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.z[0];
+        self.z[0] = self.b[1] * input - self.a[1] * output + self.z[1];
+        self.z[1] = self.b[2] * input - self.a[2] * output;
+        output
+    }
+}
+
+// The two stage K-weighting filter from BS.1770, coefficients recalculated for any sample rate
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    // Stage 1 - high shelf, models the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / sample_rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2]
+    };
+
+    // Stage 2 - high pass (RLB weighting)
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2]
+    };
+
+    [shelf, high_pass]
+}
+
+// Windowed sinc coefficients for the 3 in-between points of 4x oversampling
+fn peak_coefficients() -> [[f64; PEAK_TAPS]; 3] {
+    let mut coefs = [[0.0; PEAK_TAPS]; 3];
+    let center = (PEAK_TAPS / 2 - 1) as f64;
+
+    for (phase, row) in coefs.iter_mut().enumerate() {
+        let t = center + (phase + 1) as f64 / 4.0;
+        for (k, c) in row.iter_mut().enumerate() {
+            let x = t - k as f64;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+            // Hann window over the length of the filter
+            let window = 0.5 + 0.5 * (PI * x / (PEAK_TAPS as f64 / 2.0)).cos();
+            *c = sinc * window;
+        }
+    }
+    coefs
+}
+
+
+// ------------------- Meter -------------------
+
+struct LoudnessMeter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    // Sum of the squared samples of the current 100ms piece, per channel
+    current: Vec<f64>,
+    current_len: usize,
+    step_len: usize,
+    // Mean square of the last 4 pieces (one 400ms block)
+    steps: Vec<f64>,
+    blocks: Vec<f64>,
+    // True peak
+    history: Vec<[f64; PEAK_TAPS]>,
+    peak_coefs: [[f64; PEAK_TAPS]; 3],
+    peak: f64,
+    channel: usize
+}
+
+impl LoudnessMeter {
+    fn new(channels: usize, sample_rate: f64) -> Self {
+        let channels = channels.max(1);
+        // Surround channels are weighted louder and the LFE is skipped (L, R, C, LFE, Ls, Rs)
+        let weights = (0..channels).map(|c| {
+            if channels == 6 && c == 3 { 0.0 }
+            else if channels == 6 && c >= 4 { 1.41 }
+            else { 1.0 }
+        }).collect();
+
+        Self {
+            channels,
+            weights,
+            filters: vec![k_weighting(sample_rate); channels],
+            current: vec![0.0; channels],
+            current_len: 0,
+            step_len: ((sample_rate / 10.0).round() as usize).max(1),
+            steps: vec![],
+            blocks: vec![],
+            history: vec![[0.0; PEAK_TAPS]; channels],
+            peak_coefs: peak_coefficients(),
+            peak: 0.0,
+            channel: 0
+        }
+    }
+
+    // Samples are interleaved, the same as they come out of the decoder
+    fn push(&mut self, sample: f64) {
+        let c = self.channel;
+
+        // Loudness
+        let [shelf, high_pass] = &mut self.filters[c];
+        let weighted = high_pass.process(shelf.process(sample));
+        self.current[c] += weighted * weighted;
+
+        // True peak
+        let history = &mut self.history[c];
+        history.rotate_left(1);
+        history[PEAK_TAPS - 1] = sample;
+        self.peak = self.peak.max(sample.abs());
+        for coefs in self.peak_coefs.iter() {
+            let value: f64 = coefs.iter().zip(history.iter()).map(|(a, b)| a * b).sum();
+            self.peak = self.peak.max(value.abs());
+        }
+
+        self.channel += 1;
+        if self.channel == self.channels {
+            self.channel = 0;
+            self.current_len += 1;
+            if self.current_len == self.step_len {
+                self.finish_step();
+            }
+        }
+    }
+
+    fn finish_step(&mut self) {
+        let power: f64 = self.current.iter()
+            .zip(self.weights.iter())
+            .map(|(sum, weight)| weight * sum / self.current_len as f64)
+            .sum();
+        self.steps.push(power);
+
+        // Each block is 400ms long, and a new one starts every 100ms
+        if self.steps.len() >= 4 {
+            let block: f64 = self.steps[self.steps.len() - 4..].iter().sum::<f64>() / 4.0;
+            self.blocks.push(block);
+        }
+
+        self.current.iter_mut().for_each(|v| *v = 0.0);
+        self.current_len = 0;
+    }
+
+    fn finish(self) -> LoudnessResult {
+        let to_lufs = |power: f64| -0.691 + 10.0 * power.log10();
+
+        let above_absolute: Vec<f64> = self.blocks.iter()
+            .copied()
+            .filter(|b| *b > 0.0 && to_lufs(*b) > ABSOLUTE_GATE)
+            .collect();
+
+        if above_absolute.is_empty() {
+            return LoudnessResult { integrated: None, true_peak: self.peak };
+        }
+
+        let relative_gate = to_lufs(above_absolute.iter().sum::<f64>() / above_absolute.len() as f64) + RELATIVE_GATE;
+        let gated: Vec<f64> = above_absolute.into_iter().filter(|b| to_lufs(*b) > relative_gate).collect();
+
+        let integrated = if gated.is_empty() {
+            None
+        }
+        else {
+            Some(to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+        };
+
+        LoudnessResult { integrated, true_peak: self.peak }
+    }
+}
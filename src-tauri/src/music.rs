@@ -1,9 +1,13 @@
-use std::{ fs::File, io::BufReader, time };
-use rodio::{ Decoder, Player };
-use tauri::State;
+use std::{ collections::VecDeque, fs::File, sync::Arc, thread, time };
+use flume::Receiver;
+use rodio::{ Player, Source, mixer::Mixer };
 use tauri_plugin_log::log::{self, error};
 
-use crate::{ AppState, commands, types::SongTable };
+use crate::{
+    ab_loop::{ AbLoop, LoopControls }, bookmarks::{ BookmarkChange, Bookmarks }, channels::{ ChannelControls, Channels }, crossfade::{ Crossfade, CrossfadeControls }, cue, equalizer::{ Equalizer, EqualizerControls }, formats, loudness, podcast, radio::RadioSource,
+    ramp::{ Ramp, RampControls }, sleep_timer::SleepTimer, tempo::{ Tempo, TempoControls }, visualizer::{ AnalyzerControls, AnalyzerTap },
+    types::{ AbLoopState, ChannelSettings, CrossfadeSettings, EqualizerSettings, PlaybackProgress, PlaybackSession, PlaybackSpeed, PlayerStatus, RadioNowPlaying, RadioStation, ResumeSettings, SleepTimerStatus, SongTable }
+};
 
 /*
 Current Errors:
@@ -20,9 +24,83 @@ pub struct MusicPlayer {
     pub position: usize,
     pub repeat_mode: i64,
     pub shuffle_mode: bool,
-    pub queue: Vec<SongTable>
+    pub queue: Vec<SongTable>,
+    // (queue, position) before each edit, newest last
+    queue_undo: Vec<(Vec<SongTable>, usize)>,
+    pub equalizer: Arc<EqualizerControls>,
+    pub channels: Arc<ChannelControls>,
+    // 0 - Off, 1 - Track, 2 - Album
+    pub replaygain_mode: i64,
+    // Set when the queue was started from an album, switches ReplayGain to album mode
+    pub is_album_queue: bool,
+    pub crossfade: Arc<CrossfadeControls>,
+    pub crossfade_settings: CrossfadeSettings,
+    pub tempo: Arc<TempoControls>,
+    pub ab_loop: Arc<LoopControls>,
+    pub ramp: Arc<RampControls>,
+    pub analyzer: Arc<AnalyzerControls>,
+    // A can be set on its own, the loop starts once B is set
+    loop_a: Option<time::Duration>,
+    loop_b: Option<time::Duration>,
+    // Number of songs the sink should hold, songs taken out on purpose are counted here so a drop means a song ended by itself
+    sink_len: usize,
+    // Paths and loop ids of the songs appended to the sink, the last sink.len() of them are still in it
+    loaded: VecDeque<(String, u64)>,
+    // Only try to pre-load once per song, so a broken next song isn't opened over and over
+    preload_tried: bool,
+    // Volume picked by the user, the sink can be lower while the sleep timer fades out
+    volume: f32,
+    sleep_timer: Option<SleepTimer>,
+    // Set when the sleep timer stopped playback, until the player thread has told the frontend
+    sleep_finished: bool,
+    // (path, reason) of songs that failed to load, until the player thread has saved them
+    load_errors: Vec<(String, String)>,
+    // Paths of songs that played to the end, until the player thread has handled them
+    finished: Vec<String>,
+    // Songs that ended while a command was fading out, until the player thread has told the frontend
+    track_ends: Vec<TrackEnd>,
+    // Where long songs were left off, see bookmarks.rs
+    bookmarks: Bookmarks,
+    // Set while a radio station is playing instead of the queue
+    radio: Option<RadioStation>,
+    radio_titles: Option<Receiver<String>>,
+    radio_title: Option<String>
 }
 
+// What happened when the sink finished a song by itself
+pub enum TrackEnd {
+    // The queue moved on to this song
+    NextSong(SongTable),
+    // Repeat is off and the last song in the queue ended
+    QueueEnded
+}
+
+// The queue after an edit, the queue tables are rewritten from the player's copy
+pub struct QueueSnapshot {
+    pub songs: Vec<SongTable>,
+    pub position: usize,
+    // The songs are in the shuffled order
+    pub shuffled: bool
+}
+
+// A planned queue edit, the queue tables are saved from it before the player applies it
+pub struct QueueEdit {
+    pub queue: QueueSnapshot,
+    // Where the queue was when the edit was planned, a queue that moved on in the meantime drops the edit
+    planned_from: (Vec<String>, usize),
+    undo: bool
+}
+
+// How many queue edits can be undone
+const QUEUE_UNDO_LIMIT: usize = 20;
+
+// How long before the end of a song the next one is put in the sink (plus the crossfade length)
+const PRELOAD_AHEAD: time::Duration = time::Duration::from_secs(5);
+
+// How often a fade out is checked on, and how much longer than the ramp it is waited for
+const RAMP_POLL: time::Duration = time::Duration::from_millis(2);
+const RAMP_MARGIN: time::Duration = time::Duration::from_millis(50);
+
 // Rework Parts
 impl MusicPlayer {
     pub fn new(sink: Player) -> Result<Self, String> {
@@ -33,152 +111,290 @@ impl MusicPlayer {
             position: 0,
             repeat_mode: 1,
             shuffle_mode: false,
-            queue: vec![]
+            queue: vec![],
+            queue_undo: vec![],
+            equalizer: EqualizerControls::new(EqualizerSettings::default()),
+            channels: ChannelControls::new(ChannelSettings::default()),
+            replaygain_mode: 0,
+            is_album_queue: false,
+            crossfade: CrossfadeControls::new(),
+            crossfade_settings: CrossfadeSettings::default(),
+            tempo: TempoControls::new(),
+            ab_loop: LoopControls::new(),
+            ramp: RampControls::new(),
+            analyzer: AnalyzerControls::new(),
+            loop_a: None,
+            loop_b: None,
+            sink_len: 0,
+            loaded: VecDeque::new(),
+            preload_tried: false,
+            volume: 1.0,
+            sleep_timer: None,
+            sleep_finished: false,
+            load_errors: vec![],
+            finished: vec![],
+            track_ends: vec![],
+            bookmarks: Bookmarks::default(),
+            radio: None,
+            radio_titles: None,
+            radio_title: None
         })
     }
     
     // ------------------- Simple Media Functions -------------------
-    // Play the song in the seek
+    // Play the song in the seek, fading in from silence
     pub fn play_song(&self) {
+        if self.sink.is_paused() {
+            self.ramp.set_gain(0.0);
+        }
+        self.ramp.fade_in();
         self.sink.play();
     }
-    // Pause the song in the sink
-    pub fn pause_song(&self) {
+    // Pause the song in the sink, once it has faded out
+    pub fn pause_song(&mut self) {
+        self.save_bookmark();
+        self.ramp_down();
         self.sink.pause();
     }
     // Pause the song in the sink
-    pub fn stop_song(&self) {
-        self.sink.stop();
+    pub fn stop_song(&mut self) {
+        self.save_bookmark();
+        self.stop_radio();
+        self.stop_sink();
+        self.crossfade.reset();
     }
-    // Get current spot in the song
-    pub fn get_song_pos(&self) {
-        self.sink.get_pos();
+    // Get current spot in the song, in the song's own time so it is right at any speed
+    pub fn get_song_pos(&self) -> time::Duration {
+        if self.sink.empty() || self.radio.is_some() {
+            return time::Duration::ZERO;
+        }
+        return self.tempo.position();
     }
     // Change the time of the song
-    pub fn seek(&self, position: u64) {
-        // println!("position: {:?}", time::Duration::from_secs(position));
-        let _ = self.sink.try_seek(time::Duration::from_secs(position)).map_err(|op| println!("{:?}", op));
+    pub fn seek(&mut self, position: time::Duration) {
+        // println!("position: {:?}", position);
+        let playing = !self.sink.is_paused() && !self.sink.empty();
+        if playing {
+            self.ramp_down();
+        }
+        let _ = self.sink.try_seek(position).map_err(|op| println!("{:?}", op));
+        if playing {
+            self.ramp.fade_in();
+        }
+    }
+    // Fade the playing song out and wait for it, so pausing, seeking or skipping doesn't click
+    // Blocks the player thread for at most the ramp length (+ a margin in case the device stopped pulling samples)
+    fn ramp_down(&mut self) {
+        self.ramp.fade_out();
+        let duration = self.ramp.duration();
+        if duration.is_zero() || self.sink.is_paused() || self.sink.empty() {
+            self.ramp.set_gain(0.0);
+            return;
+        }
+
+        let deadline = time::Instant::now() + duration + RAMP_MARGIN;
+        while self.ramp.gain() > 0.0 && time::Instant::now() < deadline {
+            thread::sleep(RAMP_POLL);
+        }
+        // The song can end while it fades out, the queue has to move on before the command changes the sink
+        if let Some(end) = self.check_track_end() {
+            self.track_ends.push(end);
+        }
+    }
+    pub fn set_ramp_duration(&self, duration: time::Duration) {
+        self.ramp.set_duration(duration);
+    }
+    pub fn get_ramp_duration(&self) -> time::Duration {
+        return self.ramp.duration();
     }
     // Set the repeat mode for the player
     pub fn set_repeat_mode(&mut self, mode: i64) {
         self.repeat_mode = mode;
+        self.update_crossfade();
     }
     // Change the volume of the sink
-    pub fn set_volume(&self, vol: f32) {
-        self.sink.set_volume(vol);
+    pub fn set_volume(&mut self, vol: f32) {
+        self.volume = vol;
+        let fade = self.sleep_timer.as_ref().map(|t| t.fade_factor(self.song_left())).unwrap_or(1.0);
+        self.sink.set_volume(vol * fade);
     }
     // Move and play the next song in the queue
     pub fn next_song(&mut self)  {
+        self.save_bookmark();
+        self.clear_loop();
+        // Repeat one seeks back to the start, which has its own ramp
+        if self.repeat_mode != 2 {
+            self.ramp_down();
+        }
         // no repeat
         if self.repeat_mode == 0 {
-            if self.position + 1 == self.queue.len() - 1 {
+            // The last song (or an empty queue) stops playback
+            if self.position + 1 >= self.queue.len() {
                 self.position = 0;
-                self.sink.stop();
+                self.stop_sink();
+                self.crossfade.reset();
             }
-            else {
+            // The next song was pre-loaded
+            else if self.sink.len() > 1 {
                 let new_pos = self.position + 1;
                 
                 // Update the current position in the player
                 let _ = self.update_current_index(new_pos);
                 // Play the next song - Create case for last song in queue
-                let _ = self.sink.skip_one();
+                self.skip_sink();
                 self.play_song();
             }
+            else {
+                self.clear_sink();
+                self.crossfade.reset();
+                self.play_playable(self.position + 1, false);
+            }
         }
         // repeat the queue
         else if self.repeat_mode == 1 {
-            self.sink.clear();
+            self.clear_sink();
+            self.crossfade.reset();
             let mut new_pos = self.position + 1;
             // If the new position will be larger than the length of the queue, reset to 0
             if new_pos >= self.queue.len() {
                 new_pos = 0;
             }
-            // Update the current position in the player
-            let _ = self.update_current_index(new_pos);
-            // Load the new song
-            let _ = self.load_song(new_pos);
-            self.play_song();
+            // Load the new song, skipping any that can't be played
+            self.play_playable(new_pos, false);
         }
         // Repeat one song
         else {
-            self.seek(0);
+            self.seek(time::Duration::ZERO);
             self.play_song();
         }
     }
     // Move and play the previous song in the queue
     pub fn previous_song(&mut self) {
+        self.save_bookmark();
+        self.clear_loop();
+        self.ramp_down();
         // Drop all the songs in the sink, then load new songs
-        self.sink.clear();
+        self.clear_sink();
+        self.crossfade.reset();
 
         let new_pos;
         // If the new position will be smaller than the starting song, set the pos to the last song in the queue
         if self.position == 0 {
-            new_pos = self.queue.len() - 1;
+            new_pos = self.queue.len().saturating_sub(1);
         }
         else {
             new_pos = self.position - 1;
         }
 
-        let _ = self.update_current_index(new_pos);
-        // Load the first song
-        let _ = self.load_song(new_pos);
-        self.play_song();
+        // Load the first song, going further back past any that can't be played
+        self.play_playable(new_pos, true);
         
         // If the new position plus 1 is less than the queue length, load next song
-        if (1 + new_pos) < self.queue.len() {
-           let _ = self.load_song(new_pos + 1);
+        if !self.sink.empty() && (1 + self.position) < self.queue.len() {
+           let _ = self.load_song(self.position + 1);
         }
     }
 
     pub fn jump_to_song(&mut self, index: usize) {
-        
-        self.sink.clear();
+        self.save_bookmark();
+        self.clear_loop();
+        self.ramp_down();
 
-        // Update the current position in the player
-        let _ = self.update_current_index(index);
-        // Load the new song
-        let _ = self.load_song(index);
-        self.play_song();
-        
+        self.clear_sink();
+        self.crossfade.reset();
+
+        // Load the new song, skipping any that can't be played
+        self.play_playable(index, false);
+    }
+    // Load and play the song at index, or the closest one after it (before it when going backwards) that can be played
+    // Only wraps around the queue while repeat is on, otherwise playback stops at the end
+    fn play_playable(&mut self, index: usize, backwards: bool) {
+        match self.load_playable(index, backwards) {
+            Ok(index) => {
+                let _ = self.update_current_index(index);
+                self.play_song();
+            },
+            Err(e) => log::error!("Play Song - {}", e)
+        }
+    }
+    // Returns the index of the song that was loaded
+    fn load_playable(&mut self, index: usize, backwards: bool) -> Result<usize, String> {
+        let len = self.queue.len();
+        let wrap = self.repeat_mode != 0 || backwards;
+        let mut index = index;
+
+        for _ in 0..len {
+            if index >= len {
+                if !wrap {
+                    break;
+                }
+                index = 0;
+            }
+            if self.load_song(index).is_ok() {
+                return Ok(index);
+            }
+
+            index = match (backwards, index) {
+                (true, 0) if wrap => len - 1,
+                (true, 0) => break,
+                (true, i) => i - 1,
+                (false, i) => i + 1
+            };
+        }
+        Err("No song left in the queue that can be played".to_string())
     }
     // ------------------- Queue Functions -------------------
     // Called when a user clicks play on a song, album, or playlist
     pub fn set_queue(&mut self, q: Vec<SongTable>) {
         self.queue = q;
+        self.queue_undo.clear();
         // self.sink.stop();
         self.position = 0;
     }
     // Clear the queue and empty the sink
     pub fn clear_queue(&mut self) {
-        self.sink.stop();
+        self.save_bookmark();
+        self.clear_loop();
+        self.stop_radio();
+        self.stop_sink();
+        self.crossfade.reset();
         self.queue.clear();
+        self.queue_undo.clear();
         self.position = 0;
     }
     // Called when a user adds a song to the queue
     pub fn add_to_queue(&mut self, q: Vec<SongTable>) {
+        self.save_queue_undo();
         for song in q {
             self.queue.push(song);
         }
     }
 
-    pub fn remove_from_queue(&mut self, index: usize, state: State<'_, AppState>, app: tauri::AppHandle) {
+    // Returns true when the playing song was removed, so the frontend can be told about the new song
+    pub fn remove_from_queue(&mut self, index: usize) -> bool {
+        if index >= self.queue.len() {
+            error!("Remove From Queue: index is out of bounds: {:?} - {:?}", &self.position, index);
+            return false;
+        }
+        self.save_queue_undo();
         // If the song you want to emove is curently playing, skip to next song
         if self.position == index {
             self.next_song();
             self.queue.remove(index);
-            self.position = self.position - 1;
-            commands::update_current_song_played(state, app);
-        }
-        else if self.queue.len() < index {
-            error!("Remove From Queue: index is out of bounds: {:?} - {:?}", &self.position, index);
+            // next_song stops at (or wraps to) the start of the queue, which is already before the removed song
+            if self.position > index {
+                self.position -= 1;
+            }
+            return true;
         }
         else if self.position < index {
             self.queue.remove(index);
         }
-        else if self.position > index {
+        else {
             self.queue.remove(index);
-            self.position = self.position - 1;
+            self.position -= 1;
         }
+        false
     }
 
     pub fn remove_from_queue_by_value(&mut self, path: String) {
@@ -188,7 +404,7 @@ impl MusicPlayer {
             if self.position == index {
                 // self.next_song();
                 self.queue.remove(index);
-                self.position = self.position - 1;
+                self.position = self.position.saturating_sub(1);
             }
             else if self.queue.len() < index {
                 error!("Remove From Queue: index is out of bounds: {:?} - {:?}", &self.position, index);
@@ -198,7 +414,7 @@ impl MusicPlayer {
             }
             else if self.position > index {
                 self.queue.remove(index);
-                self.position = self.position - 1;
+                self.position -= 1;
             }
             println!("Queue Length: {:?} - Position: {:?} - Index: {:?}", &self.queue.len(), &self.position, &index);
         }
@@ -217,7 +433,7 @@ impl MusicPlayer {
     }
     
     pub fn update_current_index(&mut self, pos: usize) -> Result<(), String> {
-        if pos > self.queue.len() {
+        if pos >= self.queue.len() {
             self.position = 0;
             log::error!("Update Current Index MusicPlayer - Position is larger than Queue length");
             Err("position is larger than queue length".to_string())
@@ -231,10 +447,135 @@ impl MusicPlayer {
         }
     }
     
+    // ------------------- Queue Edit Functions -------------------
+    // Work on the queue as it is played (the shuffled order while shuffle is on), the playing song never stops
+    // Edits are planned first, the queue tables are saved from the plan and only then is it applied
+
+    fn save_queue_undo(&mut self) {
+        self.queue_undo.push((self.queue.clone(), self.position));
+        if self.queue_undo.len() > QUEUE_UNDO_LIMIT {
+            self.queue_undo.remove(0);
+        }
+    }
+
+    pub fn get_queue_snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            songs: self.queue.clone(),
+            position: self.position,
+            shuffled: self.shuffle_mode
+        }
+    }
+
+    fn plan_edit(&self, songs: Vec<SongTable>, position: usize, undo: bool) -> QueueEdit {
+        QueueEdit {
+            queue: QueueSnapshot { songs, position, shuffled: self.shuffle_mode },
+            planned_from: (self.queue.iter().map(|song| song.path.clone()).collect(), self.position),
+            undo
+        }
+    }
+
+    // Put the songs right after the playing one, None when there is nothing to add
+    pub fn plan_play_next(&self, songs: Vec<SongTable>) -> Option<QueueEdit> {
+        if songs.is_empty() {
+            return None;
+        }
+        let mut queue = self.queue.clone();
+        let index = if queue.is_empty() { 0 } else { self.position + 1 };
+        queue.splice(index..index, songs);
+        Some(self.plan_edit(queue, self.position, false))
+    }
+
+    // Move the songs at these indexes so they start at `to` (an index from before the move), keeping their order
+    pub fn plan_move(&self, mut indexes: Vec<usize>, to: usize) -> Result<Option<QueueEdit>, String> {
+        indexes.sort_unstable();
+        indexes.dedup();
+        let len = self.queue.len();
+        if indexes.last().is_some_and(|i| *i >= len) {
+            log::error!("Move In Queue - index is out of bounds: {:?} - {:?}", &indexes, len);
+            return Err("Index is out of bounds".to_string());
+        }
+        if indexes.is_empty() {
+            return Ok(None);
+        }
+
+        // The new order as indexes into the old queue
+        let to = to.min(len);
+        let stays = |i: &usize| indexes.binary_search(i).is_err();
+        let order: Vec<usize> = (0..to).filter(stays)
+            .chain(indexes.iter().copied())
+            .chain((to..len).filter(stays))
+            .collect();
+
+        Ok(Some(self.plan_edit(
+            order.iter().map(|i| self.queue[*i].clone()).collect(),
+            order.iter().position(|i| *i == self.position).unwrap_or(0),
+            false
+        )))
+    }
+
+    // The queue before the last edit, None when there is nothing to undo
+    pub fn plan_undo(&self) -> Option<QueueEdit> {
+        let (queue, position) = self.queue_undo.last()?;
+
+        // The song playing now keeps playing, it may have moved on since the edit
+        let playing = self.queue.get(self.position).map(|song| &song.path);
+        let position = match playing {
+            Some(path) if queue.get(*position).is_some_and(|song| song.path == *path) => *position,
+            Some(path) => queue.iter().position(|song| song.path == *path).unwrap_or(*position),
+            None => *position
+        };
+        Some(self.plan_edit(queue.clone(), position.min(queue.len().saturating_sub(1)), true))
+    }
+
+    // Undoing drops the edit that was undone instead of saving the queue for a later undo
+    pub fn apply_queue_edit(&mut self, edit: QueueEdit) -> Result<(), String> {
+        let (paths, position) = &edit.planned_from;
+        if *position != self.position || edit.queue.shuffled != self.shuffle_mode || !self.queue.iter().map(|song| &song.path).eq(paths.iter()) {
+            log::error!("Apply Queue Edit - The queue changed since the edit was planned");
+            return Err("The queue changed while the edit was being saved".to_string());
+        }
+
+        if edit.undo {
+            self.queue_undo.pop();
+        }
+        else {
+            self.save_queue_undo();
+        }
+        self.queue = edit.queue.songs;
+        self.position = edit.queue.position;
+        self.refresh_preload();
+        Ok(())
+    }
+
+    // The song pre-loaded for gapless playback might not be the next one anymore after an edit
+    // The sink can't drop only that song, so the playing song is loaded again at the same spot
+    fn refresh_preload(&mut self) {
+        if self.sink.len() < 2 || self.radio.is_some() {
+            return;
+        }
+        let preloaded = self.loaded.back().map(|(path, _)| path.clone());
+        let next = self.next_index().map(|index| self.queue[index].path.clone());
+        if preloaded == next {
+            return;
+        }
+
+        let position = self.get_song_pos();
+        let paused = self.sink.is_paused();
+        self.clear_loop();
+        self.clear_sink();
+        self.crossfade.reset();
+        if self.load_song(self.position).is_ok() {
+            let _ = self.sink.try_seek(position).map_err(|e| log::error!("Refresh Pre-load - Error seeking {:?}", e));
+            if !paused {
+                self.play_song();
+            }
+        }
+    }
+
     // ------------------- Checker Functions -------------------
     pub fn get_current_song(&self) -> Result<SongTable, bool>  {
-        if self.queue.len() > 0 {
-            return Ok(self.queue[self.position].clone());
+        if let Some(song) = self.queue.get(self.position) {
+            return Ok(song.clone());
         }
         else {
             log::error!("Get Current Song MusicPlayer - Error decoding Audio File");
@@ -261,44 +602,591 @@ impl MusicPlayer {
     pub fn get_shuffle(&self) -> bool {
         return self.shuffle_mode;
     }
+
+    // Everything the frontend needs to keep its controls in sync
+    pub fn get_status(&self) -> PlayerStatus {
+        return PlayerStatus {
+            index: self.position,
+            queue_length: self.queue.len(),
+            is_paused: self.sink.is_paused(),
+            is_loaded: !self.sink.empty(),
+            repeat_mode: self.repeat_mode,
+            shuffle: self.shuffle_mode,
+            volume: self.volume,
+            ab_loop: self.get_loop()
+        };
+    }
+    // ------------------- Equalizer Functions -------------------
+    // Changes are picked up by the song that is playing, no need to reload it
+    pub fn set_equalizer(&self, settings: EqualizerSettings) {
+        self.equalizer.set(settings);
+    }
+
+    pub fn get_equalizer(&self) -> EqualizerSettings {
+        return self.equalizer.get();
+    }
+
+    // ------------------- Channel Functions -------------------
+    // Changes are picked up by the song that is playing
+    pub fn set_channels(&self, settings: ChannelSettings) {
+        self.channels.set(settings);
+    }
+
+    pub fn get_channels(&self) -> ChannelSettings {
+        return self.channels.get();
+    }
+    // ------------------- ReplayGain Functions -------------------
+    // Only applies to songs loaded after the change
+    pub fn set_replaygain_mode(&mut self, mode: i64) {
+        self.replaygain_mode = mode;
+    }
+
+    pub fn get_replaygain_mode(&self) -> i64 {
+        return self.replaygain_mode;
+    }
+
+    pub fn set_album_queue(&mut self, is_album: bool) {
+        self.is_album_queue = is_album;
+        self.update_crossfade();
+    }
+
+    // Get the volume multiplier for a song, lowered if needed so the peak doesn't clip
+    pub fn get_replaygain(&self, song: &SongTable) -> f32 {
+        if self.replaygain_mode == 0 {
+            return 1.0;
+        }
+
+        let track = (song.replaygain_track_gain, song.replaygain_track_peak);
+        let album = (song.replaygain_album_gain, song.replaygain_album_peak);
+
+        // Use the other value if the song is missing the one we want
+        let (gain, peak) = if self.replaygain_mode == 2 || self.is_album_queue {
+            if album.0.is_some() { album } else { track }
+        }
+        else {
+            if track.0.is_some() { track } else { album }
+        };
+
+        // No tags, fall back to the analyzed loudness
+        let (gain, peak) = match gain {
+            Some(_) => (gain, peak),
+            None => (song.loudness_lufs.map(|lufs| loudness::REFERENCE_LUFS - lufs), song.loudness_peak)
+        };
+
+        match gain {
+            Some(db) => {
+                let mut factor = 10f64.powf(db / 20.0);
+                if let Some(p) = peak.filter(|p| *p > 0.0) {
+                    factor = factor.min(1.0 / p);
+                }
+                factor as f32
+            },
+            None => 1.0
+        }
+    }
+    // ------------------- Crossfade Functions -------------------
+    pub fn set_crossfade(&mut self, settings: CrossfadeSettings) {
+        self.crossfade_settings = settings;
+        self.update_crossfade();
+    }
+
+    pub fn get_crossfade(&self) -> CrossfadeSettings {
+        return self.crossfade_settings.clone();
+    }
+
+    // Repeating one song loops it instead of fading into the next one
+    // Albums can stay gapless, since their songs often run into each other
+    fn update_crossfade(&self) {
+        let allowed = self.repeat_mode != 2 && !(self.is_album_queue && self.crossfade_settings.album_gapless);
+
+        if allowed {
+            self.crossfade.set_duration(time::Duration::from_secs_f32(self.crossfade_settings.duration.clamp(0.0, 12.0)));
+        }
+        else {
+            self.crossfade.set_duration(time::Duration::ZERO);
+        }
+    }
+
+    // ------------------- Speed Functions -------------------
+    // Changes are picked up by the song that is playing
+    pub fn set_speed(&self, speed: f32, keep_pitch: bool) {
+        self.tempo.set(speed, keep_pitch);
+    }
+
+    pub fn get_speed(&self) -> PlaybackSpeed {
+        return PlaybackSpeed { speed: self.tempo.speed(), keep_pitch: self.tempo.keep_pitch() };
+    }
+
+    // Position in the song's own time, the sink's position is off when the speed isn't 1x
+    pub fn get_position(&self) -> f64 {
+        return self.get_song_pos().as_secs_f64();
+    }
+
+    // Sent with the "playback-progress" event
+    pub fn get_progress(&self) -> PlaybackProgress {
+        let duration = self.queue.get(self.position).map(|song| song.duration * 1000).unwrap_or(0);
+        return PlaybackProgress {
+            index: self.position,
+            position_ms: self.get_song_pos().as_millis() as u64,
+            duration_ms: duration,
+            // The next song is already in the sink for gapless playback
+            next_buffered: self.sink.len() > 1
+        };
+    }
+
+    // ------------------- Visualizer Functions -------------------
+    pub fn set_visualizer(&self, enabled: bool) {
+        self.analyzer.set_enabled(enabled);
+    }
+
+    pub fn get_visualizer(&self) -> bool {
+        return self.analyzer.is_enabled();
+    }
+
+    // ------------------- Radio Functions -------------------
+    // Plays the station instead of the queue, the queue stays where it was
+    // Speed, A-B loop and crossfade are left out since a live stream can't seek
+    pub fn play_radio(&mut self, station: RadioStation, source: RadioSource) {
+        self.clear_loop();
+        self.ramp_down();
+        self.clear_sink();
+        self.crossfade.reset();
+
+        let chain = Equalizer::new(source.decoder, self.equalizer.clone());
+        let chain = Channels::new(chain, self.channels.clone());
+        let chain = Ramp::new(chain, self.ramp.clone());
+        self.sink.append(AnalyzerTap::new(chain, self.analyzer.clone()));
+        self.sink_len += 1;
+
+        self.radio = Some(station);
+        self.radio_titles = Some(source.titles);
+        self.radio_title = None;
+        self.play_song();
+    }
+
+    fn stop_radio(&mut self) {
+        self.radio = None;
+        self.radio_titles = None;
+        self.radio_title = None;
+    }
+
+    // Called by the player thread, picks up the newest title sent by the station
+    pub fn update_radio_title(&mut self) {
+        if let Some(titles) = self.radio_titles.as_ref() {
+            if let Some(title) = titles.try_iter().last() {
+                self.radio_title = Some(title).filter(|t| !t.is_empty());
+            }
+        }
+    }
+
+    pub fn get_radio(&self) -> Option<RadioNowPlaying> {
+        return self.radio.as_ref().map(|station| RadioNowPlaying {
+            station_id: station.id,
+            station_name: station.name.clone(),
+            title: self.radio_title.clone()
+        });
+    }
+
+    // ------------------- Output Device Functions -------------------
+    // Move playback to a new device, the song picks up where it was on the old one
+    // A radio station can't be picked up, it is handed back (with whether it was paused) so the stream can be opened again
+    pub fn switch_output(&mut self, mixer: &Mixer) -> Option<(RadioStation, bool)> {
+        let was_paused = self.sink.is_paused();
+        let volume = self.sink.volume();
+        // More than one song means the next song was already loaded for gapless playback
+        let loaded = self.sink.len();
+        let position = self.tempo.position();
+        let station = self.radio.take();
+
+        self.stop_sink();
+        self.crossfade.reset();
+
+        self.sink = Player::connect_new(mixer);
+        self.sink.pause();
+        self.sink.set_volume(volume);
+
+        if station.is_some() {
+            self.stop_radio();
+            return station.map(|station| (station, was_paused));
+        }
+
+        if loaded > 0 && self.position < self.queue.len() {
+            if self.load_song(self.position).is_ok() {
+                let _ = self.sink.try_seek(position).map_err(|e| log::error!("Switch Output - Error seeking: {:?}", e));
+                if loaded > 1 && self.position + 1 < self.queue.len() {
+                    let _ = self.load_song(self.position + 1);
+                }
+            }
+            if !was_paused {
+                self.play_song();
+            }
+        }
+        None
+    }
+
+    // ------------------- A-B Loop Functions -------------------
+    // None uses the current position
+    pub fn set_loop_a(&mut self, position: Option<time::Duration>) -> Result<(), String> {
+        if self.sink.empty() {
+            return Err("No song is playing".to_string());
+        }
+        let a = position.unwrap_or(self.get_song_pos());
+        self.loop_a = Some(a);
+
+        // Moving A past B starts over
+        match self.loop_b {
+            Some(b) if b > a => self.ab_loop.set(self.playing_id(), a, b),
+            _ => {
+                self.loop_b = None;
+                self.ab_loop.clear();
+            }
+        }
+        Ok(())
+    }
+
+    // Starts the loop, A is the start of the song if it wasn't set
+    pub fn set_loop_b(&mut self, position: Option<time::Duration>) -> Result<(), String> {
+        if self.sink.empty() {
+            return Err("No song is playing".to_string());
+        }
+        let a = self.loop_a.unwrap_or(time::Duration::ZERO);
+        let b = position.unwrap_or(self.get_song_pos());
+        if b <= a {
+            return Err("Loop end has to be after the loop start".to_string());
+        }
+
+        self.loop_a = Some(a);
+        self.loop_b = Some(b);
+        self.ab_loop.set(self.playing_id(), a, b);
+        Ok(())
+    }
+
+    // The loop id of the song at the front of the sink
+    fn playing_id(&self) -> u64 {
+        self.loaded.front().map(|(_, id)| *id).unwrap_or(0)
+    }
+
+    pub fn clear_loop(&mut self) {
+        self.loop_a = None;
+        self.loop_b = None;
+        self.ab_loop.clear();
+    }
+
+    pub fn get_loop(&self) -> AbLoopState {
+        return AbLoopState {
+            a_ms: self.loop_a.map(|a| a.as_millis() as u64),
+            b_ms: self.loop_b.map(|b| b.as_millis() as u64)
+        };
+    }
+
+    // ------------------- Sleep Timer Functions -------------------
+    // Time left in the playing song
+    fn song_left(&self) -> time::Duration {
+        match self.queue.get(self.position) {
+            Some(song) if !self.sink.empty() => time::Duration::from_secs(song.duration).saturating_sub(self.get_song_pos()),
+            _ => time::Duration::ZERO
+        }
+    }
+
+    // Replaces a timer that is already running
+    pub fn start_sleep_timer(&mut self, timer: SleepTimer) {
+        self.sleep_timer = Some(timer);
+        self.update_sleep_timer();
+    }
+
+    pub fn extend_sleep_timer(&mut self, amount: u32) -> Result<(), String> {
+        match self.sleep_timer.as_mut() {
+            Some(timer) => {
+                timer.extend(amount);
+                self.update_sleep_timer();
+                Ok(())
+            },
+            None => Err("There is no sleep timer running".to_string())
+        }
+    }
+
+    pub fn cancel_sleep_timer(&mut self) {
+        self.sleep_timer = None;
+        self.sink.set_volume(self.volume);
+    }
+
+    pub fn get_sleep_timer(&self) -> Option<SleepTimerStatus> {
+        return self.sleep_timer.as_ref().map(|t| t.status(self.song_left()));
+    }
+
+    // Called by the player thread a few times a second, fades the volume and stops playback in minutes mode
+    pub fn update_sleep_timer(&mut self) {
+        let Some(timer) = self.sleep_timer.as_ref() else { return };
+
+        if timer.is_done() {
+            self.save_bookmark();
+            self.sink.pause();
+            self.finish_sleep_timer();
+            return;
+        }
+        self.sink.set_volume(self.volume * timer.fade_factor(self.song_left()));
+    }
+
+    // Playback has stopped, put the volume back for next time
+    fn finish_sleep_timer(&mut self) {
+        self.sleep_timer = None;
+        self.sink.set_volume(self.volume);
+        self.sleep_finished = true;
+    }
+
+    pub fn take_sleep_finished(&mut self) -> bool {
+        std::mem::take(&mut self.sleep_finished)
+    }
+
+    // ------------------- Bookmark Functions -------------------
+    // Settings and bookmarks saved in the database, given at startup
+    pub fn load_bookmarks(&mut self, settings: ResumeSettings, songs: Vec<String>, positions: Vec<(String, u64)>) {
+        self.bookmarks.load(settings, songs, positions);
+    }
+
+    pub fn set_resume_settings(&mut self, settings: ResumeSettings) {
+        self.bookmarks.set_settings(settings);
+    }
+
+    pub fn get_resume_settings(&self) -> ResumeSettings {
+        self.bookmarks.get_settings()
+    }
+
+    pub fn set_song_resume(&mut self, path: String, remember: bool) {
+        self.bookmarks.set_song(path, remember);
+    }
+
+    // Remember where the playing song is, if it is one that should be remembered
+    pub fn save_bookmark(&mut self) {
+        if self.sink.empty() || self.radio.is_some() {
+            return;
+        }
+        let position = self.get_song_pos();
+        if let Some(song) = self.queue.get(self.position) {
+            self.bookmarks.save(song, position);
+        }
+    }
+
+    pub fn clear_bookmark(&mut self, path: &str) {
+        self.bookmarks.remove(path);
+    }
+
+    // Bookmarks saved or removed since the last call
+    pub fn take_bookmark_changes(&mut self) -> Vec<BookmarkChange> {
+        self.bookmarks.take_changes()
+    }
+
+    // ------------------- Session Functions -------------------
+    // The queue tables only have the library songs, so podcast episodes don't count towards the index
+    pub fn get_session(&self) -> PlaybackSession {
+        let index = self.queue.iter().take(self.position).filter(|song| !podcast::is_episode(&song.path)).count();
+        let on_episode = self.queue.get(self.position).map(|song| podcast::is_episode(&song.path)).unwrap_or(false);
+        return PlaybackSession {
+            index,
+            position_ms: if on_episode { 0 } else { self.get_song_pos().as_millis() as u64 },
+            volume: self.volume,
+            repeat_mode: self.repeat_mode,
+            shuffle: self.shuffle_mode
+        };
+    }
+
+    // Rebuild the player from a saved session, paused at the saved spot
+    pub fn restore_session(&mut self, queue: Vec<SongTable>, session: PlaybackSession) -> Result<(), String> {
+        self.clear_queue();
+        self.sink.pause();
+        self.set_volume(session.volume);
+        self.set_repeat_mode(session.repeat_mode);
+        self.set_shuffle(session.shuffle);
+        self.set_album_queue(false);
+
+        if queue.is_empty() {
+            return Ok(());
+        }
+
+        let index = session.index.min(queue.len() - 1);
+        self.set_queue(queue);
+        self.update_current_index(index)?;
+        self.load_song(index)?;
+        self.seek(time::Duration::from_millis(session.position_ms));
+        Ok(())
+    }
+
+    // ------------------- Track End Functions -------------------
+    // The queue position of the song after this one, following the repeat mode
+    fn next_index(&self) -> Option<usize> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        match self.repeat_mode {
+            2 => Some(self.position),
+            1 => Some((self.position + 1) % self.queue.len()),
+            _ => if self.position + 1 < self.queue.len() { Some(self.position + 1) } else { None }
+        }
+    }
+
+    // Only keep the paths of songs that are still in the sink
+    fn trim_loaded(&mut self, len: usize) {
+        while self.loaded.len() > len {
+            self.loaded.pop_front();
+        }
+    }
+
+    // Called by the player thread, moves the queue along when the sink finished a song by itself
+    pub fn check_track_end(&mut self) -> Option<TrackEnd> {
+        let len = self.sink.len();
+        // The sink counts dropped songs a moment after they were taken out, more than expected isn't an end
+        if len >= self.sink_len {
+            return None;
+        }
+
+        self.sink_len = len;
+        self.trim_loaded(len);
+        self.preload_tried = false;
+        self.clear_loop();
+
+        // The station stopped sending, radio doesn't move on to the queue
+        if self.radio.is_some() {
+            self.stop_radio();
+            return Some(TrackEnd::QueueEnded);
+        }
+        if let Some(path) = self.queue.get(self.position).map(|song| song.path.clone()) {
+            self.bookmarks.remove(&path);
+            self.finished.push(path);
+        }
+        let next = self.next_index();
+
+        // The sleep timer stops here, the queue still moves on so pressing play starts the next song
+        let sleep_stop = self.sleep_timer.as_mut().map(|t| t.song_finished()).unwrap_or(false);
+        if sleep_stop {
+            self.sink.pause();
+        }
+
+        // The pre-loaded song is already playing, find where it is in the queue
+        if let Some((path, _)) = self.loaded.front() {
+            let expected = next.filter(|i| self.queue[*i].path == *path);
+            // The queue was changed (ex. shuffled) after the song was pre-loaded
+            let found = expected.or_else(|| self.queue.iter().position(|song| song.path == *path));
+            if let Some(index) = found.or(next) {
+                self.position = index;
+            }
+        }
+        // Nothing was pre-loaded, load the next song now, skipping any that can't be played
+        else {
+            match next.map(|index| self.load_playable(index, false)) {
+                Some(Ok(index)) => {
+                    self.position = index;
+                    if !sleep_stop {
+                        self.play_song();
+                    }
+                },
+                _ => {
+                    self.position = 0;
+                    self.sink.pause();
+                    self.crossfade.reset();
+                    return Some(TrackEnd::QueueEnded);
+                }
+            }
+        }
+
+        if sleep_stop {
+            self.finish_sleep_timer();
+        }
+
+        self.preload_next(true);
+        self.get_current_song().ok().map(TrackEnd::NextSong)
+    }
+
+    // Put the next song in the sink ahead of time so it starts without a gap
+    // Only done near the end of the song, unless `now` is set right after a song change
+    pub fn preload_next(&mut self, now: bool) {
+        if self.sink.len() != 1 || self.preload_tried || self.radio.is_some() {
+            return;
+        }
+        // Playback stops at the end of this song, nothing to pre-load
+        if self.sleep_timer.as_ref().map(|t| t.stops_after_this_song()).unwrap_or(false) {
+            return;
+        }
+
+        if !now {
+            let duration = match self.queue.get(self.position) {
+                Some(song) => time::Duration::from_secs(song.duration),
+                None => return
+            };
+            let lead = PRELOAD_AHEAD + time::Duration::from_secs_f32(self.crossfade_settings.duration.clamp(0.0, 12.0));
+            if duration.saturating_sub(self.get_song_pos()) > lead {
+                return;
+            }
+        }
+
+        if let Some(index) = self.next_index() {
+            // A song with a bookmark has to start at its own spot, it is loaded once this one ends instead
+            if self.bookmarks.position(&self.queue[index]).is_some() {
+                return;
+            }
+            self.preload_tried = true;
+            if let Err(e) = self.load_song(index) {
+                log::error!("Pre-load Next Song - {:?}", e);
+            }
+        }
+    }
+
     // ------------------- Media Loading / Setup Functions -------------------
     
     pub fn load_song(&mut self, pos: usize) -> Result<(), String> {
+        // Anything from the queue replaces the radio
+        self.stop_radio();
         // Get the path of the song from the queue
-        if self.queue.len() > 0 {
+        if pos < self.queue.len() {
             let path = &self.queue[pos].path;
-            let file = File::open(&path);
+            // Cue sheet tracks are read from the file they are a part of
+            let file_path = self.queue[pos].source_path.as_ref().unwrap_or(path);
+            let (start, end) = cue::song_span(&self.queue[pos]);
+            let file = File::open(file_path);
 
             // No error reading the file path
             if file.is_ok() {
                 let good_file = file.unwrap();
-                // Makes it a little faster if we are guessing with only mp3 files
-                // Length is needed for backwards seeking
-                let len = good_file.metadata().unwrap().len();
-                match Decoder::builder().with_data(BufReader::new(good_file))
-                    .with_hint("mp3")
-                    .with_decoder::<symphonia_adapter_libopus::OpusDecoder>()
-                    .with_byte_len(len)
-                    .with_seekable(true)
-                    .with_gapless(true)
-                    .build()
+                let source = formats::decoder(good_file, file_path, true)
+                    .and_then(|source| cue::Span::new(source, start, end).map_err(|e| format!("Error seeking to the cue track: {}", e)));
+                match source
                 {
                     Ok(source) => {
+                        // Nothing is playing, so the position belongs to the song being loaded
+                        let starts_now = self.sink.empty();
+                        if starts_now {
+                            self.tempo.reset_position();
+                        }
                         // On Success, load song into the sink
                         // log::info!("Load Song - Song Successfully loaded - {:?} -- {:?}", &self.queue[pos].name, &self.queue[pos].album);
-                        self.sink.append(source);
+                        let gain = self.get_replaygain(&self.queue[pos]);
+                        let chain = Equalizer::new(source.amplify(gain), self.equalizer.clone());
+                        let chain = Channels::new(chain, self.channels.clone());
+                        self.crossfade.track_queued();
+                        let chain = Crossfade::new(Box::new(chain), self.crossfade.clone());
+                        let chain = AbLoop::new(Tempo::new(chain, self.tempo.clone()), self.ab_loop.clone());
+                        self.loaded.push_back((self.queue[pos].path.clone(), chain.id()));
+                        let chain = Ramp::new(chain, self.ramp.clone());
+                        self.sink.append(AnalyzerTap::new(chain, self.analyzer.clone()));
+                        self.sink_len += 1;
+
+                        // Pick up where the song was left off
+                        if let Some(position) = self.bookmarks.position(&self.queue[pos]).filter(|_| starts_now) {
+                            let _ = self.sink.try_seek(position).map_err(|e| log::error!("Load Song - Error resuming {:?}", e));
+                        }
                         return Ok(());
                     },
                     Err(e) => {
-                        log::error!("Load Song - Error decoding Audio File {:?} - {:?}", &e, &path);
-                        eprintln!("Error decoding audio file: {:?} - {:?}", &e, &path);
-                        return Err("Error decoding audio file".to_string());
+                        log::error!("Load Song - {} - {:?}", &e, &path);
+                        self.load_errors.push((path.clone(), e.clone()));
+                        return Err(e);
                     }
                 };
             }
             // If there is an error reading in the file (ex. File has been moved an doesn't exist in that location)
             else {
-                log::error!("Load Song - {:?}", file.unwrap_err());
+                let e = file.unwrap_err();
+                log::error!("Load Song - {:?}", e);
+                self.load_errors.push((path.clone(), format!("Song does not exist: {}", e)));
                 return Err("Song does not exist".to_string())
             }
         }
@@ -306,8 +1194,55 @@ impl MusicPlayer {
             log::error!("Load Song - Queue is set to 0. Cannot load a song");
             return Err("Load Song - Queue is set to 0. Cannot load a song".to_string());
         }
-        
-        Ok(())
+        // The index can come from the frontend
+        else {
+            log::error!("Load Song - Position {} is past the end of the queue ({} songs)", pos, self.queue.len());
+            return Err("Load Song - Position is past the end of the queue".to_string());
+        }
+    }
+
+    // ------------------- Sink Functions -------------------
+    // Songs taken out of the sink on purpose are counted here, so check_track_end only sees the ones that ended by themselves
+
+    // Drop every song and pause the sink
+    fn clear_sink(&mut self) {
+        self.sink.clear();
+        self.sink_emptied();
+    }
+
+    // Drop every song in the sink
+    fn stop_sink(&mut self) {
+        self.sink.stop();
+        self.sink_emptied();
+    }
+
+    fn sink_emptied(&mut self) {
+        self.sink_len = 0;
+        self.loaded.clear();
+        self.preload_tried = false;
+    }
+
+    // Move on to the pre-loaded song
+    fn skip_sink(&mut self) {
+        let _ = self.sink.skip_one();
+        self.sink_len = self.sink_len.saturating_sub(1);
+        self.loaded.pop_front();
+        self.preload_tried = false;
+    }
+
+    // Songs that ended while a command was running, told to the frontend by the player thread
+    pub fn take_track_ends(&mut self) -> Vec<TrackEnd> {
+        std::mem::take(&mut self.track_ends)
+    }
+
+    // Songs that failed to load since the last call, saved to the database by the player thread
+    pub fn take_load_errors(&mut self) -> Vec<(String, String)> {
+        std::mem::take(&mut self.load_errors)
+    }
+
+    // Songs that played to the end since the last call
+    pub fn take_finished(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.finished)
     }
 
     // Just for debugging new gapless features
@@ -519,3 +1454,210 @@ impl MusicPlayer {
 //     }
 // }
 
+
+#[cfg(test)]
+mod tests {
+    use std::{ f32::consts::PI, fs, path::Path, thread, time::{ Duration, Instant } };
+    use rodio::Player;
+
+    use super::{ MusicPlayer, TrackEnd };
+    use crate::{ helper, output::{ NullBackend, OutputBackend, WavBackend }, test_helpers::test_dir, types::SongTable };
+
+    // A mono 16-bit sine wave, long enough to tell songs apart
+    fn test_song(dir: &Path, name: &str, millis: u32) -> SongTable {
+        let rate: u32 = 44100;
+        let samples = rate * millis / 1000;
+        let mut data = vec![];
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&(36 + samples * 2).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&rate.to_le_bytes());
+        data.extend_from_slice(&(rate * 2).to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&16u16.to_le_bytes());
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&(samples * 2).to_le_bytes());
+        for i in 0..samples {
+            let sample = ((i as f32 * 440.0 * 2.0 * PI / rate as f32).sin() * 8000.0) as i16;
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        let path = dir.join(format!("{}.wav", name));
+        fs::write(&path, data).unwrap();
+        SongTable {
+            name: name.to_string(),
+            path: path.to_string_lossy().to_string(),
+            duration: (millis / 1000) as u64,
+            ..SongTable::default()
+        }
+    }
+
+    fn test_songs(dir: &Path, count: usize, millis: u32) -> Vec<SongTable> {
+        (0..count).map(|i| test_song(dir, &format!("song{}", i), millis)).collect()
+    }
+
+    fn test_player(backend: &dyn OutputBackend, songs: Vec<SongTable>, repeat_mode: i64) -> MusicPlayer {
+        let mut player = MusicPlayer::new(Player::connect_new(backend.mixer())).unwrap();
+        player.set_repeat_mode(repeat_mode);
+        player.set_queue(songs);
+        player.jump_to_song(0);
+        player
+    }
+
+    // Keeps the player thread's loop going until a song ends by itself
+    fn wait_for_track_end(player: &mut MusicPlayer, timeout: Duration) -> Option<TrackEnd> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(end) = player.check_track_end() {
+                return Some(end);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        None
+    }
+
+    #[test]
+    fn next_and_previous_follow_the_queue() {
+        let dir = test_dir("next-previous");
+        let backend = NullBackend::new().unwrap();
+        let mut player = test_player(&backend, test_songs(&dir, 3, 2000), 0);
+        assert_eq!(player.get_current_position(), 0);
+        assert!(!player.check_is_paused());
+
+        player.next_song();
+        assert_eq!(player.get_current_position(), 1);
+        player.next_song();
+        assert_eq!(player.get_current_position(), 2);
+
+        player.previous_song();
+        assert_eq!(player.get_current_position(), 1);
+        player.previous_song();
+        assert_eq!(player.get_current_position(), 0);
+        // Going back from the first song wraps to the last one
+        player.previous_song();
+        assert_eq!(player.get_current_position(), 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn repeat_off_stops_after_the_last_song() {
+        let dir = test_dir("repeat-off");
+        let backend = NullBackend::new().unwrap();
+        let mut player = test_player(&backend, test_songs(&dir, 2, 2000), 0);
+
+        player.next_song();
+        assert_eq!(player.get_current_position(), 1);
+        player.next_song();
+        assert_eq!(player.get_current_position(), 0);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn repeat_queue_wraps_to_the_first_song() {
+        let dir = test_dir("repeat-queue");
+        let backend = NullBackend::new().unwrap();
+        let mut player = test_player(&backend, test_songs(&dir, 2, 2000), 1);
+
+        player.next_song();
+        assert_eq!(player.get_current_position(), 1);
+        player.next_song();
+        assert_eq!(player.get_current_position(), 0);
+        assert!(!player.check_is_paused());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn repeat_one_stays_on_the_song() {
+        let dir = test_dir("repeat-one");
+        let backend = NullBackend::new().unwrap();
+        let mut player = test_player(&backend, test_songs(&dir, 2, 2000), 2);
+
+        player.next_song();
+        assert_eq!(player.get_current_position(), 0);
+        assert!(!player.check_is_paused());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn shuffled_queue_is_played_in_its_new_order() {
+        let dir = test_dir("shuffle");
+        let songs = test_songs(&dir, 5, 2000);
+        let mut shuffled = songs.clone();
+        helper::shuffle(&mut shuffled);
+
+        let mut paths: Vec<&String> = shuffled.iter().map(|song| &song.path).collect();
+        paths.sort();
+        assert_eq!(paths, songs.iter().map(|song| &song.path).collect::<Vec<&String>>());
+
+        let backend = NullBackend::new().unwrap();
+        let mut player = test_player(&backend, shuffled.clone(), 0);
+        player.set_shuffle(true);
+        assert!(player.get_queue_snapshot().shuffled);
+
+        player.next_song();
+        assert_eq!(player.get_current_song().unwrap().path, shuffled[1].path);
+
+        // A single song or an empty queue has nothing to shuffle
+        helper::shuffle(&mut vec![]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn empty_queue_does_not_panic() {
+        let backend = NullBackend::new().unwrap();
+        let mut player = MusicPlayer::new(Player::connect_new(backend.mixer())).unwrap();
+        player.set_repeat_mode(0);
+
+        player.next_song();
+        player.previous_song();
+        assert!(!player.remove_from_queue(0));
+        player.remove_from_queue_by_value("missing".to_string());
+        assert_eq!(player.get_current_position(), 0);
+    }
+
+    #[test]
+    fn positions_past_the_queue_are_errors() {
+        let backend = NullBackend::new().unwrap();
+        let mut player = MusicPlayer::new(Player::connect_new(backend.mixer())).unwrap();
+        player.set_queue(vec![SongTable { path: "/music/missing.flac".to_string(), ..SongTable::default() }]);
+
+        assert!(player.load_song(1).is_err());
+        assert!(player.update_current_index(1).is_err());
+        assert!(player.get_current_song().is_ok());
+    }
+
+    #[test]
+    fn preloaded_song_takes_over_without_a_gap() {
+        let dir = test_dir("gapless");
+        let recording = dir.join("out.wav");
+        let backend = WavBackend::new(recording.clone()).unwrap();
+        let mut player = test_player(&backend, test_songs(&dir, 2, 300), 0);
+
+        player.preload_next(true);
+        assert_eq!(player.get_sink_length(), 2);
+
+        match wait_for_track_end(&mut player, Duration::from_secs(3)) {
+            Some(TrackEnd::NextSong(song)) => assert!(song.path.ends_with("song1.wav")),
+            _ => panic!("The pre-loaded song didn't take over")
+        }
+        assert_eq!(player.get_current_position(), 1);
+
+        match wait_for_track_end(&mut player, Duration::from_secs(3)) {
+            Some(TrackEnd::QueueEnded) => {},
+            _ => panic!("The queue didn't end after the last song")
+        }
+        assert_eq!(player.get_current_position(), 0);
+
+        drop(player);
+        drop(backend);
+        assert!(fs::metadata(&recording).unwrap().len() > 44);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    fs::File, io::{ BufWriter, Seek, SeekFrom, Write }, path::{ Path, PathBuf }, thread, time::{ Duration, Instant },
+    sync::{ Arc, atomic::{ AtomicBool, Ordering } }
+};
+use rodio::{ ChannelCount, DeviceSinkBuilder, MixerDeviceSink, SampleRate, cpal::{ self, traits::{ DeviceTrait, HostTrait } }, mixer::{ self, Mixer } };
+use tauri_plugin_log::log;
+
+use crate::types::OutputDevice;
+
+// Output device handling
+// The backend is kept on its own thread, the audio stream can't always be moved between threads
+// Switching devices opens the new one first, so a failed switch keeps playing on the old device
+// Without a sound card (ex. headless Linux / CI) the null backend plays into nothing, and the WAV backend records to a file
+// ex. ROBINTUK_OUTPUT=null or ROBINTUK_OUTPUT=wav:/tmp/out.wav
+
+// Layout used by the null and WAV backends
+const PULL_CHANNELS: u16 = 2;
+const PULL_SAMPLE_RATE: u32 = 44100;
+// How much sound the null and WAV backends take from the mixer at a time
+const PULL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Anything that takes sound out of a mixer, dropping it stops the sound
+pub trait OutputBackend {
+    fn mixer(&self) -> &Mixer;
+}
+
+// Which backend to open
+pub enum Backend {
+    // None opens the system default device
+    Device(Option<String>),
+    Null,
+    Wav(PathBuf)
+}
+
+impl Backend {
+    // Picked with the ROBINTUK_OUTPUT environment variable, otherwise the saved device is used
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("ROBINTUK_OUTPUT").ok()?;
+        match value.split_once(':') {
+            Some(("wav", path)) => Some(Backend::Wav(PathBuf::from(path))),
+            _ if value == "null" => Some(Backend::Null),
+            _ => {
+                log::error!("Output Backend - Unknown backend: {}", value);
+                None
+            }
+        }
+    }
+
+    fn open(self) -> Result<Box<dyn OutputBackend>, String> {
+        match self {
+            Backend::Device(name) => Ok(Box::new(open_device(name.as_deref())?)),
+            Backend::Null => Ok(Box::new(NullBackend::new()?)),
+            Backend::Wav(path) => Ok(Box::new(WavBackend::new(path)?))
+        }
+    }
+}
+
+enum OutputCommand {
+    Open(Backend, flume::Sender<Result<Mixer, String>>)
+}
+
+pub struct OutputHandle {
+    tx: flume::Sender<OutputCommand>
+}
+
+impl OutputHandle {
+    pub fn new() -> Self {
+        let (tx, rx) = flume::unbounded::<OutputCommand>();
+
+        let _ = thread::Builder::new().name("audio-output".to_string()).spawn(move || {
+            // Dropping the backend stops the stream, so it lives here until it is replaced
+            let mut _backend: Option<Box<dyn OutputBackend>> = None;
+
+            while let Ok(command) = rx.recv() {
+                match command {
+                    OutputCommand::Open(backend, reply) => {
+                        match backend.open() {
+                            Ok(backend) => {
+                                let mixer = backend.mixer().clone();
+                                _backend = Some(backend);
+                                let _ = reply.send(Ok(mixer));
+                            },
+                            Err(e) => {
+                                let _ = reply.send(Err(e));
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    // Open a device and return its mixer, the old device is closed once the new one is open
+    pub fn open(&self, name: Option<String>) -> Result<Mixer, String> {
+        self.open_backend(Backend::Device(name))
+    }
+
+    pub fn open_backend(&self, backend: Backend) -> Result<Mixer, String> {
+        let (reply_tx, reply_rx) = flume::bounded(1);
+        self.tx.send(OutputCommand::Open(backend, reply_tx)).map_err(|e| e.to_string())?;
+        reply_rx.recv().map_err(|e| e.to_string())?
+    }
+}
+
+impl OutputBackend for MixerDeviceSink {
+    fn mixer(&self) -> &Mixer {
+        MixerDeviceSink::mixer(self)
+    }
+}
+
+
+// ------------------- Null / WAV Backends -------------------
+// Both pull from their own mixer on a thread at the same pace a sound card would
+
+// Pulls from the mixer until dropped, every chunk is handed to `write`
+struct PulledOutput {
+    mixer: Mixer,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl PulledOutput {
+    fn new(name: &str, mut write: impl FnMut(&[f32]) + Send + 'static) -> Result<Self, String> {
+        let channels = ChannelCount::new(PULL_CHANNELS).ok_or("Invalid channel count")?;
+        let sample_rate = SampleRate::new(PULL_SAMPLE_RATE).ok_or("Invalid sample rate")?;
+        let (mixer, mut source) = mixer::mixer(channels, sample_rate);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let chunk_len = (PULL_SAMPLE_RATE as u128 * PULL_INTERVAL.as_millis() / 1000) as usize * PULL_CHANNELS as usize;
+
+        let thread = thread::Builder::new().name(name.to_string()).spawn(move || {
+            let mut chunk = vec![0.0; chunk_len];
+            let mut next_pull = Instant::now();
+
+            while !stop_thread.load(Ordering::Relaxed) {
+                // An empty mixer plays silence, it never ends
+                for sample in chunk.iter_mut() {
+                    *sample = source.next().unwrap_or(0.0);
+                }
+                write(&chunk);
+
+                next_pull += PULL_INTERVAL;
+                thread::sleep(next_pull.saturating_duration_since(Instant::now()));
+            }
+        })
+        .map_err(|e| format!("Error starting the {} thread: {:?}", name, e))?;
+
+        Ok(Self { mixer, stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for PulledOutput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Plays into nothing, for machines without a sound card and for testing the player
+// ex. MusicPlayer::new(Player::connect_new(NullBackend::new()?.mixer()))
+pub struct NullBackend {
+    output: PulledOutput
+}
+
+impl NullBackend {
+    pub fn new() -> Result<Self, String> {
+        let output = PulledOutput::new("null-output", |_| {})?;
+        Ok(Self { output })
+    }
+}
+
+impl OutputBackend for NullBackend {
+    fn mixer(&self) -> &Mixer {
+        &self.output.mixer
+    }
+}
+
+// Records everything that is played to a 32-bit float WAV file, the file is finished when the backend is dropped
+pub struct WavBackend {
+    output: PulledOutput
+}
+
+impl WavBackend {
+    pub fn new(path: PathBuf) -> Result<Self, String> {
+        let mut writer = WavWriter::create(&path)?;
+        let output = PulledOutput::new("wav-output", move |chunk| {
+            if let Err(e) = writer.write(chunk) {
+                log::error!("WAV Output - Error writing: {:?}", e);
+            }
+        })?;
+        Ok(Self { output })
+    }
+}
+
+impl OutputBackend for WavBackend {
+    fn mixer(&self) -> &Mixer {
+        &self.output.mixer
+    }
+}
+
+struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32
+}
+
+impl WavWriter {
+    fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Error creating WAV file: {:?}", e))?;
+        let mut writer = Self { file: BufWriter::new(file), data_len: 0 };
+        writer.write_header().map_err(|e| format!("Error writing WAV file: {:?}", e))?;
+        Ok(writer)
+    }
+
+    // The sizes are filled in again once recording stops
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let block_align = PULL_CHANNELS * 4;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(36 + self.data_len).to_le_bytes())?;
+        f.write_all(b"WAVEfmt ")?;
+        f.write_all(&16u32.to_le_bytes())?;
+        // 3 - IEEE float
+        f.write_all(&3u16.to_le_bytes())?;
+        f.write_all(&PULL_CHANNELS.to_le_bytes())?;
+        f.write_all(&PULL_SAMPLE_RATE.to_le_bytes())?;
+        f.write_all(&(PULL_SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        f.write_all(&block_align.to_le_bytes())?;
+        f.write_all(&32u16.to_le_bytes())?;
+        f.write_all(b"data")?;
+        f.write_all(&self.data_len.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = self.data_len.saturating_add(samples.len() as u32 * 4);
+        Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let finished = self.file.seek(SeekFrom::Start(0))
+            .and_then(|_| self.write_header())
+            .and_then(|_| self.file.flush());
+        if let Err(e) = finished {
+            log::error!("WAV Output - Error finishing the file: {:?}", e);
+        }
+    }
+}
+
+// List the output devices that can be picked in the settings
+pub fn list_output_devices() -> Vec<OutputDevice> {
+    let host = cpal::default_host();
+    let default_name = host.default_output_device().and_then(|d| d.name().ok());
+
+    match host.output_devices() {
+        Ok(devices) => {
+            devices.filter_map(|d| d.name().ok())
+                .map(|name| OutputDevice { is_default: Some(&name) == default_name.as_ref(), name })
+                .collect()
+        },
+        Err(e) => {
+            log::error!("Output Devices - Error listing devices: {:?}", e);
+            vec![]
+        }
+    }
+}
+
+fn open_device(name: Option<&str>) -> Result<MixerDeviceSink, String> {
+    let host = cpal::default_host();
+
+    let device = match name {
+        Some(name) => {
+            host.output_devices()
+                .map_err(|e| e.to_string())?
+                .find(|d| d.name().map(|n| n == name).unwrap_or(false))
+                .ok_or_else(|| format!("Output device not found: {}", name))?
+        },
+        None => host.default_output_device().ok_or_else(|| "No default output device".to_string())?
+    };
+
+    let mut device = DeviceSinkBuilder::from_device(device)
+        .and_then(|b| {
+            b
+            .with_buffer_size(cpal::BufferSize::Fixed(1024))
+            .open_stream()
+        })
+        .or_else(|e| {
+            // The default device can still be opened with its own settings
+            if name.is_none() { DeviceSinkBuilder::open_default_sink() } else { Err(e) }
+        })
+        .map_err(|e| {
+            log::error!("Device Sink Error: {:?}", e);
+            format!("Error opening output device: {:?}", e)
+        })?;
+
+    device.log_on_drop(false);
+    Ok(device)
+}
@@ -0,0 +1,516 @@
+use std::{ fs::{ self, File }, io::Write, path::{ Path, PathBuf }, time::{ Duration, Instant } };
+use chrono::{ DateTime, Utc };
+use tauri::{ AppHandle, Emitter };
+
+use crate::{ formats, types::{ PodcastDownloadProgress, PodcastEpisode, SongTable } };
+
+// Podcasts, RSS and Atom feeds with an audio file attached to each episode
+// Episodes are downloaded into the podcast folder (one folder per feed) and then played like any other song
+// Feeds are read with a small parser that only looks for the elements used here, there is no full XML support
+
+// How often "podcast-download-progress" is sent while an episode downloads
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+// Used when the episode url doesn't end in a known audio extension
+const DEFAULT_EXTENSION: &str = "mp3";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Feeds are small, a whole feed taking longer than this is given up on
+const FEED_TIMEOUT: Duration = Duration::from_secs(30);
+// Episodes can take a long time on a slow connection, only a download that stops sending is given up on
+const DOWNLOAD_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn podcast_dir() -> String {
+    dirs::home_dir().unwrap().to_str().unwrap().to_string() + "/.config/robintuk_player/podcasts"
+}
+
+pub fn feed_dir(feed_id: i64) -> PathBuf {
+    PathBuf::from(podcast_dir()).join(feed_id.to_string())
+}
+
+// Episodes aren't in the songs table, so they are kept out of the queue tables, the history and the playback errors
+pub fn is_episode(path: &str) -> bool {
+    Path::new(path).starts_with(podcast_dir())
+}
+
+// What a feed has in it right now, saved to the database by the subscribe and refresh commands
+pub struct ParsedFeed {
+    pub title: String,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub episodes: Vec<ParsedEpisode>
+}
+
+pub struct ParsedEpisode {
+    // Stays the same between refreshes, used to tell which episodes are new
+    pub guid: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub audio_url: String,
+    // RFC 3339, so the episodes sort by date
+    pub published: Option<String>,
+    // Seconds
+    pub duration: Option<i64>
+}
+
+pub async fn fetch_feed(url: &str) -> Result<ParsedFeed, String> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(FEED_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Error fetching the feed: {}", e))?;
+    let body = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("Error fetching the feed: {}", e))?
+        .text()
+        .await
+        .map_err(|e| format!("Error reading the feed: {}", e))?;
+
+    parse_feed(&body)
+}
+
+// The episode as a song for the queue, None until it is downloaded
+pub fn episode_song(episode: &PodcastEpisode) -> Option<SongTable> {
+    let path = episode.file_path.clone()?;
+    Some(SongTable {
+        name: episode.title.clone(),
+        path,
+        release: episode.published.as_deref().map(|d| d.chars().take(10).collect()).unwrap_or_default(),
+        album: episode.feed_title.clone(),
+        artist: episode.feed_title.clone(),
+        album_artist: episode.feed_title.clone(),
+        genre: "Podcast".to_string(),
+        duration: episode.duration.unwrap_or(0).max(0) as u64,
+        ..SongTable::default()
+    })
+}
+
+
+// ------------------- Downloads -------------------
+
+// <feed folder>/<episode id>.<extension from the url>
+pub fn episode_path(feed_id: i64, episode_id: i64, audio_url: &str) -> PathBuf {
+    let url_path = audio_url.split(['?', '#']).next().unwrap_or(audio_url);
+    let extension = formats::find_format(url_path).map(|f| f.extension).unwrap_or(DEFAULT_EXTENSION);
+    feed_dir(feed_id).join(format!("{}.{}", episode_id, extension))
+}
+
+// Downloads to a .part file first, so a download that stops halfway is never played
+pub async fn download_episode(app: &AppHandle, episode_id: i64, url: &str, dest: &Path) -> Result<(), String> {
+    download(url, dest, |downloaded, total| {
+        let _ = app.emit("podcast-download-progress", PodcastDownloadProgress { episode_id, downloaded, total });
+    }).await
+}
+
+// `progress` gets (downloaded, total) every PROGRESS_INTERVAL, and once more when the file is saved
+async fn download(url: &str, dest: &Path, mut progress: impl FnMut(u64, Option<u64>)) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(DOWNLOAD_STALL_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Error downloading the episode: {}", e))?;
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("Error downloading the episode: {}", e))?;
+
+    let total = response.content_length();
+    let partial = dest.with_extension("part");
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Error creating the podcast folder: {:?}", e))?;
+    }
+    let mut file = File::create(&partial).map_err(|e| format!("Error creating the episode file: {:?}", e))?;
+
+    let mut downloaded: u64 = 0;
+    let mut last_sent = Instant::now();
+    let result: Result<(), String> = loop {
+        match response.chunk().await {
+            Ok(Some(bytes)) => {
+                if let Err(e) = file.write_all(&bytes) {
+                    break Err(format!("Error writing the episode file: {:?}", e));
+                }
+                downloaded += bytes.len() as u64;
+                if last_sent.elapsed() >= PROGRESS_INTERVAL {
+                    progress(downloaded, total);
+                    last_sent = Instant::now();
+                }
+            },
+            Ok(None) => break file.flush().map_err(|e| format!("Error writing the episode file: {:?}", e)),
+            Err(e) => break Err(format!("Error downloading the episode: {}", e))
+        }
+    };
+    drop(file);
+
+    if let Err(e) = result.and_then(|_| fs::rename(&partial, dest).map_err(|e| format!("Error saving the episode file: {:?}", e))) {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    progress(downloaded, Some(downloaded));
+    Ok(())
+}
+
+
+// ------------------- Feed Parsing -------------------
+// RSS 2.0: <channel> with <item>s, the audio is in <enclosure url="..."/>
+// Atom: <feed> with <entry>s, the audio is in <link rel="enclosure" href="..."/>
+
+pub fn parse_feed(xml: &str) -> Result<ParsedFeed, String> {
+    let is_atom = find_element(xml, "feed").is_some() && find_element(xml, "channel").is_none();
+    let item_name = if is_atom { "entry" } else { "item" };
+
+    // Everything before the first episode belongs to the feed itself
+    let header = &xml[..find_start(xml, item_name).unwrap_or(xml.len())];
+    let title = element_text(header, "title").ok_or("This is not an RSS or Atom feed")?;
+
+    let description = element_text(header, if is_atom { "subtitle" } else { "description" })
+        .or_else(|| element_text(header, "itunes:summary"));
+    let image_url = find_element(header, "itunes:image").and_then(|e| attribute(e.attrs, "href"))
+        .or_else(|| find_element(header, "image").and_then(|e| element_text(e.inner, "url")))
+        .or_else(|| element_text(header, "logo"))
+        .or_else(|| element_text(header, "icon"));
+
+    let episodes = elements(xml, item_name).into_iter()
+        .filter_map(|item| if is_atom { parse_entry(item.inner) } else { parse_item(item.inner) })
+        .collect();
+
+    Ok(ParsedFeed { title, description, image_url, episodes })
+}
+
+fn parse_item(item: &str) -> Option<ParsedEpisode> {
+    let audio_url = find_element(item, "enclosure").and_then(|e| attribute(e.attrs, "url"))?;
+    Some(ParsedEpisode {
+        guid: element_text(item, "guid").unwrap_or_else(|| audio_url.clone()),
+        title: element_text(item, "title").unwrap_or_else(|| audio_url.clone()),
+        description: element_text(item, "description").or_else(|| element_text(item, "itunes:summary")),
+        published: element_text(item, "pubDate").and_then(|d| parse_date(&d)),
+        duration: element_text(item, "itunes:duration").and_then(|d| parse_duration(&d)),
+        audio_url
+    })
+}
+
+fn parse_entry(entry: &str) -> Option<ParsedEpisode> {
+    let audio_url = elements(entry, "link").into_iter()
+        .find(|link| attribute(link.attrs, "rel").as_deref() == Some("enclosure"))
+        .and_then(|link| attribute(link.attrs, "href"))?;
+    Some(ParsedEpisode {
+        guid: element_text(entry, "id").unwrap_or_else(|| audio_url.clone()),
+        title: element_text(entry, "title").unwrap_or_else(|| audio_url.clone()),
+        description: element_text(entry, "summary").or_else(|| element_text(entry, "content")),
+        published: element_text(entry, "published").or_else(|| element_text(entry, "updated")).and_then(|d| parse_date(&d)),
+        duration: element_text(entry, "itunes:duration").and_then(|d| parse_duration(&d)),
+        audio_url
+    })
+}
+
+// pubDate is RFC 2822, Atom dates are RFC 3339
+fn parse_date(text: &str) -> Option<String> {
+    DateTime::parse_from_rfc2822(text.trim())
+        .or_else(|_| DateTime::parse_from_rfc3339(text.trim()))
+        .ok()
+        .map(|d| d.with_timezone(&Utc).to_rfc3339())
+}
+
+// Seconds, MM:SS or HH:MM:SS
+fn parse_duration(text: &str) -> Option<i64> {
+    let parts: Vec<&str> = text.trim().split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    parts.iter().try_fold(0i64, |total, part| {
+        let whole = part.trim().split('.').next()?;
+        Some(total * 60 + whole.parse::<i64>().ok()?)
+    })
+}
+
+
+// ------------------- XML Helpers -------------------
+
+struct Element<'a> {
+    attrs: &'a str,
+    // Empty for <tag/>
+    inner: &'a str
+}
+
+// Where the next <name ...> starts, not matching longer names (ex. <title> for "tit")
+fn find_start(xml: &str, name: &str) -> Option<usize> {
+    let open = format!("<{}", name);
+    let mut from = 0;
+    while let Some(found) = xml[from..].find(&open) {
+        let start = from + found;
+        match xml[start + open.len()..].chars().next() {
+            Some(c) if c == '>' || c == '/' || c.is_whitespace() => return Some(start),
+            _ => from = start + open.len()
+        }
+    }
+    None
+}
+
+// Where </name> is, skipping over CDATA so HTML in descriptions can't end the element early
+fn find_close(xml: &str, name: &str) -> Option<usize> {
+    let close = format!("</{}>", name);
+    let mut from = 0;
+    loop {
+        let rest = &xml[from..];
+        let close_at = rest.find(&close)?;
+        match rest.find("<![CDATA[") {
+            Some(cdata) if cdata < close_at => {
+                from += cdata + rest[cdata..].find("]]>")? + 3;
+            },
+            _ => return Some(from + close_at)
+        }
+    }
+}
+
+// Every <name> element in order, (attributes, inner text) for each
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<Element<'a>> {
+    let mut found = vec![];
+    let mut rest = xml;
+
+    while let Some(start) = find_start(rest, name) {
+        let tag = &rest[start + name.len() + 1..];
+        let Some(tag_end) = tag.find('>') else { break };
+
+        if tag[..tag_end].ends_with('/') {
+            found.push(Element { attrs: &tag[..tag_end - 1], inner: "" });
+            rest = &tag[tag_end + 1..];
+        }
+        else {
+            let body = &tag[tag_end + 1..];
+            let Some(close) = find_close(body, name) else { break };
+            found.push(Element { attrs: &tag[..tag_end], inner: &body[..close] });
+            rest = &body[close + name.len() + 3..];
+        }
+    }
+    found
+}
+
+fn find_element<'a>(xml: &'a str, name: &str) -> Option<Element<'a>> {
+    elements(xml, name).into_iter().next()
+}
+
+// The text of the first <name> element, None if it is missing or empty
+fn element_text(xml: &str, name: &str) -> Option<String> {
+    let element = find_element(xml, name)?;
+    Some(text(element.inner)).filter(|t| !t.is_empty())
+}
+
+fn attribute(attrs: &str, name: &str) -> Option<String> {
+    let mut rest = attrs;
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let end = value[1..].find(quote)?;
+
+        if key == name {
+            return Some(decode_entities(&value[1..end + 1]));
+        }
+        rest = &value[end + 2..];
+    }
+    None
+}
+
+// CDATA is kept as is, everything else has its entities decoded
+fn text(inner: &str) -> String {
+    let mut out = String::new();
+    let mut rest = inner;
+
+    while let Some(start) = rest.find("<![CDATA[") {
+        out.push_str(&decode_entities(&rest[..start]));
+        let body = &rest[start + 9..];
+        let end = body.find("]]>").unwrap_or(body.len());
+        out.push_str(&body[..end]);
+        rest = &body[(end + 3).min(body.len())..];
+    }
+    out.push_str(&decode_entities(rest));
+    out.trim().to_string()
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let decoded = rest.find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| entity_char(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            },
+            // A stray &, keep it
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn entity_char(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ => {
+            let number = entity.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse::<u32>().ok()?
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ collections::HashMap, fs, io::Write, path::PathBuf };
+
+    use super::{ decode_entities, download, fetch_feed, is_episode, parse_duration, podcast_dir };
+    use crate::test_helpers::{ self, test_dir };
+
+    // Answers every request with the body saved for its path, or a 404
+    fn serve(files: HashMap<&'static str, Vec<u8>>) -> String {
+        test_helpers::serve(move |request, stream| {
+            let path = request.split_whitespace().nth(1).unwrap_or("/");
+            let _ = match files.get(path) {
+                Some(body) => {
+                    let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                    stream.write_all(body)
+                },
+                None => stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            };
+        })
+    }
+
+    const FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+<channel>
+    <title>Test &amp; Friends</title>
+    <description>A feed served by the tests</description>
+    <itunes:image href="http://example.com/cover.jpg"/>
+    <item>
+        <guid>episode-2</guid>
+        <title>Second Episode</title>
+        <enclosure url="http://example.com/2.mp3" type="audio/mpeg" length="4"/>
+        <pubDate>Tue, 02 Jan 2024 10:00:00 GMT</pubDate>
+        <itunes:duration>01:02:03</itunes:duration>
+    </item>
+    <item>
+        <title><![CDATA[First Episode]]></title>
+        <enclosure url="http://example.com/1.mp3" type="audio/mpeg" length="4"/>
+        <itunes:duration>95</itunes:duration>
+    </item>
+    <item>
+        <title>No audio, skipped</title>
+    </item>
+</channel>
+</rss>"#;
+
+    #[test]
+    fn fetch_feed_reads_a_served_feed() {
+        let address = serve(HashMap::from([("/feed.xml", FEED.as_bytes().to_vec())]));
+        let feed = tauri::async_runtime::block_on(fetch_feed(&format!("{}/feed.xml", address))).unwrap();
+
+        assert_eq!(feed.title, "Test & Friends");
+        assert_eq!(feed.image_url.as_deref(), Some("http://example.com/cover.jpg"));
+        assert_eq!(feed.episodes.len(), 2);
+
+        assert_eq!(feed.episodes[0].guid, "episode-2");
+        assert_eq!(feed.episodes[0].duration, Some(3723));
+        assert!(feed.episodes[0].published.as_deref().unwrap().starts_with("2024-01-02T10:00:00"));
+        // Without a guid the audio url tells the episodes apart
+        assert_eq!(feed.episodes[1].guid, "http://example.com/1.mp3");
+        assert_eq!(feed.episodes[1].title, "First Episode");
+        assert_eq!(feed.episodes[1].duration, Some(95));
+
+        assert!(tauri::async_runtime::block_on(fetch_feed(&format!("{}/missing.xml", address))).is_err());
+    }
+
+    #[test]
+    fn download_saves_the_whole_episode() {
+        let audio: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let address = serve(HashMap::from([("/episode.mp3", audio.clone())]));
+        let dir = test_dir("podcast-download");
+        let dest = dir.join("feed").join("1.mp3");
+
+        let mut last = None;
+        let result = tauri::async_runtime::block_on(download(&format!("{}/episode.mp3", address), &dest, |downloaded, total| {
+            last = Some((downloaded, total));
+        }));
+
+        assert!(result.is_ok());
+        assert_eq!(fs::read(&dest).unwrap(), audio);
+        assert!(!dest.with_extension("part").exists());
+        assert_eq!(last, Some((audio.len() as u64, Some(audio.len() as u64))));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_download_leaves_no_file() {
+        let address = serve(HashMap::new());
+        let dir = test_dir("podcast-missing");
+        let dest = dir.join("1.mp3");
+
+        let result = tauri::async_runtime::block_on(download(&format!("{}/episode.mp3", address), &dest, |_, _| {}));
+
+        assert!(result.is_err());
+        assert!(!dest.exists());
+        assert!(!dest.with_extension("part").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_files_in_the_podcast_folder_are_episodes() {
+        let episode = PathBuf::from(podcast_dir()).join("3").join("12.mp3");
+        assert!(is_episode(&episode.to_string_lossy()));
+        assert!(!is_episode("/music/Album/01 Song.flac"));
+    }
+
+    #[test]
+    fn durations_are_read_as_seconds() {
+        let cases = [
+            ("95", Some(95)),
+            ("1:30", Some(90)),
+            ("01:02:03", Some(3723)),
+            // Fractions of a second are dropped
+            (" 12:05.5 ", Some(725)),
+            ("1:2:3:4", None),
+            ("1:xx", None),
+            ("", None)
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_duration(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        let cases = [
+            ("Tom &amp; Jerry", "Tom & Jerry"),
+            ("&lt;b&gt; &quot;x&quot; &apos;y&apos;", "<b> \"x\" 'y'"),
+            ("It&#39;s", "It's"),
+            ("Don&#x2019;t", "Don\u{2019}t"),
+            // Anything that isn't an entity is left as it is
+            ("AT&T", "AT&T"),
+            ("a & b; c", "a & b; c"),
+            ("&unknown;", "&unknown;"),
+            ("&#xD800;", "&#xD800;")
+        ];
+        for (text, expected) in cases {
+            assert_eq!(decode_entities(text), expected, "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use std::{ io::{ self, Read, Seek, SeekFrom }, time::Duration };
+use flume::Receiver;
+use rodio::Decoder;
+use tauri_plugin_log::log;
+
+use crate::formats;
+
+// Internet radio, HTTP / Icecast streams (MP3, AAC and Ogg)
+// The stream is downloaded on the async runtime and handed to the decoder through a channel, like a file that never ends
+// Icecast puts the song title in the stream every `icy-metaint` bytes, those blocks are cut out before the decoder sees them
+// SHOUTcast v1 servers answer with "ICY 200 OK" instead of HTTP, those need the server's HTTP (v2) address
+
+// Chunks of the stream waiting for the decoder, a few seconds of audio at common bitrates
+const STREAM_BUFFER_CHUNKS: usize = 64;
+// The reader is pulled by the output, a station that stops sending for this long is treated as ended
+// so a stalled stream can't hold up the sound (or the player thread)
+const STREAM_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the player needs to play a station
+pub struct RadioSource {
+    pub decoder: Decoder<RadioReader>,
+    // "Now playing" titles, in the order they show up in the stream
+    pub titles: Receiver<String>
+}
+
+// Connect to the station, the stream keeps downloading until the player drops it
+pub async fn open_stream(url: &str) -> Result<RadioSource, String> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(STREAM_STALL_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Error connecting to the station: {}", e))?;
+    let mut response = client
+        .get(url)
+        .header("Icy-MetaData", "1")
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("Error connecting to the station: {}", e))?;
+
+    let header = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+    let metaint = header("icy-metaint").and_then(|v| v.trim().parse::<usize>().ok()).filter(|v| *v > 0);
+    let hint = header("content-type").and_then(|v| content_type_hint(&v));
+
+    let (audio_tx, audio_rx) = flume::bounded::<Vec<u8>>(STREAM_BUFFER_CHUNKS);
+    let (title_tx, title_rx) = flume::unbounded::<String>();
+
+    tauri::async_runtime::spawn(async move {
+        let mut demuxer = IcyDemuxer::new(metaint);
+        loop {
+            match response.chunk().await {
+                Ok(Some(bytes)) => {
+                    let (audio, titles) = demuxer.push(&bytes);
+                    for title in titles {
+                        let _ = title_tx.send(title);
+                    }
+                    // The player stopped the station and dropped the reader
+                    if !audio.is_empty() && audio_tx.send_async(audio).await.is_err() {
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Radio - Stream error: {:?}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let reader = RadioReader { rx: audio_rx, buffer: vec![], offset: 0, position: 0 };
+
+    // The decoder reads the start of the stream to find the format, so this waits for the first few chunks
+    tauri::async_runtime::spawn_blocking(move || {
+        formats::stream_decoder(reader, None, hint, false).map_err(|e| format!("Error decoding the station: {}", e))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map(|decoder| RadioSource { decoder, titles: title_rx })
+}
+
+fn content_type_hint(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_lowercase();
+    match mime.as_str() {
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/aac" | "audio/aacp" | "audio/x-aac" => Some("aac"),
+        "audio/ogg" | "application/ogg" | "audio/opus" => Some("ogg"),
+        _ => None
+    }
+}
+
+
+// ------------------- Stream Reader -------------------
+
+// Hands the downloaded chunks to the decoder, waits when the download falls behind
+pub struct RadioReader {
+    rx: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    offset: usize,
+    position: u64
+}
+
+impl Read for RadioReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset >= self.buffer.len() {
+            match self.rx.recv_timeout(STREAM_STALL_TIMEOUT) {
+                Ok(chunk) => {
+                    self.buffer = chunk;
+                    self.offset = 0;
+                },
+                // The stream ended, the connection dropped or the station stalled
+                Err(_) => return Ok(0)
+            }
+        }
+
+        let n = buf.len().min(self.buffer.len() - self.offset);
+        buf[..n].copy_from_slice(&self.buffer[self.offset..self.offset + n]);
+        self.offset += n;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+// A live stream can't seek, only the current position can be asked for
+impl Seek for RadioReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.position),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "Radio streams can't seek"))
+        }
+    }
+}
+
+
+// ------------------- ICY Metadata -------------------
+// Every `metaint` bytes of audio there is one length byte (x16), then that many bytes of metadata
+// ex. StreamTitle='Artist - Title';StreamUrl='';
+
+struct IcyDemuxer {
+    metaint: Option<usize>,
+    until_meta: usize,
+    meta_len: Option<usize>,
+    meta: Vec<u8>
+}
+
+impl IcyDemuxer {
+    fn new(metaint: Option<usize>) -> Self {
+        Self { metaint, until_meta: metaint.unwrap_or(0), meta_len: None, meta: vec![] }
+    }
+
+    // Split a chunk into audio and any titles found in it
+    fn push(&mut self, bytes: &[u8]) -> (Vec<u8>, Vec<String>) {
+        let Some(metaint) = self.metaint else {
+            return (bytes.to_vec(), vec![]);
+        };
+
+        let mut audio = Vec::with_capacity(bytes.len());
+        let mut titles = vec![];
+        let mut i = 0;
+
+        while i < bytes.len() {
+            match self.meta_len {
+                None if self.until_meta > 0 => {
+                    let n = self.until_meta.min(bytes.len() - i);
+                    audio.extend_from_slice(&bytes[i..i + n]);
+                    self.until_meta -= n;
+                    i += n;
+                },
+                None => {
+                    let len = bytes[i] as usize * 16;
+                    i += 1;
+                    self.meta.clear();
+                    if len == 0 {
+                        self.until_meta = metaint;
+                    }
+                    else {
+                        self.meta_len = Some(len);
+                    }
+                },
+                Some(len) => {
+                    let n = (len - self.meta.len()).min(bytes.len() - i);
+                    self.meta.extend_from_slice(&bytes[i..i + n]);
+                    i += n;
+                    if self.meta.len() == len {
+                        if let Some(title) = parse_stream_title(&self.meta) {
+                            titles.push(title);
+                        }
+                        self.meta_len = None;
+                        self.until_meta = metaint;
+                    }
+                }
+            }
+        }
+
+        (audio, titles)
+    }
+}
+
+fn parse_stream_title(meta: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(meta);
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &text[start..];
+    // Titles can have quotes in them, the field ends at "';"
+    let end = rest.find("';").or_else(|| rest.rfind('\''))?;
+    Some(rest[..end].trim().to_string())
+}
+
+
+// ------------------- Playlist Import -------------------
+
+// (name, url) of each station in an M3U or PLS playlist
+pub fn parse_playlist(path: &str, contents: &str) -> Vec<(String, String)> {
+    if path.to_lowercase().ends_with(".pls") {
+        parse_pls(contents)
+    }
+    else {
+        parse_m3u(contents)
+    }
+}
+
+// #EXTINF:-1,Station Name
+// http://example.com/stream
+fn parse_m3u(contents: &str) -> Vec<(String, String)> {
+    let mut stations = vec![];
+    let mut name: Option<String> = None;
+
+    for line in contents.lines().map(|l| l.trim()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            name = info.split_once(',').map(|(_, n)| n.trim().to_string()).filter(|n| !n.is_empty());
+        }
+        else if !line.is_empty() && !line.starts_with('#') {
+            if is_stream_url(line) {
+                stations.push((name.take().unwrap_or_else(|| line.to_string()), line.to_string()));
+            }
+            name = None;
+        }
+    }
+    stations
+}
+
+// File1=http://example.com/stream
+// Title1=Station Name
+fn parse_pls(contents: &str) -> Vec<(String, String)> {
+    let mut files: Vec<(String, String)> = vec![];
+    let mut titles: Vec<(String, String)> = vec![];
+
+    for line in contents.lines().map(|l| l.trim()) {
+        let Some((key, value)) = line.split_once('=') else { continue };
+        let key = key.trim().to_lowercase();
+        if let Some(n) = key.strip_prefix("file") {
+            files.push((n.to_string(), value.trim().to_string()));
+        }
+        else if let Some(n) = key.strip_prefix("title") {
+            titles.push((n.to_string(), value.trim().to_string()));
+        }
+    }
+
+    files.into_iter()
+        .filter(|(_, url)| is_stream_url(url))
+        .map(|(n, url)| {
+            let name = titles.iter().find(|(t, _)| *t == n).map(|(_, title)| title.clone()).filter(|t| !t.is_empty());
+            (name.unwrap_or_else(|| url.clone()), url)
+        })
+        .collect()
+}
+
+pub fn is_stream_url(url: &str) -> bool {
+    let url = url.to_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ f32::consts::PI, io::Write, thread, time::{ Duration, Instant } };
+    use rodio::Player;
+    use tauri::async_runtime::block_on;
+
+    use super::{ IcyDemuxer, open_stream };
+    use crate::{ music::{ MusicPlayer, TrackEnd }, output::{ NullBackend, OutputBackend }, test_helpers, types::RadioStation };
+
+    const METAINT: usize = 4096;
+
+    // Half a second of a mono 16-bit sine wave, a WAV header without a seek table streams like any station
+    fn wav_stream() -> Vec<u8> {
+        let rate: u32 = 44100;
+        let samples = rate / 2;
+        let mut data = vec![];
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&(36 + samples * 2).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&rate.to_le_bytes());
+        data.extend_from_slice(&(rate * 2).to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&16u16.to_le_bytes());
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&(samples * 2).to_le_bytes());
+        for i in 0..samples {
+            let sample = ((i as f32 * 440.0 * 2.0 * PI / rate as f32).sin() * 8000.0) as i16;
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+        data
+    }
+
+    // One metadata block, padded to a multiple of 16 bytes
+    fn meta_block(title: Option<&str>) -> Vec<u8> {
+        let Some(title) = title else { return vec![0] };
+        let mut meta = format!("StreamTitle='{}';StreamUrl='';", title).into_bytes();
+        meta.resize(meta.len().div_ceil(16) * 16, 0);
+        let mut block = vec![(meta.len() / 16) as u8];
+        block.extend(meta);
+        block
+    }
+
+    // Audio with a metadata block every METAINT bytes, the titles are used in order and then the blocks are empty
+    fn icy_body(audio: &[u8], titles: &[&str]) -> Vec<u8> {
+        let mut body = vec![];
+        for (i, chunk) in audio.chunks(METAINT).enumerate() {
+            body.extend_from_slice(chunk);
+            if chunk.len() == METAINT {
+                body.extend(meta_block(titles.get(i).copied()));
+            }
+        }
+        body
+    }
+
+    // Every connection gets the whole stream and is then closed, like a station going off the air
+    fn serve(body: Vec<u8>) -> String {
+        let address = test_helpers::serve(move |request, stream| {
+            // The station only sends metadata to players that ask for it
+            if !request.to_lowercase().contains("icy-metadata: 1") {
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                return;
+            }
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: audio/wav\r\nicy-metaint: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", METAINT, body.len());
+            let _ = stream.write_all(&body);
+        });
+        format!("{}/stream", address)
+    }
+
+    fn station(url: &str) -> RadioStation {
+        RadioStation { id: 7, name: "Test FM".to_string(), url: url.to_string() }
+    }
+
+    fn wait_for_title(player: &mut MusicPlayer, title: &str) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while Instant::now() < deadline {
+            player.update_radio_title();
+            if player.get_radio().and_then(|radio| radio.title).as_deref() == Some(title) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    fn wait_for_end(player: &mut MusicPlayer) -> Option<TrackEnd> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(end) = player.check_track_end() {
+                return Some(end);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        None
+    }
+
+    #[test]
+    fn metadata_is_cut_out_of_the_audio() {
+        let audio = wav_stream();
+        let body = icy_body(&audio, &["Artist - First", "", "It's \"quoted\" - Second"]);
+
+        // Chunk sizes that split the length byte and the metadata across pushes
+        let mut demuxer = IcyDemuxer::new(Some(METAINT));
+        let mut out = vec![];
+        let mut titles = vec![];
+        for chunk in body.chunks(1000) {
+            let (a, t) = demuxer.push(chunk);
+            out.extend(a);
+            titles.extend(t);
+        }
+
+        assert_eq!(out, audio);
+        assert_eq!(titles, vec!["Artist - First", "", "It's \"quoted\" - Second"]);
+    }
+
+    #[test]
+    fn station_title_is_played_then_cleared_when_the_stream_ends() {
+        let audio = wav_stream();
+        let url = serve(icy_body(&audio, &["Artist - First", "Artist - Second"]));
+        let backend = NullBackend::new().unwrap();
+        let mut player = MusicPlayer::new(Player::connect_new(backend.mixer())).unwrap();
+
+        let source = block_on(open_stream(&url)).unwrap();
+        player.play_radio(station(&url), source);
+        assert_eq!(player.get_radio().map(|radio| radio.station_id), Some(7));
+        assert!(wait_for_title(&mut player, "Artist - Second"));
+
+        // The station closing the connection stops the radio, it doesn't move on to the queue
+        assert!(matches!(wait_for_end(&mut player), Some(TrackEnd::QueueEnded)));
+        assert!(player.get_radio().is_none());
+        player.update_radio_title();
+        assert!(player.get_radio().is_none());
+
+        // Connecting again starts without the old title, until the stream sends one
+        let source = block_on(open_stream(&url)).unwrap();
+        player.play_radio(station(&url), source);
+        assert_eq!(player.get_radio().and_then(|radio| radio.title), None);
+        assert!(wait_for_title(&mut player, "Artist - Second"));
+
+        // Stopping drops the stream and the title with it
+        player.stop_song();
+        assert!(player.get_radio().is_none());
+    }
+}
@@ -0,0 +1,133 @@
+use std::{ sync::{ Arc, atomic::{ AtomicU32, AtomicU64, Ordering } }, time::Duration };
+use rodio::{ ChannelCount, SampleRate, Source, source::SeekError };
+
+// Short volume ramps on play, pause, seek and skips, so the sound never starts or stops mid wave (clicks)
+// The ramp is the last thing in each song's chain, so it works the same on every output device
+// Only one song plays at a time, so the songs share one gain and a new song picks up where the last one left it
+
+// Longest ramp allowed, anything longer makes the buttons feel slow
+pub const MAX_RAMP_MS: u64 = 500;
+
+// ------------------- Shared Controls -------------------
+
+pub struct RampControls {
+    // Ramp length in ms, 0 changes the volume right away
+    duration: AtomicU64,
+    // f32 bits, 1.0 when playing and 0.0 while fading out
+    target: AtomicU32,
+    // f32 bits, the gain the playing song has reached
+    gain: AtomicU32
+}
+
+impl RampControls {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            duration: AtomicU64::new(0),
+            target: AtomicU32::new(1.0f32.to_bits()),
+            gain: AtomicU32::new(1.0f32.to_bits())
+        })
+    }
+
+    pub fn set_duration(&self, duration: Duration) {
+        self.duration.store(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration.load(Ordering::Relaxed))
+    }
+
+    pub fn fade_in(&self) {
+        self.target.store(1.0f32.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn fade_out(&self) {
+        self.target.store(0.0f32.to_bits(), Ordering::Relaxed);
+    }
+
+    // Jump straight to a gain, used while nothing is playing
+    pub fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    fn target(&self) -> f32 {
+        f32::from_bits(self.target.load(Ordering::Relaxed))
+    }
+}
+
+
+// ------------------- Ramp Source -------------------
+
+pub struct Ramp<S: Source> {
+    input: S,
+    controls: Arc<RampControls>,
+    channels: usize,
+    channel: usize,
+    gain: f32
+}
+
+impl<S: Source> Ramp<S> {
+    pub fn new(input: S, controls: Arc<RampControls>) -> Self {
+        let channels = (u16::from(input.channels()) as usize).max(1);
+        Self { input, controls, channels, channel: 0, gain: 1.0 }
+    }
+
+    // Move the gain one frame closer to the target
+    fn update_frame(&mut self) {
+        let gain = self.controls.gain();
+        let target = self.controls.target();
+        let frames = self.controls.duration().as_secs_f32() * u32::from(self.input.sample_rate()) as f32;
+
+        self.gain = if frames < 1.0 || (target - gain).abs() <= 1.0 / frames {
+            target
+        }
+        else if target > gain {
+            gain + 1.0 / frames
+        }
+        else {
+            gain - 1.0 / frames
+        };
+        self.controls.set_gain(self.gain);
+    }
+}
+
+impl<S: Source> Iterator for Ramp<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            self.update_frame();
+        }
+
+        let sample = self.input.next()?;
+        self.channel = (self.channel + 1) % self.channels;
+        Some(sample * self.gain)
+    }
+}
+
+impl<S: Source> Source for Ramp<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.channel = 0;
+        Ok(())
+    }
+}
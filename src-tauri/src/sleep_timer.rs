@@ -0,0 +1,99 @@
+use std::time::{ Duration, Instant };
+
+use crate::types::SleepTimerStatus;
+
+// Sleep timer, stops playback after some minutes or after some songs
+// The volume fades out over the last `fade` of the timer, the player puts it back once playback has stopped
+// The player thread checks the timer a few times a second, and tells it when a song finishes
+
+pub const MAX_FADE_SECS: f32 = 120.0;
+
+#[derive(PartialEq, Clone, Copy)]
+enum SleepMode {
+    Minutes,
+    Tracks
+}
+
+pub struct SleepTimer {
+    mode: SleepMode,
+    // Minutes mode
+    ends_at: Instant,
+    // Tracks mode - songs that still play after the current one, 0 stops at the end of this song
+    tracks_left: u32,
+    fade: Duration
+}
+
+impl SleepTimer {
+    pub fn minutes(minutes: u32, fade: Duration) -> Self {
+        Self {
+            mode: SleepMode::Minutes,
+            ends_at: Instant::now() + Duration::from_secs(minutes as u64 * 60),
+            tracks_left: 0,
+            fade
+        }
+    }
+
+    pub fn tracks(tracks: u32, fade: Duration) -> Self {
+        Self { mode: SleepMode::Tracks, ends_at: Instant::now(), tracks_left: tracks, fade }
+    }
+
+    // Adds minutes in minutes mode, or songs in tracks mode
+    pub fn extend(&mut self, amount: u32) {
+        match self.mode {
+            SleepMode::Minutes => self.ends_at += Duration::from_secs(amount as u64 * 60),
+            SleepMode::Tracks => self.tracks_left += amount
+        }
+    }
+
+    // Time until playback stops, song_left is what is left of the playing song
+    // None in tracks mode until the last song is playing
+    pub fn remaining(&self, song_left: Duration) -> Option<Duration> {
+        match self.mode {
+            SleepMode::Minutes => Some(self.ends_at.saturating_duration_since(Instant::now())),
+            SleepMode::Tracks => if self.tracks_left == 0 { Some(song_left) } else { None }
+        }
+    }
+
+    // Volume multiplier, goes from 1 down to 0 over the fade
+    pub fn fade_factor(&self, song_left: Duration) -> f32 {
+        match self.remaining(song_left) {
+            Some(remaining) if !self.fade.is_zero() && remaining < self.fade => {
+                (remaining.as_secs_f32() / self.fade.as_secs_f32()).clamp(0.0, 1.0)
+            },
+            _ => 1.0
+        }
+    }
+
+    // Minutes mode ran out
+    pub fn is_done(&self) -> bool {
+        self.mode == SleepMode::Minutes && Instant::now() >= self.ends_at
+    }
+
+    // The next song shouldn't be pre-loaded, playback stops when this one ends
+    pub fn stops_after_this_song(&self) -> bool {
+        self.mode == SleepMode::Tracks && self.tracks_left == 0
+    }
+
+    // Called when a song finishes, true when that was the last one
+    pub fn song_finished(&mut self) -> bool {
+        if self.mode != SleepMode::Tracks {
+            return false;
+        }
+        if self.tracks_left == 0 {
+            return true;
+        }
+        self.tracks_left -= 1;
+        false
+    }
+
+    pub fn status(&self, song_left: Duration) -> SleepTimerStatus {
+        SleepTimerStatus {
+            mode: match self.mode { SleepMode::Minutes => "minutes", SleepMode::Tracks => "tracks" }.to_string(),
+            // Rounded up, so the countdown shows 1 until it actually stops
+            remaining_secs: self.remaining(song_left).map(|r| r.as_millis().div_ceil(1000) as u64),
+            tracks_left: self.tracks_left,
+            fade_secs: self.fade.as_secs_f32(),
+            fading: self.fade_factor(song_left) < 1.0
+        }
+    }
+}
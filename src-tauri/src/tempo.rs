@@ -0,0 +1,402 @@
+use std::{ collections::VecDeque, f32::consts::PI, sync::{ Arc, atomic::{ AtomicBool, AtomicU32, AtomicU64, Ordering } }, time::Duration };
+use rodio::{ ChannelCount, SampleRate, Source, source::SeekError };
+
+// Playback speed control, used for audiobooks and lectures
+// Speed only - the song is resampled, so the pitch goes up and down with the speed
+// Keep pitch - the song is stretched with WSOLA (overlapping pieces of the song, lined up so they blend)
+// Seeking always uses the song's own time, so a seek to 1:00 is the same spot at any speed
+// The position of the playing song is also kept in the song's own time, the sink's position counts real time
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+
+// WSOLA sizes in frames (~46ms pieces at 44.1kHz, each overlapping half of the last one)
+const FRAME: usize = 2048;
+const HOP: usize = FRAME / 2;
+// How far a piece can move to line up with the last one
+const TOLERANCE: usize = 512;
+// Only every few samples are compared when lining up the pieces, keeps it cheap
+const SEARCH_STEP: usize = 2;
+const COMPARE_STEP: usize = 4;
+
+// ------------------- Shared Controls -------------------
+
+pub struct TempoControls {
+    // f32 bits of the speed
+    speed: AtomicU32,
+    keep_pitch: AtomicBool,
+    // f64 bits of the playing song's position in seconds
+    position: AtomicU64
+}
+
+impl TempoControls {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            speed: AtomicU32::new(1f32.to_bits()),
+            keep_pitch: AtomicBool::new(true),
+            position: AtomicU64::new(0f64.to_bits())
+        })
+    }
+
+    pub fn set(&self, speed: f32, keep_pitch: bool) {
+        self.speed.store(speed.clamp(MIN_SPEED, MAX_SPEED).to_bits(), Ordering::Relaxed);
+        self.keep_pitch.store(keep_pitch, Ordering::Relaxed);
+    }
+
+    pub fn speed(&self) -> f32 {
+        f32::from_bits(self.speed.load(Ordering::Relaxed))
+    }
+
+    pub fn keep_pitch(&self) -> bool {
+        self.keep_pitch.load(Ordering::Relaxed)
+    }
+
+    pub fn position(&self) -> Duration {
+        Duration::from_secs_f64(f64::from_bits(self.position.load(Ordering::Relaxed)).max(0.0))
+    }
+
+    fn set_position(&self, seconds: f64) {
+        self.position.store(seconds.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn reset_position(&self) {
+        self.set_position(0.0);
+    }
+}
+
+
+// ------------------- Tempo Source -------------------
+
+#[derive(PartialEq, Clone, Copy)]
+enum Mode {
+    Bypass,
+    Resample,
+    Stretch
+}
+
+pub struct Tempo<S: Source> {
+    input: S,
+    controls: Arc<TempoControls>,
+    channels: usize,
+    sample_rate: f64,
+    speed: f32,
+    mode: Mode,
+    // Position in the song in seconds, moves faster or slower than real time
+    position: f64,
+    // Interleaved samples ready to be played
+    output: VecDeque<f32>,
+    // Position inside of the output frame, settings only change on a frame boundary
+    channel: usize,
+    resampler: Resampler,
+    wsola: Wsola
+}
+
+impl<S: Source> Tempo<S> {
+    pub fn new(input: S, controls: Arc<TempoControls>) -> Self {
+        let channels = (u16::from(input.channels()) as usize).max(1);
+        let sample_rate = (u32::from(input.sample_rate()) as f64).max(1.0);
+        let mut tempo = Self {
+            input,
+            controls,
+            channels,
+            sample_rate,
+            speed: 1.0,
+            mode: Mode::Bypass,
+            position: 0.0,
+            output: VecDeque::new(),
+            channel: 0,
+            resampler: Resampler::new(channels),
+            wsola: Wsola::new(channels)
+        };
+        tempo.update_mode();
+        tempo
+    }
+
+    // Position in the song's own time
+    pub fn position(&self) -> Duration {
+        Duration::from_secs_f64(self.position.max(0.0))
+    }
+
+    fn update_mode(&mut self) {
+        let speed = self.controls.speed();
+        let mode = if (speed - 1.0).abs() < 0.001 {
+            Mode::Bypass
+        }
+        else if self.controls.keep_pitch() {
+            Mode::Stretch
+        }
+        else {
+            Mode::Resample
+        };
+
+        // Start fresh from the current spot in the song when switching
+        if mode != self.mode {
+            self.resampler = Resampler::new(self.channels);
+            self.wsola = Wsola::new(self.channels);
+        }
+        self.speed = speed;
+        self.mode = mode;
+    }
+}
+
+impl<S: Source> Iterator for Tempo<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.output.is_empty() {
+            if self.channel == 0 {
+                self.update_mode();
+            }
+
+            match self.mode {
+                Mode::Bypass => {
+                    let sample = self.input.next()?;
+                    self.output.push_back(sample);
+                },
+                Mode::Resample => self.resampler.process(&mut self.input, self.speed, &mut self.output),
+                Mode::Stretch => self.wsola.process(&mut self.input, self.speed, &mut self.output)
+            }
+        }
+
+        let sample = self.output.pop_front()?;
+        self.channel += 1;
+        if self.channel == self.channels {
+            self.channel = 0;
+            self.position += self.speed as f64 / self.sample_rate;
+            self.controls.set_position(self.position);
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for Tempo<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        if self.mode == Mode::Bypass { self.input.current_span_len() } else { None }
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    // How long the song takes to play at the current speed
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration().map(|d| d.div_f32(self.controls.speed()))
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.output.clear();
+        self.channel = 0;
+        self.position = pos.as_secs_f64();
+        self.controls.set_position(self.position);
+        self.resampler = Resampler::new(self.channels);
+        self.wsola = Wsola::new(self.channels);
+        Ok(())
+    }
+}
+
+fn read_frame<S: Source>(input: &mut S, channels: usize) -> Option<Vec<f32>> {
+    let mut frame = Vec::with_capacity(channels);
+    for _ in 0..channels {
+        frame.push(input.next()?);
+    }
+    Some(frame)
+}
+
+
+// ------------------- Resampler -------------------
+// Linear interpolation between frames, steps through the song faster or slower
+
+struct Resampler {
+    channels: usize,
+    current: Option<Vec<f32>>,
+    next: Option<Vec<f32>>,
+    frac: f64
+}
+
+impl Resampler {
+    fn new(channels: usize) -> Self {
+        Self { channels, current: None, next: None, frac: 0.0 }
+    }
+
+    fn process<S: Source>(&mut self, input: &mut S, speed: f32, output: &mut VecDeque<f32>) {
+        if self.current.is_none() {
+            self.current = read_frame(input, self.channels);
+            self.next = read_frame(input, self.channels);
+        }
+
+        let (current, next) = match (&self.current, &self.next) {
+            (Some(c), Some(n)) => (c, n),
+            // Last frame of the song
+            (Some(c), None) => {
+                output.extend(c.iter());
+                self.current = None;
+                return;
+            },
+            _ => return
+        };
+
+        let frac = self.frac as f32;
+        output.extend(current.iter().zip(next.iter()).map(|(a, b)| a + (b - a) * frac));
+
+        self.frac += speed as f64;
+        while self.frac >= 1.0 && self.next.is_some() {
+            self.frac -= 1.0;
+            self.current = self.next.take();
+            self.next = read_frame(input, self.channels);
+        }
+    }
+}
+
+
+// ------------------- WSOLA -------------------
+
+struct Wsola {
+    channels: usize,
+    // Buffered input for each channel, buffer[c][0] is the frame at `base` in the song
+    buffer: Vec<Vec<f32>>,
+    base: usize,
+    // Where the next piece should come from, before lining it up
+    pos: f64,
+    // Where the last piece came from
+    prev: usize,
+    first: bool,
+    // Second half of the last piece, waiting for the next piece to be added to it
+    overlap: Vec<Vec<f32>>,
+    window: Vec<f32>,
+    // Song length in frames, once the end of the input is reached
+    end: Option<usize>
+}
+
+impl Wsola {
+    fn new(channels: usize) -> Self {
+        // Periodic Hann window, two of these overlapping by half always add up to 1
+        let window = (0..FRAME).map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / FRAME as f32).cos()).collect();
+
+        Self {
+            channels,
+            buffer: vec![vec![]; channels],
+            base: 0,
+            pos: 0.0,
+            prev: 0,
+            first: true,
+            overlap: vec![vec![0.0; HOP]; channels],
+            window,
+            end: None
+        }
+    }
+
+    fn buffered_end(&self) -> usize {
+        self.base + self.buffer[0].len()
+    }
+
+    fn fill<S: Source>(&mut self, input: &mut S, until: usize) {
+        while self.buffered_end() < until {
+            match read_frame(input, self.channels) {
+                Some(frame) if self.end.is_none() => {
+                    for (c, sample) in frame.into_iter().enumerate() {
+                        self.buffer[c].push(sample);
+                    }
+                },
+                _ => {
+                    // Pad the end of the song with silence
+                    if self.end.is_none() {
+                        self.end = Some(self.buffered_end());
+                    }
+                    for channel in self.buffer.iter_mut() {
+                        channel.push(0.0);
+                    }
+                }
+            }
+        }
+    }
+
+    fn mono(&self, index: usize) -> f32 {
+        let i = index - self.base;
+        self.buffer.iter().map(|c| c[i]).sum()
+    }
+
+    // Find the spot near `target` that lines up best with how the last piece would have continued
+    fn best_offset(&self, target: usize) -> usize {
+        let template = self.prev + HOP;
+        let start = target.saturating_sub(TOLERANCE).max(self.base);
+        let stop = target + TOLERANCE;
+
+        let mut best = target;
+        let mut best_score = f32::MIN;
+        let mut candidate = start;
+        while candidate <= stop {
+            let mut corr = 0.0;
+            let mut energy = 0.0;
+            let mut i = 0;
+            while i < HOP {
+                let a = self.mono(template + i);
+                let b = self.mono(candidate + i);
+                corr += a * b;
+                energy += b * b;
+                i += COMPARE_STEP;
+            }
+            let score = corr / (energy.sqrt() + 1e-9);
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+            candidate += SEARCH_STEP;
+        }
+        best
+    }
+
+    fn process<S: Source>(&mut self, input: &mut S, speed: f32, output: &mut VecDeque<f32>) {
+        let target = self.pos.floor() as usize;
+
+        // Out of song, play whatever is left of the last piece
+        if let Some(end) = self.end {
+            if target >= end {
+                if !self.overlap[0].is_empty() {
+                    for i in 0..HOP {
+                        for c in 0..self.channels {
+                            output.push_back(self.overlap[c][i]);
+                        }
+                    }
+                    self.overlap = vec![vec![]; self.channels];
+                }
+                return;
+            }
+        }
+
+        self.fill(input, target + TOLERANCE + FRAME);
+
+        let chosen = if self.first { target } else { self.best_offset(target) };
+
+        // Add the first half of the new piece to the last piece, and keep the second half for next time
+        for i in 0..HOP {
+            for c in 0..self.channels {
+                let value = self.buffer[c][chosen - self.base + i] * self.window[i];
+                output.push_back(self.overlap[c][i] + value);
+            }
+        }
+        for c in 0..self.channels {
+            for i in 0..HOP {
+                self.overlap[c][i] = self.buffer[c][chosen - self.base + HOP + i] * self.window[HOP + i];
+            }
+        }
+
+        self.prev = chosen;
+        self.first = false;
+        self.pos += HOP as f64 * speed as f64;
+
+        // Drop input that can't be used anymore
+        let keep_from = (self.pos.floor() as usize).saturating_sub(TOLERANCE).min(self.prev + HOP);
+        if keep_from > self.base {
+            let drop = (keep_from - self.base).min(self.buffer[0].len());
+            for channel in self.buffer.iter_mut() {
+                channel.drain(..drop);
+            }
+            self.base += drop;
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use std::{ fs, io::Read, net::{ TcpListener, TcpStream }, path::PathBuf, thread };
+
+// Fixtures shared by the tests of the different modules
+
+// An empty folder in the temp dir, cleared out first so every run starts the same
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("robintuk-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// A local HTTP server, `respond` gets the head of each request and writes the whole answer to the connection
+// Returns the address, ex. http://127.0.0.1:1234
+pub fn serve(respond: impl Fn(&str, &mut TcpStream) + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n])
+                }
+            }
+            respond(&String::from_utf8_lossy(&request), &mut stream);
+        }
+    });
+
+    address
+}
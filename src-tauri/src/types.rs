@@ -21,7 +21,21 @@ pub struct SongTableUpload {
     pub song_section: Option<i32>,
     pub album_section: Option<i32>,
     pub artist_section: Option<i32>,
-    pub genre_section: Option<i32>
+    pub genre_section: Option<i32>,
+    pub replaygain_track_gain: Option<f32>,
+    pub replaygain_track_peak: Option<f32>,
+    pub replaygain_album_gain: Option<f32>,
+    pub replaygain_album_peak: Option<f32>,
+    // Saved to the chapters table, not the songs table
+    #[sqlx(skip)]
+    pub chapters: Vec<Chapter>,
+    // Set for the tracks of a cue sheet, the audio is the part of source_path between the offsets
+    pub source_path: Option<String>,
+    pub start_ms: Option<i64>,
+    pub end_ms: Option<i64>,
+    // The cue sheet of the file (embedded or next to it), split into tracks before saving
+    #[sqlx(skip)]
+    pub cue_sheet: Option<String>
 }
 
 // This struct is for data retreived from the database
@@ -38,7 +52,39 @@ pub struct SongTable {
     pub album_artist: String,
     pub disc_number: i32,
     pub duration: u64,
-    pub song_section: u64
+    pub song_section: u64,
+    // ReplayGain tags, not every query selects these
+    #[sqlx(default)] #[serde(default)]
+    pub replaygain_track_gain: Option<f64>,
+    #[sqlx(default)] #[serde(default)]
+    pub replaygain_track_peak: Option<f64>,
+    #[sqlx(default)] #[serde(default)]
+    pub replaygain_album_gain: Option<f64>,
+    #[sqlx(default)] #[serde(default)]
+    pub replaygain_album_peak: Option<f64>,
+    // Measured by the loudness analysis, used when there are no ReplayGain tags
+    #[sqlx(default)] #[serde(default)]
+    pub loudness_lufs: Option<f64>,
+    #[sqlx(default)] #[serde(default)]
+    pub loudness_peak: Option<f64>,
+    // Only filled in by get_song, everywhere else the chapters are asked for on their own
+    #[sqlx(skip)] #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    // Tracks from a cue sheet, played from source_path between start_ms and end_ms (None is the end of the file)
+    #[sqlx(default)] #[serde(default)]
+    pub source_path: Option<String>,
+    #[sqlx(default)] #[serde(default)]
+    pub start_ms: Option<i64>,
+    #[sqlx(default)] #[serde(default)]
+    pub end_ms: Option<i64>
+}
+
+// A chapter of an audiobook or a long mix, read from the file's tags
+#[derive(sqlx::FromRow, Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    pub title: String,
+    pub start_ms: i64,
+    pub end_ms: i64
 }
 
 #[derive(sqlx::FromRow, Default, Debug, Clone, Serialize)]
@@ -128,7 +174,102 @@ pub struct SongHistory {
     pub album_artist: String,
     pub disc_number: i32,
     pub duration: u64,
-    pub song_section: u64
+    pub song_section: u64,
+    // History songs are played again as they are, so they carry what SongTable needs to play them
+    pub replaygain_track_gain: Option<f64>,
+    pub replaygain_track_peak: Option<f64>,
+    pub replaygain_album_gain: Option<f64>,
+    pub replaygain_album_peak: Option<f64>,
+    pub loudness_lufs: Option<f64>,
+    pub loudness_peak: Option<f64>,
+    pub source_path: Option<String>,
+    pub start_ms: Option<i64>,
+    pub end_ms: Option<i64>
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct RadioStation {
+    pub id: i64,
+    pub name: String,
+    pub url: String
+}
+
+// Sent with the "radio-now-playing" event, null once the radio stops
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RadioNowPlaying {
+    pub station_id: i64,
+    pub station_name: String,
+    // From the stream's ICY metadata, not every station sends it
+    pub title: Option<String>
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct PodcastFeed {
+    pub id: i64,
+    pub url: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub refreshed_at: String,
+    pub unplayed: i64
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct PodcastEpisode {
+    pub id: i64,
+    pub feed_id: i64,
+    pub feed_title: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub audio_url: String,
+    pub published: Option<String>,
+    // Seconds, from the feed
+    pub duration: Option<i64>,
+    // Set once the episode is downloaded
+    pub file_path: Option<String>,
+    pub played: bool
+}
+
+// Sent with the "podcast-download-progress" event, total is missing if the server didn't say how big the file is
+#[derive(Debug, Clone, Serialize)]
+pub struct PodcastDownloadProgress {
+    pub episode_id: i64,
+    pub downloaded: u64,
+    pub total: Option<u64>
+}
+
+// Which songs remember where they were left off, besides the ones marked on their own
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResumeSettings {
+    // Songs at least this long, 0 turns it off
+    pub min_duration_minutes: u64,
+    // Matched without case, ex. ["Audiobook", "Podcast"]
+    pub genres: Vec<String>
+}
+
+// A song that was left part way through, the details are missing if it isn't in the library
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct Bookmark {
+    pub path: String,
+    pub name: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub position_ms: i64,
+    // Seconds
+    pub duration: i64,
+    pub updated_at: String
+}
+
+// A song the player couldn't open or decode, the song details are missing if it was removed from the library
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct PlaybackError {
+    pub path: String,
+    pub name: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub reason: String,
+    pub failed_at: String,
+    pub fail_count: i64
 }
 
 #[derive(sqlx::FromRow, Default, Debug, Clone, serde::Serialize)]
@@ -142,6 +283,175 @@ pub struct QueueFormat {
     pub song_id: String
 }
 
+// ---------------------------------------- Equalizer Structs ----------------------------------------
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EqualizerSettings {
+    pub enabled: bool,
+    pub preamp: f32,
+    pub bands: Vec<f32>,
+    pub preset: String
+}
+
+impl Default for EqualizerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            preamp: 0.0,
+            bands: vec![0.0; 10],
+            preset: "Flat".to_string()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EqualizerPreset {
+    pub name: String,
+    pub preamp: f32,
+    pub bands: Vec<f32>,
+    #[serde(default)]
+    pub built_in: bool
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrossfadeSettings {
+    // Seconds, 0 - 12
+    pub duration: f32,
+    // Turn crossfade off while an album is playing
+    pub album_gapless: bool
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelSettings {
+    // -1 (left only) to 1 (right only)
+    pub balance: f32,
+    // Both ears get the same downmix
+    pub mono: bool,
+    // Swap left and right
+    pub swap: bool,
+    // Headphone crossfeed, 0 - Off, 1 - Default, 2 - Chu Moy, 3 - Jan Meier
+    pub crossfeed: i64
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            balance: 0.0,
+            mono: false,
+            swap: false,
+            crossfeed: 0
+        }
+    }
+}
+
+impl Default for CrossfadeSettings {
+    fn default() -> Self {
+        Self {
+            duration: 0.0,
+            album_gapless: true
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaybackSpeed {
+    // 0.5x - 3x
+    pub speed: f32,
+    // Stretch the song instead of speeding it up like a record
+    pub keep_pitch: bool
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputDevice {
+    pub name: String,
+    pub is_default: bool
+}
+
+// Sent with the "player-state" event whenever the player changes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerStatus {
+    // Position in the queue
+    pub index: usize,
+    pub queue_length: usize,
+    pub is_paused: bool,
+    // A song is in the sink
+    pub is_loaded: bool,
+    pub repeat_mode: i64,
+    pub shuffle: bool,
+    pub volume: f32,
+    pub ab_loop: AbLoopState
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AbLoopState {
+    pub a_ms: Option<u64>,
+    // The loop is running once B is set
+    pub b_ms: Option<u64>
+}
+
+// Sent with the "playback-progress" event a few times a second while a song is playing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlaybackProgress {
+    // Position in the queue
+    pub index: usize,
+    pub position_ms: u64,
+    pub duration_ms: u64,
+    // The next song is loaded and will start without a gap
+    pub next_buffered: bool
+}
+
+// Saved every few seconds and on exit, so the player can start where it was left
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlaybackSession {
+    // Position in the queue
+    pub index: usize,
+    pub position_ms: u64,
+    pub volume: f32,
+    pub repeat_mode: i64,
+    pub shuffle: bool
+}
+
+impl Default for PlaybackSession {
+    fn default() -> Self {
+        Self {
+            index: 0,
+            position_ms: 0,
+            volume: 0.4,
+            repeat_mode: 1,
+            shuffle: false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoredSession {
+    pub session: PlaybackSession,
+    // None when there was no queue to restore
+    pub song: Option<SongTable>
+}
+
+// Sent with the "visualizer-frame" event about 30 times a second while the visualizer is on
+#[derive(Debug, Clone, Serialize)]
+pub struct VisualizerFrame {
+    // Spectrum from low to high frequencies, 0 - 255
+    pub bands: Vec<u8>,
+    pub rms_left: f32,
+    pub rms_right: f32
+}
+
+// Sent with the "sleep-timer" event, the event sends null once the timer is done or cancelled
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SleepTimerStatus {
+    // "minutes" or "tracks"
+    pub mode: String,
+    // Seconds until playback stops, None while the last song hasn't started yet
+    pub remaining_secs: Option<u64>,
+    // Songs that still play after the current one
+    pub tracks_left: u32,
+    pub fade_secs: f32,
+    pub fading: bool
+}
+
 // ---------------------------------------- Event Tracker Structs ----------------------------------------
 
 #[derive(Clone, Serialize)]
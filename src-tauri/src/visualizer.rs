@@ -0,0 +1,249 @@
+use std::{
+    f32::consts::PI, sync::{ Arc, Mutex, atomic::{ AtomicBool, AtomicU32, AtomicU64, Ordering } }, thread, time::{ Duration, Instant }
+};
+use rodio::{ ChannelCount, SampleRate, Source, source::SeekError };
+use tauri::{ AppHandle, Emitter };
+use tauri_plugin_log::log;
+
+use crate::types::VisualizerFrame;
+
+// Visualizer feed, a tap at the very end of each song's chain copies what is being played
+// A separate thread turns the latest samples into a spectrum and levels, and sends them as "visualizer-frame" events
+// Nothing is copied or sent while the visualizer is turned off
+
+// Samples kept for the analysis, also the FFT size (must be a power of 2)
+const WINDOW: usize = 2048;
+// How many frames the tap collects before handing them over, keeps the lock out of the per sample path
+const TAP_BATCH: usize = 256;
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+const IDLE_INTERVAL: Duration = Duration::from_millis(200);
+// Spectrum bands, spaced evenly on a log scale between these frequencies
+const BANDS: usize = 32;
+const MIN_FREQ: f32 = 40.0;
+const MAX_FREQ: f32 = 16000.0;
+// dB range that is mapped to 0 - 255
+const FLOOR_DB: f32 = -70.0;
+
+// ------------------- Shared Controls -------------------
+
+pub struct AnalyzerControls {
+    enabled: AtomicBool,
+    sample_rate: AtomicU32,
+    // Bumped every time the tap hands over samples, stays the same while nothing is playing
+    written: AtomicU64,
+    // The last WINDOW frames played, [left, right], oldest first
+    samples: Mutex<Vec<[f32; 2]>>
+}
+
+impl AnalyzerControls {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            enabled: AtomicBool::new(false),
+            sample_rate: AtomicU32::new(44100),
+            written: AtomicU64::new(0),
+            samples: Mutex::new(vec![[0.0; 2]; WINDOW])
+        })
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn push(&self, frames: &[[f32; 2]], sample_rate: u32) {
+        let mut samples = self.samples.lock().unwrap();
+        let keep = WINDOW.saturating_sub(frames.len());
+        samples.drain(..WINDOW - keep);
+        samples.extend_from_slice(&frames[frames.len().saturating_sub(WINDOW)..]);
+        drop(samples);
+
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+        self.written.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+
+// ------------------- Tap Source -------------------
+
+pub struct AnalyzerTap<S: Source> {
+    input: S,
+    controls: Arc<AnalyzerControls>,
+    channels: usize,
+    channel: usize,
+    frame: [f32; 2],
+    batch: Vec<[f32; 2]>
+}
+
+impl<S: Source> AnalyzerTap<S> {
+    pub fn new(input: S, controls: Arc<AnalyzerControls>) -> Self {
+        let channels = (u16::from(input.channels()) as usize).max(1);
+        Self { input, controls, channels, channel: 0, frame: [0.0; 2], batch: Vec::with_capacity(TAP_BATCH) }
+    }
+}
+
+impl<S: Source> Iterator for AnalyzerTap<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+
+        if self.controls.is_enabled() {
+            // Mono songs go to both sides, anything past the first two channels is left out
+            if self.channel < 2 {
+                self.frame[self.channel] = sample;
+            }
+            if self.channel + 1 == self.channels {
+                if self.channels == 1 {
+                    self.frame[1] = self.frame[0];
+                }
+                self.batch.push(self.frame);
+                if self.batch.len() >= TAP_BATCH {
+                    self.controls.push(&self.batch, u32::from(self.input.sample_rate()));
+                    self.batch.clear();
+                }
+            }
+        }
+        self.channel = (self.channel + 1) % self.channels;
+
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for AnalyzerTap<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.channel = 0;
+        self.batch.clear();
+        Ok(())
+    }
+}
+
+
+// ------------------- Analysis Thread -------------------
+
+// Started once the app is set up, runs until the app closes
+pub fn start(app: AppHandle, controls: Arc<AnalyzerControls>) {
+    let spawned = thread::Builder::new().name("visualizer".to_string()).spawn(move || {
+        let window: Vec<f32> = (0..WINDOW).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / WINDOW as f32).cos()).collect();
+        let mut last_written = 0;
+        let mut silent_sent = true;
+
+        loop {
+            if !controls.is_enabled() {
+                thread::sleep(IDLE_INTERVAL);
+                continue;
+            }
+            let started = Instant::now();
+
+            // Nothing new was played (paused or stopped), send one silent frame so the visualizer settles
+            let written = controls.written.load(Ordering::Relaxed);
+            if written == last_written {
+                if !silent_sent {
+                    let _ = app.emit("visualizer-frame", VisualizerFrame { bands: vec![0; BANDS], rms_left: 0.0, rms_right: 0.0 });
+                    silent_sent = true;
+                }
+            }
+            else {
+                last_written = written;
+                silent_sent = false;
+
+                let samples = controls.samples.lock().unwrap().clone();
+                let sample_rate = controls.sample_rate.load(Ordering::Relaxed);
+                let _ = app.emit("visualizer-frame", analyze(&samples, sample_rate, &window));
+            }
+
+            thread::sleep(FRAME_INTERVAL.saturating_sub(started.elapsed()));
+        }
+    });
+
+    if let Err(e) = spawned {
+        log::error!("Visualizer - Error starting the thread: {:?}", e);
+    }
+}
+
+fn analyze(samples: &[[f32; 2]], sample_rate: u32, window: &[f32]) -> VisualizerFrame {
+    // Levels only cover about one frame's worth of sound, so they move with the music
+    let recent = (sample_rate as f32 * FRAME_INTERVAL.as_secs_f32()) as usize;
+    let recent = &samples[samples.len().saturating_sub(recent.max(1))..];
+    let rms = |ch: usize| (recent.iter().map(|f| f[ch] * f[ch]).sum::<f32>() / recent.len() as f32).sqrt();
+
+    let mut re: Vec<f32> = samples.iter().zip(window).map(|(f, w)| (f[0] + f[1]) * 0.5 * w).collect();
+    let mut im = vec![0.0; re.len()];
+    fft(&mut re, &mut im);
+
+    // Hann window loses half the amplitude, the rest scales a full scale sine to 0 dB
+    let scale = 4.0 / WINDOW as f32;
+    let bin_hz = sample_rate as f32 / WINDOW as f32;
+    let max_freq = MAX_FREQ.min(sample_rate as f32 / 2.0);
+
+    let bands = (0..BANDS).map(|band| {
+        let low = MIN_FREQ * (max_freq / MIN_FREQ).powf(band as f32 / BANDS as f32);
+        let high = MIN_FREQ * (max_freq / MIN_FREQ).powf((band + 1) as f32 / BANDS as f32);
+        let first = ((low / bin_hz) as usize).max(1);
+        let last = ((high / bin_hz) as usize).max(first).min(WINDOW / 2 - 1);
+
+        // The loudest bin in the band, so narrow low bands aren't drowned out by wide high ones
+        let peak = (first..=last).map(|i| (re[i] * re[i] + im[i] * im[i]).sqrt() * scale).fold(0.0, f32::max);
+        let db = 20.0 * peak.max(1e-9).log10();
+        (((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0) * 255.0) as u8
+    }).collect();
+
+    VisualizerFrame { bands, rms_left: rms(0), rms_right: rms(1) }
+}
+
+// In place radix-2 FFT, the length must be a power of 2
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    // Bit reversal
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    fs::{ self, File }, path::{ Path, PathBuf }, time::{ Duration, UNIX_EPOCH }
+};
+use rodio::Source;
+
+use crate::{ cue, formats, types::SongTable };
+
+// Waveform overviews for the seek bar, the loudest sample of each slice of the song
+// Made the first time a song's waveform is asked for, then cached on disk (one small file per song)
+// The cache remembers the song's size and last modified time, a changed file gets a new waveform
+// Cue sheet tracks only cover their own span of the file, each track has its own cache file
+
+// Number of peaks in each waveform
+pub const WAVEFORM_POINTS: usize = 1000;
+// Frames per block while decoding, the blocks are merged down to WAVEFORM_POINTS at the end
+const BLOCK_FRAMES: usize = 1024;
+// Bumped when the cache layout changes, old files are made again
+const CACHE_MAGIC: &[u8; 4] = b"RWF1";
+// FNV-1a, cache names have to stay the same between runs and Rust versions (DefaultHasher doesn't promise that)
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+pub fn waveform_dir() -> String {
+    dirs::home_dir().unwrap().to_str().unwrap().to_string() + "/.config/robintuk_player/waveforms"
+}
+
+// Peaks are 0 - 255, 255 being full scale
+pub fn get_waveform(song: &SongTable) -> Result<Vec<u8>, String> {
+    let file_path = song.source_path.as_deref().unwrap_or(&song.path);
+    let (size, modified) = file_stamp(file_path)?;
+    let cache = cache_path(&song.path);
+
+    if let Some(peaks) = read_cache(&cache, size, modified) {
+        return Ok(peaks);
+    }
+
+    let (start, end) = cue::song_span(song);
+    let peaks = analyze_file(file_path, start, end)?;
+    // A failed write only means it is made again next time
+    let _ = fs::create_dir_all(waveform_dir());
+    let _ = fs::write(&cache, encode_cache(size, modified, &peaks));
+    Ok(peaks)
+}
+
+fn file_stamp(path: &str) -> Result<(u64, u64), String> {
+    let metadata = fs::metadata(path).map_err(|e| format!("Song does not exist: {:?}", e))?;
+    let modified = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok((metadata.len(), modified))
+}
+
+fn cache_path(path: &str) -> PathBuf {
+    let hash = path.bytes().fold(FNV_OFFSET, |hash, b| (hash ^ b as u64).wrapping_mul(FNV_PRIME));
+    PathBuf::from(waveform_dir()).join(format!("{:016x}.bin", hash))
+}
+
+// Called when the song leaves the library
+pub fn remove_cache(path: &str) {
+    let _ = fs::remove_file(cache_path(path));
+}
+
+
+// ------------------- Cache File -------------------
+// magic (4) | size (u64) | modified (u64) | peaks (u8 each)
+
+fn encode_cache(size: u64, modified: u64, peaks: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(20 + peaks.len());
+    bytes.extend_from_slice(CACHE_MAGIC);
+    bytes.extend_from_slice(&size.to_le_bytes());
+    bytes.extend_from_slice(&modified.to_le_bytes());
+    bytes.extend_from_slice(peaks);
+    bytes
+}
+
+fn read_cache(cache: &Path, size: u64, modified: u64) -> Option<Vec<u8>> {
+    let bytes = fs::read(cache).ok()?;
+    if bytes.len() < 20 || &bytes[0..4] != CACHE_MAGIC {
+        return None;
+    }
+    let cached_size = u64::from_le_bytes(bytes[4..12].try_into().ok()?);
+    let cached_modified = u64::from_le_bytes(bytes[12..20].try_into().ok()?);
+
+    if cached_size != size || cached_modified != modified {
+        return None;
+    }
+    Some(bytes[20..].to_vec())
+}
+
+
+// ------------------- Analysis -------------------
+
+// `end` None reads to the end of the file
+fn analyze_file(path: &str, start: Duration, end: Option<Duration>) -> Result<Vec<u8>, String> {
+    let file = File::open(path).map_err(|e| format!("Song does not exist: {:?}", e))?;
+    // Only a cue sheet track that starts part way in has to seek
+    let decoder = formats::decoder(file, path, !start.is_zero())?;
+    let decoder = cue::Span::new(decoder, start, end).map_err(|e| format!("Error seeking to the cue track: {}", e))?;
+
+    let block_len = BLOCK_FRAMES * u16::from(decoder.channels()) as usize;
+    let mut blocks: Vec<f32> = vec![];
+    let mut peak: f32 = 0.0;
+    let mut count = 0;
+
+    for sample in decoder {
+        peak = peak.max(sample.abs());
+        count += 1;
+        if count == block_len {
+            blocks.push(peak);
+            peak = 0.0;
+            count = 0;
+        }
+    }
+    if count > 0 {
+        blocks.push(peak);
+    }
+
+    Ok(downsample(&blocks))
+}
+
+// Merge the blocks into WAVEFORM_POINTS peaks, short songs repeat blocks so every waveform is the same length
+fn downsample(blocks: &[f32]) -> Vec<u8> {
+    if blocks.is_empty() {
+        return vec![0; WAVEFORM_POINTS];
+    }
+
+    (0..WAVEFORM_POINTS).map(|i| {
+        let start = i * blocks.len() / WAVEFORM_POINTS;
+        let end = ((i + 1) * blocks.len() / WAVEFORM_POINTS).max(start + 1).min(blocks.len());
+        let peak = blocks[start..end].iter().cloned().fold(0.0, f32::max);
+        (peak.clamp(0.0, 1.0) * 255.0).round() as u8
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cache_path;
+
+    #[test]
+    fn cache_names_stay_the_same() {
+        let name = |path: &str| cache_path(path).file_name().unwrap().to_str().unwrap().to_string();
+        assert_eq!(name(""), "cbf29ce484222325.bin");
+        assert_eq!(name("/music/Album/01 Song.flac"), "5ce1166963505efa.bin");
+        // Each cue sheet track has its own waveform
+        assert_ne!(name("/music/Album.flac#01"), name("/music/Album.flac#02"));
+    }
+}
@@ -6,7 +6,7 @@ import { invoke } from '@tauri-apps/api/core';
 import "./musicControls.css";
 
 // Custom Components
-import { GetCurrentSong, savePosition, Songs, SongLyrics, DirectoryInfo } from "../../globalValues";
+import { GetCurrentSong, savePosition, Songs, SongLyrics, DirectoryInfo, PlaybackProgress, PlayerStatus, RestoredSession } from "../../globalValues";
 import ImageWithFallBack from "../imageFallback";
 
 // Images
@@ -48,16 +48,9 @@ export default function MusicControls() {
     const currentLineRef = useRef<HTMLDivElement | null>(null);
     const currentLineNumberRef = useRef<number | null>(null);
 
-    const [lockShuffle, setLockShuffle] = useState<boolean>(false);
-
     // Called only once on load
     useEffect(() => {
         firstLoad();
-
-        const storedVolume: string | null = localStorage.getItem("volume-level");
-        if(storedVolume !== null) {
-            updateVolume(JSON.parse(storedVolume));
-        }        
     }, []);
 
     // Just for listeners
@@ -71,6 +64,9 @@ export default function MusicControls() {
         // Listen for when the MediaPlayPause shortcut is pressed
         const unlisten_play_pause = listen("controls-play-pause", async(event) => { if(!event.payload) { setIsPlaying(false); } else { setIsPlaying(true); } });
         const unlisten_shuffle_setting = listen<boolean>("player-shuffle-mode", (event) => { setIsShuffle(event.payload); });
+        // The backend moves to the next song by itself, these keep the controls in sync with it
+        const unlisten_progress = listen<PlaybackProgress>("playback-progress", (event) => { setSongProgress(event.payload.position_ms / 1000); });
+        const unlisten_player_state = listen<PlayerStatus>("player-state", (event) => { if(event.payload.is_loaded) { savePosition(event.payload.index); } });
 
         const handler = (e: any) => {
             if(e.clientY <= 29) {
@@ -87,28 +83,13 @@ export default function MusicControls() {
             unlisten_play_pause.then(f => f()),
             unlisten_shuffle_setting.then(f => f()),
             unlisten_update_song.then(f => f()),
+            unlisten_progress.then(f => f()),
+            unlisten_player_state.then(f => f()),
             document.removeEventListener('mousedown', handler);
         }        
     }, []);
 
-    // Track the progress of the song
-    useEffect(() => {
-        let interval = undefined;
-
-        if(isPlaying) {
-            interval = setInterval(() => {
-                setSongProgress((time) => time + 1);
-            }, 1000);
-        }
-        else {
-            clearInterval(interval);
-        }
-        return () => {
-            clearInterval(interval);
-        };
-    }, [isPlaying]);
-
-    // Check if the song is over - play next song is able
+    // Keep the current lyric line in view
     useEffect(() => {
         // Lyrics Line Mover
         if(currentLineRef.current) {
@@ -117,20 +98,6 @@ export default function MusicControls() {
                 block: "center"
             });
         }
-
-        // Get the next song's details when the current song ends
-        if(songProgress === songDetails?.duration) {
-            getNewSongDetails();
-        }
-
-        // Pre-load the next song when there is one second left
-        // Then lock the shuffle function to stop and song mismatch, unlock when new song starts
-        if(songDetails?.duration !== undefined) {
-            if(Math.abs(songProgress - songDetails?.duration) <= 1 && Math.abs(songProgress - songDetails?.duration) !== 0) {
-                // Append the next song to the sink
-                setNextSongforLoad();
-            }
-        }
     }, [songProgress]);
 
     // --------------------- Start of Simple Media Functions ---------------------
@@ -248,21 +215,19 @@ export default function MusicControls() {
     // Function to update the volume of the music player
     async function updateShuffleMode() {
         try {
-            if(lockShuffle === false) {
-                if(isShuffle && songDetails !== undefined) {
-                    setIsShuffle(false);
-                    // Index of the current song
-                    await invoke("shuffle_queue", { song: songDetails.path, shuffled: false });
-                    await invoke("player_set_shuffle", { mode: false });
-                }
-                else if(!isShuffle && songDetails !== undefined) {
-                    setIsShuffle(true);
-                    // Index of the current song
-                    await invoke("shuffle_queue", { song: songDetails.path, shuffled: true });
-                    await invoke("player_set_shuffle", { mode: true });
-                }
-                localStorage.setItem("shuffle-mode", JSON.stringify(!isShuffle));
+            if(isShuffle && songDetails !== undefined) {
+                setIsShuffle(false);
+                // Index of the current song
+                await invoke("shuffle_queue", { song: songDetails.path, shuffled: false });
+                await invoke("player_set_shuffle", { mode: false });
+            }
+            else if(!isShuffle && songDetails !== undefined) {
+                setIsShuffle(true);
+                // Index of the current song
+                await invoke("shuffle_queue", { song: songDetails.path, shuffled: true });
+                await invoke("player_set_shuffle", { mode: true });
             }
+            localStorage.setItem("shuffle-mode", JSON.stringify(!isShuffle));
         }
         catch(e) {
             error("Controls - Error updating shuffle");
@@ -289,78 +254,42 @@ export default function MusicControls() {
 
     // --------------------- Start of Song Management Functions ---------------------
 
-    async function sendQueueToBackend(queue: Songs[], index: number) {
-        // This will reset the song's progress when a refresh happens
-        try {
-            await invoke('player_setup_queue_and_song', { queue: queue, index: index });
-        }
-        catch(e) {
-            error("Controls - Failed to send queue to backend");
-            console.log(`Failed to setup player: ${e}`);
-            setIsLoaded(false);
-        }
-    }
-    // Similar to the loadQueue, but doesn't play the music because it's just loading the data
+    // Rebuild the player where it was left when the app was closed, paused
     async function firstLoad() {
-        const qPosition: string | null = localStorage.getItem('last-played-queue-position');
-        const shuffleMode: boolean = JSON.parse(localStorage.getItem('shuffle-mode')!);
-
-        pauseMusic();
-        if(qPosition !== null) {
-            info("Controls (Info) - Loaded Volume Level: " + JSON.parse(qPosition));
-            try {
-                // Get the last played song, if exists
-                const queue: Songs[] = await invoke<Songs[]>("get_queue", {shuffled: shuffleMode !== null ? shuffleMode : false});
-
-                if(queue.length === 0) {
-                    setIsLoaded(false);
-                    info("Controls (Info) - There is a Queue");
-                }
-                else if(queue.length <= parseInt(qPosition)) {
-                    console.log("qPosition is equal to or greater than queue length - cannot load");
-                    error("Music Controls - Queue Position is equal to or greater than Queue Length - Cannot load Controls");
-                }
-                else {
-                    setSongDetails(queue[JSON.parse(qPosition!)]);
-                    setSongProgress(0);
-                    setIsLoaded(true);
-
-                    if(shuffleMode !== null) {
-                        if(shuffleMode) {
-                            setIsShuffle(true);
-                            sendQueueToBackend(queue, JSON.parse(qPosition!));
-                        }
-                        else {
-                            setIsShuffle(false);
-                            sendQueueToBackend(queue, JSON.parse(qPosition!));
-                        }
-                        await checkForLyrics(queue[JSON.parse(qPosition!)].path);
-                    }
-                    else {
-                        setIsShuffle(false);
-                    }
-                    info("Controls (Info) - Loaded Shuffle Status: " + shuffleMode);
-                }
+        setIsPlaying(false);
+        try {
+            const restored: RestoredSession = await invoke<RestoredSession>("player_restore_session");
+
+            setIsShuffle(restored.session.shuffle);
+            setRepeatMode(restored.session.repeat_mode);
+            // The volume slider has always been saved here, so it is used over the session (which has a default before its first save)
+            const storedVolume: string | null = localStorage.getItem("volume-level");
+            if(storedVolume !== null) {
+                await updateVolume(JSON.parse(storedVolume));
             }
-            catch(e) {
-                error(`Music Controls - Failed load music controls: ${e}`);
-                console.log(`Failed load music controls: ${e}`);
+            else {
+                setVolume(Math.round(restored.session.volume * 50));
+            }
+            localStorage.setItem("shuffle-mode", JSON.stringify(restored.session.shuffle));
+            info("Controls (Info) - Restored Session: " + JSON.stringify(restored.session));
+
+            if(restored.song !== null) {
+                setSongDetails(restored.song);
+                setSongProgress(restored.session.position_ms / 1000);
+                setIsLoaded(true);
+                saveSong(restored.song);
+                savePosition(restored.session.index);
+                await checkForLyrics(restored.song.path);
+            }
+            else {
                 setIsLoaded(false);
             }
         }
-        else {
-            info("Controls (Info) - Loaded Volume Level: null");
+        catch(e) {
+            error(`Music Controls - Failed to restore the last session: ${e}`);
+            console.log(`Failed to restore the last session: ${e}`);
             setIsLoaded(false);
         }
-
-        const storedVolume = localStorage.getItem("volume-level");
-        if(storedVolume !== null) {
-            updateVolume(JSON.parse(storedVolume));
-            info("Controls (Info) - Loaded Volume Level: " + JSON.parse(storedVolume));
-        }
-        else {
-            updateVolume(volume);
-        }
     }
 
     async function checkForLyrics(path: string) {
@@ -449,72 +378,11 @@ export default function MusicControls() {
             setIsPlaying(false);
         }
         finally {
-            await invoke('add_song_to_history', { path: q.path });
             setIsLoaded(true);
             setIsPlaying(true);
         }
     }
     
-    // Pre-load the next song, used for gapless playback
-    async function setNextSongforLoad() {
-        try {
-            setLockShuffle(true);
-            // Used to limit the frontend from making an extra call, which would add a dup song
-            const num_loaded_songs: number = await invoke("player_get_sink_length");
-            const pos: number = await invoke("player_get_current_position");
-            const qLength: number = await invoke("player_get_queue_length");
-
-            if (pos + 1 >= qLength) {
-                if(num_loaded_songs == 1) {
-                    // Load the next song in the queue to be ready
-                    await invoke("player_load_song", { index: 0 });
-                }
-            }
-            else {
-                if(num_loaded_songs == 1) {
-                    // Load the next, unloaded song to the queue (The second song from the one that just ended)
-                    await invoke("player_load_song", { index: pos + 1 });
-                }
-            }
-        }
-        catch(e) {
-            error("Controls - Error pre-loading the next song: " + e);
-            console.log("Error loading the next song: " + e);
-        }
-    }
-
-    // Get the next song details, when a song ends
-    async function getNewSongDetails() {
-        try {
-            setSongProgress(0);
-            const pos: number = await invoke("player_get_current_position");
-            const qLength: number = await invoke("player_get_queue_length");
-            if (pos + 1 >= qLength) {
-                await invoke("player_update_pos", { index: 0 });
-                savePosition(0);
-            }
-            else {
-                await invoke("player_update_pos", { index: pos + 1 });
-                savePosition(pos + 1);
-            }
-        }
-        catch(e) {
-            error("Controls - Error getting new song details");
-            console.log("Error getting the new song details: " + e);
-            setLockShuffle(false);
-        }
-        finally {
-            // Get the new song's details
-            const newSong: Songs = await invoke("player_get_current_song");
-            setSongDetails(newSong);
-            saveSong(newSong);
-            
-            // Send out update that a new song is being played
-            await invoke("update_current_song_played");
-            await checkForLyrics(newSong.path);
-            setLockShuffle(false);
-        }
-    }
     // --------------------- End of Song Management Functions ---------------------
     
     
@@ -40,7 +40,13 @@ export interface SongsFull {
     album_artist: string,
     disc_number: number,
     duration: number,
-    song_section: number
+    song_section: number,
+    // Only sent by get_song
+    chapters?: Chapter[],
+    // Tracks from a cue sheet, played from source_path between start_ms and end_ms
+    source_path?: string | null,
+    start_ms?: number | null,
+    end_ms?: number | null
 }
 
 export interface AlbumRes {
@@ -133,11 +139,147 @@ export interface PlayHistory {
     album_artist: string,
     disc_number: number,
     duration: number,
-    song_section: number
+    song_section: number,
+    // Sent back to the player as they are, so cue tracks and the volume levelling keep working
+    replaygain_track_gain: number | null,
+    replaygain_track_peak: number | null,
+    replaygain_album_gain: number | null,
+    replaygain_album_peak: number | null,
+    loudness_lufs: number | null,
+    loudness_peak: number | null,
+    source_path: string | null,
+    start_ms: number | null,
+    end_ms: number | null
 }
 
 export type GetCurrentSong = { q: Songs; };
 
+// "playback-progress" event from the player
+export interface PlaybackProgress {
+    index: number,
+    position_ms: number,
+    duration_ms: number,
+    next_buffered: boolean
+}
+// Saved by the player and restored on startup
+export interface PlaybackSession {
+    index: number,
+    position_ms: number,
+    volume: number,
+    repeat_mode: number,
+    shuffle: boolean
+}
+export interface RestoredSession {
+    session: PlaybackSession,
+    song: Songs | null
+}
+// "player-state" event from the player
+export interface PlayerStatus {
+    index: number,
+    queue_length: number,
+    is_paused: boolean,
+    is_loaded: boolean,
+    repeat_mode: number,
+    shuffle: boolean,
+    volume: number,
+    ab_loop: { a_ms: number | null, b_ms: number | null }
+}
+
+// bands are 0 - 255, low to high frequencies
+export interface VisualizerFrame {
+    bands: number[],
+    rms_left: number,
+    rms_right: number
+}
+
+export interface RadioStation {
+    id: number,
+    name: string,
+    url: string
+}
+
+export interface RadioNowPlaying {
+    station_id: number,
+    station_name: string,
+    title: string | null
+}
+
+export interface PodcastFeed {
+    id: number,
+    url: string,
+    title: string,
+    description: string | null,
+    image_url: string | null,
+    refreshed_at: string,
+    unplayed: number
+}
+
+export interface PodcastEpisode {
+    id: number,
+    feed_id: number,
+    feed_title: string,
+    title: string,
+    description: string | null,
+    audio_url: string,
+    published: string | null,
+    duration: number | null,
+    file_path: string | null,
+    played: boolean
+}
+
+export interface PodcastDownloadProgress {
+    episode_id: number,
+    downloaded: number,
+    total: number | null
+}
+
+export interface ResumeSettings {
+    min_duration_minutes: number,
+    genres: string[]
+}
+
+export interface Bookmark {
+    path: string,
+    name: string | null,
+    artist: string | null,
+    album: string | null,
+    position_ms: number,
+    duration: number,
+    updated_at: string
+}
+
+export interface Chapter {
+    title: string,
+    start_ms: number,
+    end_ms: number
+}
+
+export interface PlaybackError {
+    path: string,
+    name: string | null,
+    artist: string | null,
+    album: string | null,
+    reason: string,
+    failed_at: string,
+    fail_count: number
+}
+
+// 0 - Off, 1 - Default, 2 - Chu Moy, 3 - Jan Meier
+export interface ChannelSettings {
+    balance: number,
+    mono: boolean,
+    swap: boolean,
+    crossfeed: number
+}
+
+export interface SleepTimerStatus {
+    mode: string,
+    remaining_secs: number | null,
+    tracks_left: number,
+    fade_secs: number,
+    fading: boolean
+}
+
 export interface SongLyrics {
     lyrics_id: number,
     plain_lyrics: string,